@@ -0,0 +1,43 @@
+//! Times building expressions from long operator sequences, run with `cargo bench`.  
+//! Each sequence length is doubled, so linear scaling shows up as roughly doubled times.
+
+use markmath::language::expression::Expression;
+use markmath::language::format::FormattableLibraryProvider;
+use markmath::language::latex_impl::LatexFormatter;
+use markmath::language::parse::tokenize;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 20;
+
+fn sequence(terms: usize, operators: &[&str]) -> String {
+    (0..terms)
+        .map(|i| format!("{} {} ", i + 1, operators[i % operators.len()]))
+        .collect::<String>()
+        + "1"
+}
+
+fn time(source: &str, lib: &FormattableLibraryProvider<LatexFormatter>) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        let tokens = tokenize(source).expect("valid sequence");
+        black_box(Expression::new(tokens, lib).expect("valid sequence"));
+    }
+    start.elapsed() / RUNS
+}
+
+fn main() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let cases: [(&str, &[&str]); 4] = [
+        ("sum", &["+"]),
+        ("product", &["*"]),
+        ("power", &["**"]),
+        ("mixed", &["+", "*", "**", "-", "/"]),
+    ];
+    for (name, operators) in cases {
+        for terms in [250, 500, 1000, 2000] {
+            let source = sequence(terms, operators);
+            println!("{name:>8} {terms:>5} terms: {:?}", time(&source, &lib));
+        }
+    }
+}
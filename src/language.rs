@@ -8,4 +8,6 @@ pub mod latex_impl;
 mod parse_tests;
 #[cfg(test)]
 mod expression_tests;
+#[cfg(test)]
+mod format_tests;
 
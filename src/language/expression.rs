@@ -1,31 +1,50 @@
 use crate::language::parse::TokenTree;
 use std::collections::HashMap;
-use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
 
 pub trait LibraryProvider {
     type LibraryError: Debug;
 
     fn function_exists(&self, name: &str, param_c: usize) -> bool;
     fn operator_exists(&self, symbol: &str) -> bool;
+    /// Prefix operators other than `-`, which is always available as [Expression::Negate].
+    fn unary_operator_exists(&self, symbol: &str) -> bool;
 
     fn eval_function(&self, name: &str, params: &[f64]) -> Result<f64, Self::LibraryError>;
 
+    /// The unit of a function result, given the units of the arguments.  
+    /// Errors if the function can't take arguments with these units.
+    fn function_unit(&self, name: &str, arg_units: &[Unit]) -> Result<Unit, Self::LibraryError>;
+
     fn eval_operator(&self, symbol: &str, left: f64, right: f64)
     -> Result<f64, Self::LibraryError>;
 
+    /// Also evaluates `-` for [Expression::Negate].
+    fn eval_unary_operator(&self, symbol: &str, value: f64) -> Result<f64, Self::LibraryError>;
+
     fn operator_associative(&self, symbol: &str) -> bool;
 
+    /// Werther both operands must have the same unit, like for `+` and `-`. False by default.
+    fn operator_requires_equal_units(&self, _symbol: &str) -> bool {
+        false
+    }
+
     fn operator_precedence(&self, symbol: &str) -> u32;
+
+    /// Operators with a higher precedence than this bind tighter than a leading `-`, so `-a ** b` is `-(a ** b)`.
+    fn negation_precedence(&self) -> u32;
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum Unit {
     Defined(DefinedUnit),
     Literal(String),
     None,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum DefinedUnit {
     Defined(String),
     Implicit {
@@ -37,34 +56,263 @@ pub enum DefinedUnit {
     },
 }
 
+/// How deeply expressions can be nested by default, both when parsing and evaluating.  
+/// Low enough to stay within the stack of a thread in debug builds.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// How many expressions the [ExpressionRef](Expression::ExpressionRef)s of a formatted expression can expand to in total.
+pub const MAX_EXPANDED_SIZE: usize = 10_000;
+
+/// The expression a variable was assigned, see [get_variable_expr](EvaluationContext::get_variable_expr).
+#[derive(PartialEq)]
+pub struct VariableExpression {
+    pub expression: Expression,
+    /// The values of the variables the expression read when it was assigned, so formatting it later shows the same numbers.
+    pub values: HashMap<String, (f64, Unit)>,
+}
+
+#[derive(Clone)]
 pub struct EvaluationContext {
-    map: HashMap<String, (f64, Unit)>,
+    /// Innermost scope last, there is always at least the global scope.
+    scopes: Vec<HashMap<String, (f64, Unit)>>,
+    /// from, to -> factor
+    /// from, to, factor
+    conversions: HashMap<String, HashMap<String, f64>>,
+    /// The expression each variable was last assigned, for [ExpressionRef](Expression::ExpressionRef).
+    expressions: HashMap<String, Rc<VariableExpression>>,
+    /// See [set_max_depth](Self::set_max_depth).
+    max_depth: usize,
 }
+impl Default for EvaluationContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EvaluationContext {
     pub fn new() -> Self {
         Self {
-            map: HashMap::new(),
+            scopes: vec![HashMap::new()],
+            conversions: HashMap::new(),
+            expressions: HashMap::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
         }
     }
 
+    /// See [set_max_depth](Self::set_max_depth).
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Evaluating expressions nested deeper than this fails with [EvaluationError::TooDeep] instead of overflowing the stack, 128 by default.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// A value in `from` is multiplied by `factor` to get the value in `to`.  
+    /// Units are either the name of a defined unit, or a literal unit.
+    pub fn add_conversion(&mut self, from: &str, to: &str, factor: f64) {
+        self.conversions
+            .entry(from.to_string())
+            .or_default()
+            .insert(to.to_string(), factor);
+    }
+
+    /// Also finds the inverse of added conversions.
+    pub fn get_conversion(&self, from: &str, to: &str) -> Option<f64> {
+        if from == to {
+            return Some(1.);
+        }
+        if let Some(f) = self.conversions.get(from).and_then(|c| c.get(to)) {
+            return Some(*f);
+        }
+        self.conversions
+            .get(to)
+            .and_then(|c| c.get(from))
+            .map(|f| 1. / f)
+    }
+
     pub fn get_variable(&self, name: &str) -> Option<(f64, Unit)> {
-        self.map.get(name).cloned()
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .cloned()
     }
 
+    /// Stores in the innermost scope, shadowing variables with the same name in outer scopes.
     pub fn store_variable(&mut self, name: &str, value: (f64, Unit)) {
-        self.map.insert(name.to_string(), value);
+        self.scopes
+            .last_mut()
+            .expect("global scope is never popped")
+            .insert(name.to_string(), value);
+    }
+
+    /// The expression the variable was last assigned.  
+    /// [ExpressionRef](Expression::ExpressionRef)s in it are only expanded when formatting.
+    pub fn get_variable_expr(&self, name: &str) -> Option<Rc<VariableExpression>> {
+        self.expressions.get(name).cloned()
+    }
+
+    /// Sets the expression returned by [get_variable_expr](Self::get_variable_expr), eg. when restoring a variable from a cache.
+    pub fn store_variable_expr(&mut self, name: &str, exp: Rc<VariableExpression>) {
+        self.expressions.insert(name.to_string(), exp);
+    }
+
+    /// The expression referenced by `exp` if it is an [ExpressionRef](Expression::ExpressionRef), otherwise `exp`.  
+    /// References to references are followed, until a cycle or a variable without expression.
+    pub fn referenced<'a>(&'a self, mut exp: &'a Expression) -> &'a Expression {
+        let mut seen = Vec::new();
+        while let Expression::ExpressionRef(name) = exp
+            && !seen.contains(&name)
+            && let Some(next) = self.expressions.get(name)
+        {
+            seen.push(name);
+            exp = &next.expression;
+        }
+        exp
+    }
+
+    /// Starts a scope for temporary variables, eg. the bound variable of a sum.
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /// Removes the innermost scope and its variables, restoring shadowed variables.
+    pub fn pop_scope(&mut self) {
+        assert!(self.scopes.len() > 1, "pop_scope called without push_scope");
+        self.scopes.pop();
+    }
+
+    /// All visible variables, in no particular order. Shadowed variables are left out.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &(f64, Unit))> {
+        self.scopes.iter().enumerate().flat_map(move |(i, scope)| {
+            scope
+                .iter()
+                .filter(move |(name, _)| !self.scopes[i + 1..].iter().any(|s| s.contains_key(*name)))
+                .map(|(name, value)| (name.as_str(), value))
+        })
+    }
+
+    /// The number of visible variables.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scopes.iter().all(HashMap::is_empty)
+    }
+}
+
+/// The visible variables, one per line as `name;value;unit`, sorted by name.  
+/// A literal unit is written as `"unit`, a defined unit as its name, and an implicit unit as `(operator a|n left right)`.
+impl Display for EvaluationContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut variables = self.iter().collect::<Vec<_>>();
+        variables.sort_by_key(|(name, _)| *name);
+        for (name, (value, unit)) in variables {
+            write!(f, "{name};{value};")?;
+            match unit {
+                Unit::Defined(d) => write_defined_unit(d, f)?,
+                Unit::Literal(l) => write!(f, "\"{l}")?,
+                Unit::None => {}
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_defined_unit(unit: &DefinedUnit, f: &mut Formatter<'_>) -> std::fmt::Result {
+    match unit {
+        DefinedUnit::Defined(name) => write!(f, "{name}"),
+        DefinedUnit::Implicit {
+            operator,
+            associative,
+            left,
+            right,
+        } => {
+            write!(f, "({operator} {} ", if *associative { "a" } else { "n" })?;
+            write_defined_unit(left, f)?;
+            write!(f, " ")?;
+            write_defined_unit(right, f)?;
+            write!(f, ")")
+        }
+    }
+}
+
+/// Reads the variables written by [Display], into the global scope of a new context.
+impl FromStr for EvaluationContext {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ctx = Self::new();
+        for line in s.lines().filter(|l| !l.trim().is_empty()) {
+            let [name, value, unit] = line.splitn(3, ';').collect::<Vec<_>>()[..] else {
+                return Err(format!("Invalid variable line: {line}"));
+            };
+            let Ok(value) = value.parse() else {
+                return Err(format!("Invalid variable value: {line}"));
+            };
+            let unit = if let Some(literal) = unit.strip_prefix('"') {
+                Unit::Literal(literal.to_string())
+            } else if unit.is_empty() {
+                Unit::None
+            } else {
+                let spaced = unit.replace('(', " ( ").replace(')', " ) ");
+                let mut tokens = spaced.split_whitespace();
+                let defined = read_defined_unit(&mut tokens).ok_or_else(|| format!("Invalid unit: {line}"))?;
+                if tokens.next().is_some() {
+                    return Err(format!("Invalid unit: {line}"));
+                }
+                Unit::Defined(defined)
+            };
+            ctx.store_variable(name, (value, unit));
+        }
+        Ok(ctx)
+    }
+}
+
+fn read_defined_unit<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Option<DefinedUnit> {
+    match tokens.next()? {
+        "(" => {
+            let operator = tokens.next()?.to_string();
+            let associative = match tokens.next()? {
+                "a" => true,
+                "n" => false,
+                _ => return None,
+            };
+            let left = Box::new(read_defined_unit(tokens)?);
+            let right = Box::new(read_defined_unit(tokens)?);
+            (tokens.next()? == ")").then_some(DefinedUnit::Implicit {
+                operator,
+                associative,
+                left,
+                right,
+            })
+        }
+        ")" => None,
+        name => Some(DefinedUnit::Defined(name.to_string())),
     }
 }
 
 pub enum ExpressionError {
     UnknownFunction { name: String, param_c: usize },
     UnknownOperator(String),
+    UnknownUnaryOperator(String),
     InvalidNumber(String),
+    UnexpectedString(String),
 }
 
 pub enum EvaluationError<LibraryError: Debug> {
     LibraryError(LibraryError),
     MissingVariable { name: String },
+    MissingConversion { from: Option<String>, to: String },
+    TooDeep { max_depth: usize },
+    /// The names of the references, starting and ending with the same name.
+    CyclicReference { names: Vec<String> },
+    /// See [MAX_EXPANDED_SIZE].
+    TooLarge { max_size: usize },
 }
 
 impl<LibraryError: Debug> Debug for EvaluationError<LibraryError> {
@@ -72,10 +320,54 @@ impl<LibraryError: Debug> Debug for EvaluationError<LibraryError> {
         match &self {
             EvaluationError::LibraryError(err) => err.fmt(f),
             EvaluationError::MissingVariable { name } => write!(f, "Variable '{}' not found", name),
+            EvaluationError::MissingConversion { from: Some(from), to } => {
+                write!(f, "No conversion from '{}' to '{}'", from, to)
+            }
+            EvaluationError::MissingConversion { from: None, to } => {
+                write!(f, "Can only convert values with a named unit to '{}'", to)
+            }
+            EvaluationError::TooDeep { max_depth } => {
+                write!(f, "Expression is nested deeper than {} levels", max_depth)
+            }
+            EvaluationError::CyclicReference { names } => {
+                write!(f, "cyclic variable reference: {}", names.join(" -> "))
+            }
+            EvaluationError::TooLarge { max_size } => {
+                write!(f, "Variable references expand to more than {} expressions", max_size)
+            }
+        }
+    }
+}
+
+/// Returned by [Expression::check_units].
+pub enum UnitError<LibraryError: Debug> {
+    LibraryError(LibraryError),
+    IncompatibleUnits {
+        operator: String,
+        left: String,
+        right: String,
+    },
+}
+
+impl<LibraryError: Debug> Debug for UnitError<LibraryError> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            UnitError::LibraryError(err) => err.fmt(f),
+            UnitError::IncompatibleUnits {
+                operator,
+                left,
+                right,
+            } => write!(f, "Can not use '{}' between {} and {}", operator, left, right),
         }
     }
 }
 
+impl<LibraryError: Debug> From<LibraryError> for UnitError<LibraryError> {
+    fn from(value: LibraryError) -> Self {
+        Self::LibraryError(value)
+    }
+}
+
 impl<LibraryError: Debug> From<LibraryError> for EvaluationError<LibraryError> {
     fn from(value: LibraryError) -> Self {
         Self::LibraryError(value)
@@ -91,7 +383,13 @@ impl Debug for ExpressionError {
                 name, param_c
             ),
             ExpressionError::UnknownOperator(op) => write!(f, "Invalid operator: '{}'", op),
+            ExpressionError::UnknownUnaryOperator(op) => {
+                write!(f, "Invalid prefix operator: '{}'", op)
+            }
             ExpressionError::InvalidNumber(num) => write!(f, "Invalid number: '{}'", num),
+            ExpressionError::UnexpectedString(s) => {
+                write!(f, "Unexpected string \"{}\", strings are units", s)
+            }
         }
     }
 }
@@ -119,9 +417,71 @@ pub enum Expression {
         name: String,
         child: Box<Expression>,
     },
+    /// `to(child, unit)`, rescales the value of child to the given unit using the conversions in [EvaluationContext].
+    Convert {
+        unit: UnitName,
+        child: Box<Expression>,
+    },
     VariableRef(String),
+    /// `!name`, evaluates like [VariableRef](Self::VariableRef), but is rendered as the expression the variable was assigned.  
+    /// The expression is rendered with the current values of its variables.
+    ExpressionRef(String),
     NumberLiteral(f64),
     Negate(Box<Expression>),
+    /// A prefix operator, keeps the unit of its child like [Negate](Self::Negate).
+    UnaryOperator {
+        operator: String,
+        child: Box<Expression>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnitName {
+    Defined(String),
+    Literal(String),
+}
+
+impl UnitName {
+    fn name(&self) -> &str {
+        match self {
+            UnitName::Defined(n) | UnitName::Literal(n) => n,
+        }
+    }
+}
+
+/// Every operator is parenthesised, so the grouping is visible, eg. `1 + 2 * 3` is `(1 + (2 * 3))`.
+impl Display for Expression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::VariableAssign { name, child } => write!(f, "{name} = {child}"),
+            Expression::Operator {
+                operator,
+                left,
+                right,
+            } => write!(f, "({left} {operator} {right})"),
+            Expression::FunctionCall { function, args } => {
+                let args: Vec<_> = args.iter().map(ToString::to_string).collect();
+                write!(f, "{function}({})", args.join(", "))
+            }
+            Expression::DefinedUnit { name, child } => {
+                write!(f, "{child} {}", name.as_deref().unwrap_or("None"))
+            }
+            Expression::LiteralUnit { name, child } => write!(f, "{child} \"{name}\""),
+            Expression::Convert {
+                unit: UnitName::Defined(unit),
+                child,
+            } => write!(f, "to({child}, {unit})"),
+            Expression::Convert {
+                unit: UnitName::Literal(unit),
+                child,
+            } => write!(f, "to({child}, \"{unit}\")"),
+            Expression::VariableRef(name) => write!(f, "{name}"),
+            Expression::ExpressionRef(name) => write!(f, "!{name}"),
+            Expression::NumberLiteral(v) => write!(f, "{v}"),
+            Expression::Negate(child) => write!(f, "-{child}"),
+            Expression::UnaryOperator { operator, child } => write!(f, "{operator}{child}"),
+        }
+    }
 }
 
 impl Expression {
@@ -138,15 +498,19 @@ impl Expression {
                 operators,
                 children,
             } => {
-                let children = children
-                    .into_iter()
-                    .map(|tt| Self::new(tt, provider))
-                    .collect::<Result<_, _>>()?;
                 for op in operators.iter() {
                     if !provider.operator_exists(op) {
                         return Err(ExpressionError::UnknownOperator(op.clone()));
                     }
                 }
+                let (operators, mut children) = group_negations(provider, operators, children);
+                if operators.is_empty() {
+                    return Self::new(children.pop().expect("opseq has at least one child"), provider);
+                }
+                let children = children
+                    .into_iter()
+                    .map(|tt| Self::new(tt, provider))
+                    .collect::<Result<_, _>>()?;
                 Ok(transform_operators(provider, operators, children))
             }
             TokenTree::DefinedUnit { name, child } => Ok(Self::DefinedUnit {
@@ -157,6 +521,22 @@ impl Expression {
                 name,
                 child: Box::new(Self::new(*child, provider)?),
             }),
+            TokenTree::FunctionCall { name, mut args } if name == "to" && args.len() == 2 => {
+                let unit = match args.pop().unwrap() {
+                    TokenTree::VariableRef(n) => UnitName::Defined(n),
+                    TokenTree::String(s) => UnitName::Literal(s),
+                    _ => {
+                        return Err(ExpressionError::UnknownFunction {
+                            name,
+                            param_c: 2,
+                        });
+                    }
+                };
+                Ok(Self::Convert {
+                    unit,
+                    child: Box::new(Self::new(args.pop().unwrap(), provider)?),
+                })
+            }
             TokenTree::FunctionCall { name, args } => {
                 if provider.function_exists(&name, args.len()) {
                     Ok(Self::FunctionCall {
@@ -174,6 +554,7 @@ impl Expression {
                 }
             }
             TokenTree::VariableRef(name) => Ok(Self::VariableRef(name)),
+            TokenTree::ExpressionRef(name) => Ok(Self::ExpressionRef(name)),
             TokenTree::NumberLiteral(val) => Ok(Self::NumberLiteral(parse_number(&val)?)),
             TokenTree::Negate(child) => {
                 if let TokenTree::NumberLiteral(v) = child.as_ref() {
@@ -182,58 +563,139 @@ impl Expression {
                     Ok(Self::Negate(Box::new(Self::new(*child, provider)?)))
                 }
             }
+            TokenTree::UnaryOperator { operator, child } => {
+                if !provider.unary_operator_exists(&operator) {
+                    return Err(ExpressionError::UnknownUnaryOperator(operator));
+                }
+                Ok(Self::UnaryOperator {
+                    operator,
+                    child: Box::new(Self::new(*child, provider)?),
+                })
+            }
+            TokenTree::Group(child) => Self::new(*child, provider),
+            TokenTree::String(s) => Err(ExpressionError::UnexpectedString(s)),
         }
     }
 
+    /// Adds the names of the variables read by the expression to reads, and of those assigned to assigned.
+    pub fn variables(&self, reads: &mut Vec<String>, assigned: &mut Vec<String>) {
+        match self {
+            Expression::VariableAssign { name, child } => {
+                assigned.push(name.clone());
+                child.variables(reads, assigned);
+            }
+            Expression::Operator { left, right, .. } => {
+                left.variables(reads, assigned);
+                right.variables(reads, assigned);
+            }
+            Expression::FunctionCall { args, .. } => {
+                args.iter().for_each(|a| a.variables(reads, assigned))
+            }
+            Expression::DefinedUnit { child, .. }
+            | Expression::LiteralUnit { child, .. }
+            | Expression::Convert { child, .. }
+            | Expression::Negate(child)
+            | Expression::UnaryOperator { child, .. } => child.variables(reads, assigned),
+            Expression::VariableRef(name) | Expression::ExpressionRef(name) => {
+                reads.push(name.clone())
+            }
+            Expression::NumberLiteral(_) => {}
+        }
+    }
+
+    /// The number of expressions in the expression, including itself.
+    pub fn size(&self) -> usize {
+        let mut stack = vec![self];
+        let mut size = 0;
+        while let Some(exp) = stack.pop() {
+            size += 1;
+            match exp {
+                Expression::Operator { left, right, .. } => stack.extend([left.as_ref(), right]),
+                Expression::FunctionCall { args, .. } => stack.extend(args),
+                Expression::VariableAssign { child, .. }
+                | Expression::DefinedUnit { child, .. }
+                | Expression::LiteralUnit { child, .. }
+                | Expression::Convert { child, .. }
+                | Expression::Negate(child)
+                | Expression::UnaryOperator { child, .. } => stack.push(child),
+                Expression::VariableRef(_)
+                | Expression::ExpressionRef(_)
+                | Expression::NumberLiteral(_) => {}
+            }
+        }
+        size
+    }
+
     pub fn eval<LP: LibraryProvider>(
         &self,
         provider: &LP,
         context: &mut EvaluationContext,
     ) -> Result<(f64, Unit), EvaluationError<LP::LibraryError>> {
+        self.eval_nested(provider, context, 0)
+    }
+
+    fn eval_nested<LP: LibraryProvider>(
+        &self,
+        provider: &LP,
+        context: &mut EvaluationContext,
+        depth: usize,
+    ) -> Result<(f64, Unit), EvaluationError<LP::LibraryError>> {
+        if depth > context.max_depth {
+            return Err(EvaluationError::TooDeep {
+                max_depth: context.max_depth,
+            });
+        }
+        let depth = depth + 1;
         match &self {
             Expression::VariableAssign { name, child } => {
-                let res = child.eval(provider, context)?;
+                let res = child.eval_nested(provider, context, depth)?;
+                // read before storing, eg. x in x = x + 1 is the old value
+                let mut reads = Vec::new();
+                child.variables(&mut reads, &mut Vec::new());
+                let values = reads
+                    .into_iter()
+                    .filter_map(|name| context.get_variable(&name).map(|v| (name, v)))
+                    .collect();
                 context.store_variable(name, res.clone());
+                // references are stored by name, and only expanded when formatting
+                let exp = VariableExpression {
+                    expression: child.as_ref().clone(),
+                    values,
+                };
+                context.expressions.insert(name.clone(), Rc::new(exp));
                 Ok(res)
             }
-            Expression::Operator {
-                operator,
-                left,
-                right,
-            } => {
-                let (l_v, l_u) = left.eval(provider, context)?;
-                let (r_v, r_u) = right.eval(provider, context)?;
-                let res_v = provider.eval_operator(operator, l_v, r_v)?;
-                let res_u = match l_u {
-                    Unit::Defined(l_d) => match r_u {
-                        Unit::Defined(r_d) => Unit::Defined(DefinedUnit::Implicit {
-                            operator: operator.clone(),
-                            associative: provider.operator_associative(operator),
-                            left: Box::new(l_d),
-                            right: Box::new(r_d),
-                        }),
-                        Unit::Literal(_) | Unit::None => Unit::Defined(l_d),
-                    },
-                    Unit::Literal(l_s) => match r_u {
-                        Unit::Defined(r_s) => Unit::Defined(r_s),
-                        Unit::Literal(_) => Unit::None,
-                        Unit::None => Unit::Literal(l_s),
-                    },
-                    Unit::None => r_u,
-                };
-                Ok((res_v, res_u))
+            Expression::Operator { .. } => {
+                // left operands are walked iteratively, so a long chain like a + b + c is not counted as nesting
+                let mut chain = Vec::new();
+                let mut exp: &Expression = self;
+                while let Expression::Operator { left, .. } = exp {
+                    chain.push(exp);
+                    exp = left;
+                }
+                let (mut v, mut u) = exp.eval_nested(provider, context, depth)?;
+                for exp in chain.into_iter().rev() {
+                    let Expression::Operator { operator, right, .. } = exp else {
+                        unreachable!("only operators are in the chain")
+                    };
+                    let (r_v, r_u) = right.eval_nested(provider, context, depth)?;
+                    v = provider.eval_operator(operator, v, r_v)?;
+                    u = combine_units(provider, operator, u, r_u);
+                }
+                Ok((v, u))
             }
             Expression::FunctionCall { function, args } => {
-                let r = provider.eval_function(
-                    function,
-                    &args
-                        .iter()
-                        .map(|arg| arg.eval(provider, context).map(|(v, _)| v))
-                        .collect::<Result<Vec<_>, _>>()?,
-                )?;
-                Ok((r, Unit::None))
-            }
-            Expression::VariableRef(name) => {
+                let (values, units): (Vec<_>, Vec<_>) = args
+                    .iter()
+                    .map(|arg| arg.eval_nested(provider, context, depth))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter()
+                    .unzip();
+                let unit = provider.function_unit(function, &units)?;
+                let r = provider.eval_function(function, &values)?;
+                Ok((r, unit))
+            }
+            Expression::VariableRef(name) | Expression::ExpressionRef(name) => {
                 if let Some(r) = context.get_variable(name) {
                     Ok(r)
                 } else {
@@ -241,7 +703,7 @@ impl Expression {
                 }
             }
             Expression::DefinedUnit { name, child } => {
-                let (r, _) = child.eval(provider, context)?;
+                let (r, _) = child.eval_nested(provider, context, depth)?;
                 Ok((
                     r,
                     name.as_ref().map_or(Unit::None, |n| {
@@ -250,24 +712,265 @@ impl Expression {
                 ))
             }
             Expression::LiteralUnit { name, child } => {
-                let (r, _) = child.eval(provider, context)?;
+                let (r, _) = child.eval_nested(provider, context, depth)?;
                 Ok((r, Unit::Literal(name.clone())))
             }
+            Expression::Convert { unit, child } => {
+                let (v, u) = child.eval_nested(provider, context, depth)?;
+                let from = match u {
+                    Unit::Defined(DefinedUnit::Defined(n)) | Unit::Literal(n) => Some(n),
+                    _ => None,
+                };
+                let factor = from
+                    .as_ref()
+                    .and_then(|from| context.get_conversion(from, unit.name()));
+                let Some(factor) = factor else {
+                    return Err(EvaluationError::MissingConversion {
+                        from,
+                        to: unit.name().to_string(),
+                    });
+                };
+                let unit = match unit {
+                    UnitName::Defined(n) => Unit::Defined(DefinedUnit::Defined(n.clone())),
+                    UnitName::Literal(n) => Unit::Literal(n.clone()),
+                };
+                Ok((v * factor, unit))
+            }
             Expression::NumberLiteral(num) => Ok((*num, Unit::None)),
             Expression::Negate(expr) => {
-                let (r, u) = expr.eval(provider, context)?;
-                Ok((-r, u))
+                let (r, u) = expr.eval_nested(provider, context, depth)?;
+                Ok((provider.eval_unary_operator("-", r)?, u))
+            }
+            Expression::UnaryOperator { operator, child } => {
+                let (r, u) = child.eval_nested(provider, context, depth)?;
+                Ok((provider.eval_unary_operator(operator, r)?, u))
+            }
+        }
+    }
+
+    /// Like [eval](Self::eval), but [None] instead of an error when a variable is not known, so the expression stays symbolic.
+    pub fn partial_eval<LP: LibraryProvider>(
+        &self,
+        provider: &LP,
+        context: &mut EvaluationContext,
+    ) -> Result<Option<(f64, Unit)>, EvaluationError<LP::LibraryError>> {
+        match self.eval(provider, context) {
+            Ok(res) => Ok(Some(res)),
+            Err(EvaluationError::MissingVariable { .. }) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Checks that operands of operators like `+` have the same unit, without evaluating any values.  
+    /// Variables are looked up in the context, unknown variables have no unit.
+    pub fn check_units<LP: LibraryProvider>(
+        &self,
+        provider: &LP,
+        context: &EvaluationContext,
+    ) -> Result<(), UnitError<LP::LibraryError>> {
+        self.static_unit(provider, context).map(|_| ())
+    }
+
+    fn static_unit<LP: LibraryProvider>(
+        &self,
+        provider: &LP,
+        context: &EvaluationContext,
+    ) -> Result<Unit, UnitError<LP::LibraryError>> {
+        match &self {
+            Expression::Operator {
+                operator,
+                left,
+                right,
+            } => {
+                let l_u = left.static_unit(provider, context)?;
+                let r_u = right.static_unit(provider, context)?;
+                if !provider.operator_requires_equal_units(operator)
+                    || l_u == Unit::None
+                    || r_u == Unit::None
+                {
+                    Ok(combine_units(provider, operator, l_u, r_u))
+                } else if same_unit(&l_u, &r_u) {
+                    // eg. Meter + Meter is still Meter
+                    Ok(l_u)
+                } else {
+                    Err(UnitError::IncompatibleUnits {
+                        operator: operator.clone(),
+                        left: unit_name(&l_u),
+                        right: unit_name(&r_u),
+                    })
+                }
+            }
+            Expression::FunctionCall { function, args } => {
+                let units = args
+                    .iter()
+                    .map(|arg| arg.static_unit(provider, context))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(provider.function_unit(function, &units)?)
+            }
+            Expression::VariableRef(name) | Expression::ExpressionRef(name) => {
+                Ok(context.get_variable(name).map_or(Unit::None, |(_, u)| u))
+            }
+            Expression::DefinedUnit { name, child } => {
+                child.static_unit(provider, context)?;
+                Ok(name.as_ref().map_or(Unit::None, |n| {
+                    Unit::Defined(DefinedUnit::Defined(n.clone()))
+                }))
+            }
+            Expression::LiteralUnit { name, child } => {
+                child.static_unit(provider, context)?;
+                Ok(Unit::Literal(name.clone()))
+            }
+            Expression::Convert { unit, child } => {
+                child.static_unit(provider, context)?;
+                Ok(match unit {
+                    UnitName::Defined(n) => Unit::Defined(DefinedUnit::Defined(n.clone())),
+                    UnitName::Literal(n) => Unit::Literal(n.clone()),
+                })
             }
+            Expression::NumberLiteral(_) => Ok(Unit::None),
+            Expression::VariableAssign { child, .. }
+            | Expression::Negate(child)
+            | Expression::UnaryOperator { child, .. } => child.static_unit(provider, context),
         }
     }
 }
 
-fn parse_number(val: &str) -> Result<f64, ExpressionError> {
-    if let Ok(v) = val.parse() {
-        Ok(v)
+/// The unit of an operator result, see the *units* section of the readme.
+fn combine_units(provider: &impl LibraryProvider, operator: &str, left: Unit, right: Unit) -> Unit {
+    match left {
+        Unit::Defined(l_d) => match right {
+            Unit::Defined(r_d) => Unit::Defined(DefinedUnit::Implicit {
+                operator: operator.to_string(),
+                associative: provider.operator_associative(operator),
+                left: Box::new(l_d),
+                right: Box::new(r_d),
+            }),
+            Unit::Literal(_) | Unit::None => Unit::Defined(l_d),
+        },
+        Unit::Literal(l_s) => match right {
+            Unit::Defined(r_s) => Unit::Defined(r_s),
+            Unit::Literal(_) => Unit::None,
+            Unit::None => Unit::Literal(l_s),
+        },
+        Unit::None => right,
+    }
+}
+
+/// A unit as written in an expression, for error messages.
+/// Equal up to the order of operands of associative operators, so Meter * Second is the same as Second * Meter.
+fn same_unit(left: &Unit, right: &Unit) -> bool {
+    match (left, right) {
+        (Unit::Defined(l), Unit::Defined(r)) => canonical_name(l) == canonical_name(r),
+        _ => left == right,
+    }
+}
+
+fn canonical_name(unit: &DefinedUnit) -> String {
+    match unit {
+        DefinedUnit::Defined(name) => name.clone(),
+        DefinedUnit::Implicit {
+            operator,
+            associative: true,
+            ..
+        } => {
+            let mut operands = Vec::new();
+            collect_operands(unit, operator, &mut operands);
+            let mut names: Vec<_> = operands.into_iter().map(canonical_name).collect();
+            names.sort();
+            format!("({})", names.join(&format!(" {operator} ")))
+        }
+        DefinedUnit::Implicit {
+            operator,
+            left,
+            right,
+            ..
+        } => format!("({} {operator} {})", canonical_name(left), canonical_name(right)),
+    }
+}
+
+/// The operands of a chain of the same associative operator, eg. a, b and c for (a * b) * c.
+fn collect_operands<'a>(unit: &'a DefinedUnit, operator: &str, operands: &mut Vec<&'a DefinedUnit>) {
+    match unit {
+        DefinedUnit::Implicit {
+            operator: o,
+            associative: true,
+            left,
+            right,
+        } if o == operator => {
+            collect_operands(left, operator, operands);
+            collect_operands(right, operator, operands);
+        }
+        _ => operands.push(unit),
+    }
+}
+
+fn unit_name(unit: &Unit) -> String {
+    match unit {
+        Unit::Defined(DefinedUnit::Defined(name)) => name.clone(),
+        Unit::Defined(DefinedUnit::Implicit {
+            operator,
+            left,
+            right,
+            ..
+        }) => format!(
+            "({} {operator} {})",
+            unit_name(&Unit::Defined(left.as_ref().clone())),
+            unit_name(&Unit::Defined(right.as_ref().clone()))
+        ),
+        Unit::Literal(name) => format!("\"{name}\""),
+        Unit::None => "None".to_string(),
+    }
+}
+
+fn parse_number(source: &str) -> Result<f64, ExpressionError> {
+    let val = source.replace('_', "");
+    let parsed = if let Some(hex) = val.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok().map(|v| v as f64)
+    } else if let Some(bin) = val.strip_prefix("0b") {
+        u64::from_str_radix(bin, 2).ok().map(|v| v as f64)
     } else {
-        Err(ExpressionError::InvalidNumber(val.to_string()))
+        val.parse().ok()
+    };
+    parsed.ok_or_else(|| ExpressionError::InvalidNumber(source.to_string()))
+}
+
+/// Moves operators binding tighter than negation into the negated child, so `-2 ** 2` becomes `-(2 ** 2)`.
+fn group_negations(
+    provider: &impl LibraryProvider,
+    operators: Vec<String>,
+    children: Vec<TokenTree>,
+) -> (Vec<String>, Vec<TokenTree>) {
+    let mut ops = operators.into_iter().peekable();
+    let mut children = children.into_iter();
+    let mut res_ops = Vec::new();
+    let mut res_children = Vec::new();
+    while let Some(child) = children.next() {
+        if let TokenTree::Negate(inner) = child {
+            let mut run_ops = Vec::new();
+            let mut run_children = vec![*inner];
+            while let Some(op) = ops.next_if(|op| {
+                provider.operator_precedence(op) > provider.negation_precedence()
+            }) {
+                run_ops.push(op);
+                run_children.push(children.next().expect("opseq has one more child than operators"));
+            }
+            let negated = if run_ops.is_empty() {
+                run_children.pop().unwrap()
+            } else {
+                TokenTree::OperatorSequence {
+                    operators: run_ops,
+                    children: run_children,
+                }
+            };
+            res_children.push(TokenTree::Negate(Box::new(negated)));
+        } else {
+            res_children.push(child);
+        }
+        if let Some(op) = ops.next() {
+            res_ops.push(op);
+        }
     }
+    (res_ops, res_children)
 }
 
 /// Must have independent tree for transforming operators, to not get mixed up with already transformed operators
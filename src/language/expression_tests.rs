@@ -1,14 +1,12 @@
-use crate::language::expression::{EvaluationContext, Expression, LibraryProvider};
-use crate::language::parse::{TokenTree, tokenize};
-use std::f32::consts::E;
+use crate::language::expression::{DefinedUnit, EvaluationContext, Expression, LibraryProvider, Unit};
+use crate::language::parse::{ParseOptions, TokenTree, tokenize, tokenize_with};
 
 /// Real one would be [FormattableLibraryProvider](crate::language::format::FormattableLibraryProvider)
-
 struct MockLibraryProvider;
 impl LibraryProvider for MockLibraryProvider {
     type LibraryError = String;
 
-    fn function_exists(&self, name: &str, param_c: usize) -> bool {
+    fn function_exists(&self, name: &str, _param_c: usize) -> bool {
         name == "sum"
     }
 
@@ -16,7 +14,15 @@ impl LibraryProvider for MockLibraryProvider {
         matches!(symbol, "+" | "-" | "*" | "/" | "^" | "?")
     }
 
-    fn eval_function(&self, name: &str, params: &[f64]) -> Result<f64, Self::LibraryError> {
+    fn function_unit(&self, _name: &str, _arg_units: &[Unit]) -> Result<Unit, Self::LibraryError> {
+        Ok(Unit::None)
+    }
+
+    fn unary_operator_exists(&self, symbol: &str) -> bool {
+        symbol == "!"
+    }
+
+    fn eval_function(&self, _name: &str, params: &[f64]) -> Result<f64, Self::LibraryError> {
         Ok(params.iter().sum())
     }
 
@@ -43,10 +49,22 @@ impl LibraryProvider for MockLibraryProvider {
         }
     }
 
+    fn eval_unary_operator(&self, symbol: &str, value: f64) -> Result<f64, Self::LibraryError> {
+        match symbol {
+            "!" => Ok((1..=value as u64).product::<u64>() as f64),
+            "-" => Ok(-value),
+            _ => panic!("illegal unary operator"),
+        }
+    }
+
     fn operator_associative(&self, symbol: &str) -> bool {
         symbol != "/" && symbol != "^" && symbol != "-"
     }
 
+    fn operator_requires_equal_units(&self, symbol: &str) -> bool {
+        symbol == "+" || symbol == "-"
+    }
+
     fn operator_precedence(&self, symbol: &str) -> u32 {
         match symbol {
             "+" | "-" => 0,
@@ -56,6 +74,18 @@ impl LibraryProvider for MockLibraryProvider {
             _ => panic!("Unknown operator {}", symbol),
         }
     }
+
+    fn negation_precedence(&self) -> u32 {
+        1
+    }
+}
+
+fn eval_source(source: &str) -> f64 {
+    let tree = tokenize(source).unwrap();
+    let exp = Expression::new(tree, &MockLibraryProvider).unwrap();
+    exp.eval(&MockLibraryProvider, &mut EvaluationContext::new())
+        .unwrap()
+        .0
 }
 
 fn assert_expr(inp: TokenTree, out: Expression) {
@@ -158,6 +188,8 @@ fn functions() {
             args: vec![Expression::NumberLiteral(1.), Expression::NumberLiteral(2.)],
         },
     );
+    // user defined zero argument call, parsed from source
+    assert_eq!(eval_source("sum() + 1"), 1.);
 }
 
 #[test]
@@ -300,6 +332,36 @@ fn operator_sequence() {
     )
 }
 
+#[test]
+fn operator_sequence_shapes() {
+    let shape = |source: &str| {
+        Expression::new(tokenize(source).unwrap(), &MockLibraryProvider)
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(shape("1 + 2 + 3 + 4"), "(((1 + 2) + 3) + 4)");
+    assert_eq!(shape("1 * 2 + 3 * 4"), "((1 * 2) + (3 * 4))");
+    assert_eq!(shape("1 + 2 * 3 ^ 4 ? 5"), "(1 + (2 * (3 ^ (4 ? 5))))");
+    assert_eq!(shape("1 ? 2 ^ 3 * 4 + 5"), "((((1 ? 2) ^ 3) * 4) + 5)");
+    assert_eq!(shape("1 + 2 ^ 3 - 4 / 5 * 6"), "((1 + (2 ^ 3)) - ((4 / 5) * 6))");
+    assert_eq!(shape("1 * 2 ^ 3 + 4 ^ 5 * 6 ? 7 - 8"), "(((1 * (2 ^ 3)) + ((4 ^ 5) * (6 ? 7))) - 8)");
+}
+
+#[test]
+fn long_operator_sequence() {
+    let source = (1..=1000).map(|i| i.to_string()).collect::<Vec<_>>().join(" + ");
+    let mut exp = Expression::new(tokenize(&source).unwrap(), &MockLibraryProvider).unwrap();
+    // left leaning, walked without recursion
+    for i in (2..=1000).rev() {
+        let Expression::Operator { left, right, .. } = exp else {
+            panic!("expected operator");
+        };
+        assert_eq!(*right, Expression::NumberLiteral(i as f64));
+        exp = *left;
+    }
+    assert_eq!(exp, Expression::NumberLiteral(1.));
+}
+
 #[test]
 fn eval() {
     let mut ctxt = EvaluationContext::new();
@@ -334,3 +396,247 @@ fn eval() {
     // unwrap is part of test as it should not be err
     assert_eq!(expr.eval(&MockLibraryProvider, &mut ctxt).unwrap().0, 42.)
 }
+
+#[test]
+fn left_associative() {
+    assert_eq!(eval_source("10 - 3 - 2"), 5.);
+    assert_eq!(eval_source("100 / 5 / 2"), 10.);
+    assert_eq!(eval_source("10 - 3 + 2"), 9.);
+    assert_eq!(eval_source("100 / 5 * 2"), 40.);
+    assert_expr(
+        tokenize("10 - 3 - 2").unwrap(),
+        Expression::Operator {
+            operator: "-".into(),
+            left: Box::new(Expression::Operator {
+                operator: "-".into(),
+                left: Box::new(Expression::NumberLiteral(10.)),
+                right: Box::new(Expression::NumberLiteral(3.)),
+            }),
+            right: Box::new(Expression::NumberLiteral(2.)),
+        },
+    );
+}
+
+#[test]
+fn implicit_negated_product() {
+    let options = ParseOptions {
+        implicit_multiplication: true,
+        ..Default::default()
+    };
+    let eval = |source| {
+        let exp = Expression::new(tokenize_with(source, &options).unwrap(), &MockLibraryProvider).unwrap();
+        exp.eval(&MockLibraryProvider, &mut EvaluationContext::new()).unwrap().0
+    };
+    assert_eq!(eval("-2(3)"), eval_source("-(2 * 3)"));
+    assert_eq!(eval("-2(3)^2"), -18.);
+}
+
+#[test]
+fn negate_power() {
+    assert_eq!(eval_source("-2 ^ 2"), -4.);
+    assert_eq!(eval_source("(-2) ^ 2"), 4.);
+    assert_eq!(eval_source("3 * -2 ^ 2"), -12.);
+    assert_eq!(eval_source("-2 ^ 2 + 1"), -3.);
+    assert_eq!(eval_source("-2 * 3 + 1"), -5.);
+    assert_expr(
+        tokenize("-2 ^ 2").unwrap(),
+        Expression::Negate(Box::new(Expression::Operator {
+            operator: "^".into(),
+            left: Box::new(Expression::NumberLiteral(2.)),
+            right: Box::new(Expression::NumberLiteral(2.)),
+        })),
+    );
+}
+
+#[test]
+fn convert() {
+    let mut ctxt = EvaluationContext::new();
+    ctxt.add_conversion("h", "s", 3600.);
+    let mut eval = |source: &str| {
+        Expression::new(tokenize(source).unwrap(), &MockLibraryProvider)
+            .unwrap()
+            .eval(&MockLibraryProvider, &mut ctxt)
+    };
+
+    let (v, u) = eval("to(3600 s, \"h\")").unwrap();
+    assert!((v - 1.).abs() < 1e-9);
+    assert!(matches!(u, Unit::Literal(n) if n == "h"));
+    let (v, u) = eval("to(2 \"h\", s)").unwrap();
+    assert_eq!(v, 7200.);
+    assert!(matches!(u, Unit::Defined(DefinedUnit::Defined(n)) if n == "s"));
+
+    assert!(eval("to(60 s, \"min\")").is_err());
+    // no unit to convert from
+    assert!(eval("to(60, \"h\")").is_err());
+    assert!(Expression::new(tokenize("1 + \"h\"").unwrap(), &MockLibraryProvider).is_err());
+}
+
+#[test]
+fn unary_operator() {
+    assert_eq!(eval_source("!4"), 24.);
+    assert_eq!(eval_source("2 * !3 + 1"), 13.);
+    assert_eq!(eval_source("-!3"), -6.);
+    assert!(Expression::new(tokenize("~4").unwrap(), &MockLibraryProvider).is_err());
+}
+
+#[test]
+fn iterate_variables() {
+    let mut ctx = EvaluationContext::new();
+    assert!(ctx.is_empty());
+    ctx.store_variable("a", (1., Unit::None));
+    ctx.store_variable("b", (2., Unit::Literal("m".into())));
+    ctx.store_variable("a", (3., Unit::None));
+    assert_eq!(ctx.len(), 2);
+    let mut vars: Vec<_> = ctx.iter().map(|(name, (value, _))| (name, *value)).collect();
+    vars.sort_by(|a, b| a.0.cmp(b.0));
+    assert_eq!(vars, [("a", 3.), ("b", 2.)]);
+}
+
+#[test]
+fn variable_expressions() {
+    let mut ctx = EvaluationContext::new();
+    let eval = |source: &str, ctx: &mut EvaluationContext| {
+        Expression::new(tokenize(source).unwrap(), &MockLibraryProvider)
+            .unwrap()
+            .eval(&MockLibraryProvider, ctx)
+            .unwrap()
+    };
+    eval("w = 4", &mut ctx);
+    eval("area = 3 * w", &mut ctx);
+    let area = Expression::Operator {
+        operator: "*".into(),
+        left: Box::new(Expression::NumberLiteral(3.)),
+        right: Box::new(Expression::VariableRef("w".into())),
+    };
+    assert_eq!(ctx.get_variable_expr("area").unwrap().expression, area);
+    assert_eq!(ctx.get_variable_expr("w").unwrap().expression, Expression::NumberLiteral(4.));
+    assert_eq!(ctx.get_variable_expr("area").unwrap().values["w"].0, 4.);
+    assert!(ctx.get_variable_expr("missing").is_none());
+    // references are stored by name, and evaluate to the value
+    assert_eq!(eval("double = !area + !area", &mut ctx).0, 24.);
+    assert_eq!(ctx.get_variable_expr("double").unwrap().expression.to_string(), "(!area + !area)");
+}
+
+#[test]
+fn mutual_variable_expressions() {
+    let mut ctx = EvaluationContext::new();
+    let mut eval = |source: &str| {
+        Expression::new(tokenize(source).unwrap(), &MockLibraryProvider)
+            .unwrap()
+            .eval(&MockLibraryProvider, &mut ctx)
+    };
+    // a reference to a variable that has no value yet can not start a cycle
+    assert!(eval("a = !a").is_err());
+    eval("b = 2").unwrap();
+    eval("a = !b * 3").unwrap();
+    // references are stored by name, so b refers back to a, which only formatting rejects
+    eval("b = !a + 1").unwrap();
+    assert_eq!(eval("a = !b").unwrap().0, 7.);
+    // values are read before assigning
+    eval("c = 1").unwrap();
+    eval("c = c + 1").unwrap();
+    assert_eq!(ctx.get_variable_expr("a").unwrap().expression.to_string(), "!b");
+    assert_eq!(ctx.get_variable_expr("b").unwrap().expression.to_string(), "(!a + 1)");
+    assert_eq!(ctx.get_variable_expr("c").unwrap().values["c"].0, 1.);
+}
+
+#[test]
+fn scopes() {
+    let mut ctx = EvaluationContext::new();
+    ctx.store_variable("x", (1., Unit::None));
+    ctx.push_scope();
+    assert_eq!(ctx.get_variable("x").unwrap().0, 1.);
+    ctx.store_variable("x", (2., Unit::None));
+    ctx.store_variable("i", (5., Unit::None));
+    assert_eq!(ctx.get_variable("x").unwrap().0, 2.);
+    // shadowed x is only listed once
+    assert_eq!(ctx.len(), 2);
+    ctx.pop_scope();
+    assert_eq!(ctx.get_variable("x").unwrap().0, 1.);
+    assert!(ctx.get_variable("i").is_none());
+    assert_eq!(ctx.len(), 1);
+}
+
+#[test]
+fn context_round_trip() {
+    let speed = DefinedUnit::Implicit {
+        operator: "/".into(),
+        associative: false,
+        left: Box::new(DefinedUnit::Defined("Meter".into())),
+        right: Box::new(DefinedUnit::Implicit {
+            operator: "*".into(),
+            associative: true,
+            left: Box::new(DefinedUnit::Defined("Second".into())),
+            right: Box::new(DefinedUnit::Defined("Second".into())),
+        }),
+    };
+    let mut ctx = EvaluationContext::new();
+    ctx.store_variable("a", (9.81, Unit::Defined(speed)));
+    ctx.store_variable("h", (0.1 + 0.2, Unit::Literal("m;(x)".into())));
+    ctx.store_variable("n", (-3., Unit::None));
+    let saved = ctx.to_string();
+    assert_eq!(saved, "a;9.81;(/ n Meter (* a Second Second))\nh;0.30000000000000004;\"m;(x)\nn;-3;\n");
+    let loaded: EvaluationContext = saved.parse().unwrap();
+    for name in ["a", "h", "n"] {
+        assert!(loaded.get_variable(name) == ctx.get_variable(name), "{name}");
+    }
+    assert_eq!(loaded.len(), 3);
+    assert!("a;1;(/ n Meter".parse::<EvaluationContext>().is_err());
+    assert!("a;x;".parse::<EvaluationContext>().is_err());
+}
+
+#[test]
+fn static_unit_check() {
+    let mut ctx = EvaluationContext::new();
+    ctx.store_variable("v", (3., Unit::Defined(DefinedUnit::Defined("Speed".into()))));
+    let check = |source: &str, ctx: &EvaluationContext| {
+        Expression::new(tokenize(source).unwrap(), &MockLibraryProvider)
+            .unwrap()
+            .check_units(&MockLibraryProvider, ctx)
+    };
+    assert!(check("2 Meter / 4 Second + v * 2 - 1", &ctx).is_err());
+    assert!(check("v + 2 Speed - v * 3", &ctx).is_ok());
+    assert!(check("2 Meter * 3 Second + 1 + sum(2 Meter, 3 Second)", &ctx).is_ok());
+    // operands of associative operators can be in any order
+    assert!(check("2 Meter * 3 Second + 3 Second * 2 Meter", &ctx).is_ok());
+    assert!(check("(2 Meter * 3 Second) * 1 Speed - 1 Speed * (3 Second * 2 Meter)", &ctx).is_ok());
+    assert!(check("2 Meter / 3 Second + 3 Second / 2 Meter", &ctx).is_err());
+    assert_eq!(
+        format!("{:?}", check("2 Meter + 3 Second", &ctx).unwrap_err()),
+        "Can not use '+' between Meter and Second"
+    );
+    assert_eq!(
+        format!("{:?}", check("x = (2 Meter / 1 Second) - v", &ctx).unwrap_err()),
+        "Can not use '-' between (Meter / Second) and Speed"
+    );
+    // values are never evaluated, so dividing by zero is fine
+    assert!(check("1 / 0 + unknown", &ctx).is_ok());
+}
+
+#[test]
+fn max_depth() {
+    let mut nested = Expression::NumberLiteral(1.);
+    for _ in 0..5000 {
+        nested = Expression::Negate(Box::new(nested));
+    }
+    let mut ctx = EvaluationContext::new();
+    let Err(err) = nested.eval(&MockLibraryProvider, &mut ctx) else {
+        panic!("deeply nested expression should fail");
+    };
+    assert_eq!(format!("{err:?}"), "Expression is nested deeper than 128 levels");
+
+    let exp = Expression::new(tokenize("-(-(1 + 2) * 3) - 4").unwrap(), &MockLibraryProvider).unwrap();
+    ctx.set_max_depth(5);
+    assert_eq!(exp.eval(&MockLibraryProvider, &mut ctx).unwrap().0, 5.);
+    ctx.set_max_depth(4);
+    assert!(exp.eval(&MockLibraryProvider, &mut ctx).is_err());
+}
+
+#[test]
+fn long_operator_chain() {
+    // the operators of a chain are not nesting
+    let sum = format!("1{}", " + 1".repeat(999));
+    assert_eq!(eval_source(&sum), 1000.);
+    let product = format!("1{}", " * 1".repeat(999));
+    assert_eq!(eval_source(&product), 1.);
+}
@@ -1,5 +1,7 @@
+mod custom_operator;
 mod library_provider;
 
+pub use custom_operator::*;
 pub use library_provider::*;
 
 use crate::language::expression::{
@@ -9,6 +11,7 @@ use crate::language::expression::{
 /// A sort of middleman between an [Expression] and a [String].  
 /// The Unit is generic because it can be either [Unit](crate::language::expression::Unit) or [Option<String>].   
 /// The former case is defined as an [UnresolvedFormattableExpression], and units are still not resolved.   
+#[derive(Clone, PartialEq)]
 pub enum FormattableExpression<Unit> {
     Function {
         name: String,
@@ -19,13 +22,32 @@ pub enum FormattableExpression<Unit> {
         left: Box<FormattableExpression<Unit>>,
         right: Box<FormattableExpression<Unit>>,
     },
+    /// A chain of the same associative operator, eg. `a + b + c`, see [FormattableLibraryProvider::set_flatten_associative].
+    OperatorChain {
+        operator: String,
+        operands: Vec<FormattableExpression<Unit>>,
+    },
     Negate(Box<FormattableExpression<Unit>>),
+    UnaryOperator {
+        operator: String,
+        child: Box<FormattableExpression<Unit>>,
+    },
     Parenthesis(Box<FormattableExpression<Unit>>),
     Variable(String),
+    /// A variable with its unit, see [ValueMode::NamedVariableUnit].
+    AnnotatedVariable {
+        name: String,
+        unit: Unit,
+    },
     Number {
         value: f64,
         unit: Unit,
     },
+    /// `to(child, unit)`, written with the unit it converts to.
+    Conversion {
+        child: Box<FormattableExpression<Unit>>,
+        unit: Unit,
+    },
 }
 
 impl<U> FormattableExpression<U> {
@@ -40,26 +62,51 @@ impl<U> FormattableExpression<U> {
                 name,
                 args: args.into_iter().map(|e| e.map_unit_impl(f)).collect(),
             },
-            Self::Operator {
-                operator,
-                left,
-                right,
-            } => FormattableExpression::<O>::Operator {
+            Self::Operator { .. } => {
+                // left operands are walked iteratively, so long chains like a + b + c do not overflow the stack
+                let mut chain = Vec::new();
+                let mut exp = self;
+                while let Self::Operator { operator, left, right } = exp {
+                    chain.push((operator, right));
+                    exp = *left;
+                }
+                let mut res = exp.map_unit_impl(f);
+                for (operator, right) in chain.into_iter().rev() {
+                    res = FormattableExpression::<O>::Operator {
+                        operator,
+                        left: Box::new(res),
+                        right: Box::new(right.map_unit_impl(f)),
+                    };
+                }
+                res
+            }
+            Self::OperatorChain { operator, operands } => FormattableExpression::<O>::OperatorChain {
                 operator,
-                left: Box::new(left.map_unit_impl(f)),
-                right: Box::new(right.map_unit_impl(f)),
+                operands: operands.into_iter().map(|e| e.map_unit_impl(f)).collect(),
             },
             Self::Negate(child) => {
                 FormattableExpression::<O>::Negate(Box::new(child.map_unit_impl(f)))
             }
+            Self::UnaryOperator { operator, child } => FormattableExpression::<O>::UnaryOperator {
+                operator,
+                child: Box::new(child.map_unit_impl(f)),
+            },
             Self::Parenthesis(child) => {
                 FormattableExpression::<O>::Parenthesis(Box::new(child.map_unit_impl(f)))
             }
             Self::Variable(name) => FormattableExpression::<O>::Variable(name),
+            Self::AnnotatedVariable { name, unit } => FormattableExpression::<O>::AnnotatedVariable {
+                name,
+                unit: f(unit),
+            },
             Self::Number { value, unit } => FormattableExpression::<O>::Number {
                 value,
                 unit: f(unit),
             },
+            Self::Conversion { child, unit } => FormattableExpression::<O>::Conversion {
+                child: Box::new(child.map_unit_impl(f)),
+                unit: f(unit),
+            },
         }
     }
 }
@@ -77,17 +124,39 @@ pub trait LanguageFormatter: Sized {
         out: &mut String,
     );
 
-    fn negate(
-        &self,
-        lib: &FormattableLibraryProvider<Self>,
-        expr: &ResolvedFormattableExpression,
-        out: &mut String,
-    );
-
     fn write_number(&self, number: f64, unit: Option<&str>, out: &mut String);
 
+    /// Writes a unit on its own, styled like the units written by [write_number](Self::write_number).
+    fn write_unit(&self, unit: &str, out: &mut String) {
+        out.push_str(&format!("\\small\\text{{{}}}\\normalsize", escape_text(unit)));
+    }
+
+    /// See [LibraryProvider::negation_precedence].
+    fn negation_precedence(&self) -> u32;
+
     fn write_variable(&self, variable: &str, out: &mut String);
 
+    /// Writes a variable followed by its unit, see [ValueMode::NamedVariableUnit].
+    fn write_annotated_variable(&self, variable: &str, unit: &str, out: &mut String) {
+        self.write_variable(variable, out);
+        out.push_str("\\,[");
+        self.write_unit(unit, out);
+        out.push(']');
+    }
+
+    /// Writes `to(child, unit)`, eg. `3600 s → h`.
+    fn write_conversion(
+        &self,
+        lib: &FormattableLibraryProvider<Self>,
+        child: &ResolvedFormattableExpression,
+        unit: &str,
+        out: &mut String,
+    ) {
+        lib.write_expression(child, out);
+        out.push_str(" \\to ");
+        self.write_unit(unit, out);
+    }
+
     fn format_single(
         &self,
         lib: &FormattableLibraryProvider<Self>,
@@ -95,15 +164,27 @@ pub trait LanguageFormatter: Sized {
         result: Option<&ResolvedFormattableExpression>,
     ) -> String;
 
+    /// Each row is a chain of equalities like in [format_steps](Self::format_steps), aligned on the first relation.  
+    /// Rows with a single expression have no relation and are left-aligned.
     fn format_multi(
         &self,
         lib: &FormattableLibraryProvider<Self>,
-        expr: &[(ResolvedFormattableExpression, ResolvedFormattableExpression)],
+        rows: &[Vec<ResolvedFormattableExpression>],
+    ) -> String;
+
+    /// Formats the stages of one calculation as a chain of equalities, eg. `A = l w = 3 \cdot 4 = 12`.
+    fn format_steps(
+        &self,
+        lib: &FormattableLibraryProvider<Self>,
+        steps: &[ResolvedFormattableExpression],
     ) -> String;
 
     fn build_operators(&self) -> Vec<Box<dyn FormattableOperator<Self>>>;
 
     fn build_functions(&self) -> Vec<Box<dyn FormattableFunction<Self>>>;
+
+    /// Must include `-`, which is used to write negations.
+    fn build_unary_operators(&self) -> Vec<Box<dyn FormattableUnaryOperator<Self>>>;
 }
 
 pub trait FormattableOperator<Formatter: LanguageFormatter> {
@@ -111,6 +192,11 @@ pub trait FormattableOperator<Formatter: LanguageFormatter> {
 
     fn is_associative(&self) -> bool;
 
+    /// Werther both operands must have the same unit, like for `+` and `-`.
+    fn requires_equal_units(&self) -> bool {
+        false
+    }
+
     /// Werther parenthesis can be added to the left (false for something like divide line or power)  
     fn should_parenthesize_left(&self) -> bool;
 
@@ -128,6 +214,42 @@ pub trait FormattableOperator<Formatter: LanguageFormatter> {
         left: &ResolvedFormattableExpression,
         right: &ResolvedFormattableExpression,
     );
+
+    /// Writes an [OperatorChain](FormattableExpression::OperatorChain), by default the same as the nested operators.
+    fn write_chain(
+        &self,
+        lib: &FormattableLibraryProvider<Formatter>,
+        out: &mut String,
+        operands: &[ResolvedFormattableExpression],
+    ) {
+        let (right, rest) = operands.split_last().expect("chain has at least two operands");
+        if let [left] = rest {
+            self.write(lib, out, left, right);
+        } else {
+            let left = FormattableExpression::OperatorChain {
+                operator: self.symbol().to_string(),
+                operands: rest.to_vec(),
+            };
+            self.write(lib, out, &left, right);
+        }
+    }
+}
+
+/// A prefix operator like `-` or `√`.
+pub trait FormattableUnaryOperator<Formatter: LanguageFormatter> {
+    fn symbol(&self) -> &str;
+
+    /// Werther an operator as operand should be parenthesised, eg. -(a+b) (false for something like a root sign)  
+    fn should_parenthesize(&self) -> bool;
+
+    fn eval(&self, value: f64) -> Result<f64, String>;
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<Formatter>,
+        out: &mut String,
+        child: &ResolvedFormattableExpression,
+    );
 }
 
 pub trait FormattableFunction<Formatter: LanguageFormatter> {
@@ -137,6 +259,29 @@ pub trait FormattableFunction<Formatter: LanguageFormatter> {
 
     fn eval(&self, args: &[f64]) -> Result<f64, String>;
 
+    /// Most functions return a plain number.
+    fn result_unit(&self, _arg_units: &[Unit]) -> Unit {
+        Unit::None
+    }
+
+    /// Werther arguments with a defined unit are meaningless, like in `sin(5 Meter)`.  
+    /// Only checked when [FormattableLibraryProvider::set_strict_units] is set.
+    fn dimensionless_args(&self) -> bool {
+        false
+    }
+
+    /// Werther the function is undefined for these arguments, even though [eval](Self::eval) gives a finite approximation, like `tan(90)`.  
+    /// Only checked when [FormattableLibraryProvider::set_require_finite] is set, where it is treated as a non-finite result.
+    fn undefined_at(&self, _args: &[f64]) -> bool {
+        false
+    }
+
+    /// Werther the function only groups its single argument, like `par`.  
+    /// It is then written as a [Parenthesis](FormattableExpression::Parenthesis) instead of calling [write](Self::write).
+    fn is_grouping(&self) -> bool {
+        false
+    }
+
     fn write(
         &self,
         lib: &FormattableLibraryProvider<Formatter>,
@@ -148,6 +293,7 @@ pub trait FormattableFunction<Formatter: LanguageFormatter> {
 pub trait BasicOperator<Formatter: LanguageFormatter> {
     const PRECEDENCE: u32;
     const ASSOCIATIVE: bool;
+    const REQUIRES_EQUAL_UNITS: bool = false;
 
     const SHOULD_PARENTHESIZE_LEFT: bool;
     const SHOULD_PARENTHESIZE_RIGHT: bool;
@@ -169,6 +315,10 @@ impl<F: LanguageFormatter, T: BasicOperator<F>> FormattableOperator<F> for T {
         T::ASSOCIATIVE
     }
 
+    fn requires_equal_units(&self) -> bool {
+        T::REQUIRES_EQUAL_UNITS
+    }
+
     fn should_parenthesize_left(&self) -> bool {
         T::SHOULD_PARENTHESIZE_LEFT
     }
@@ -205,6 +355,26 @@ pub trait BasicFunction<Formatter: LanguageFormatter> {
     const FMT: &'static str;
 
     fn eval(&self, args: &[f64]) -> Result<f64, String>;
+
+    /// See [FormattableFunction::result_unit].
+    fn result_unit(&self, _arg_units: &[Unit]) -> Unit {
+        Unit::None
+    }
+
+    /// See [FormattableFunction::dimensionless_args].
+    fn dimensionless_args(&self) -> bool {
+        false
+    }
+
+    /// See [FormattableFunction::undefined_at].
+    fn undefined_at(&self, _args: &[f64]) -> bool {
+        false
+    }
+
+    /// See [FormattableFunction::is_grouping].
+    fn is_grouping(&self) -> bool {
+        false
+    }
 }
 
 impl<F: LanguageFormatter, T: BasicFunction<F>> FormattableFunction<F> for T {
@@ -220,6 +390,22 @@ impl<F: LanguageFormatter, T: BasicFunction<F>> FormattableFunction<F> for T {
         self.eval(args)
     }
 
+    fn result_unit(&self, arg_units: &[Unit]) -> Unit {
+        BasicFunction::result_unit(self, arg_units)
+    }
+
+    fn dimensionless_args(&self) -> bool {
+        BasicFunction::dimensionless_args(self)
+    }
+
+    fn undefined_at(&self, args: &[f64]) -> bool {
+        BasicFunction::undefined_at(self, args)
+    }
+
+    fn is_grouping(&self) -> bool {
+        BasicFunction::is_grouping(self)
+    }
+
     fn write(
         &self,
         lib: &FormattableLibraryProvider<F>,
@@ -238,12 +424,29 @@ pub enum ValueMode {
     NumbersWithUnit,
     /// All variables get converted to numbers, but no units on any numbers
     NumbersNoUnit,
+    /// Like [NumbersWithUnit](Self::NumbersWithUnit), but variables without a value keep their name, eg. `3 + b`
+    NumbersPartial,
+    /// Like [NumbersNoUnit](Self::NumbersNoUnit), but the result keeps its unit
+    NumbersResultUnitOnly,
     /// Variables get names, and number literals are with units
     NamedLiteralUnit,
+    /// Like [NamedLiteralUnit](Self::NamedLiteralUnit), but variables are annotated with their unit, eg. for dimensional analysis
+    NamedVariableUnit,
     /// Variables get names, and units are never added
     NamedNoUnit,
 }
 
+/// What each line of a multi calculation shows.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RowStyle {
+    /// The expression and its result
+    Expression,
+    /// The expression with names, then with values, then the result
+    Substitution,
+    /// Only the result, after the variable name for assignments
+    ResultOnly,
+}
+
 /// Responsible for evaluating unit names and applying operators to units.  
 pub trait UnitLibrary: Sized {
     /// Called when generating [FormattableExpression]s.
@@ -253,9 +456,9 @@ pub trait UnitLibrary: Sized {
     /// Called after calls of [cache_defined_unit](Self::cache_defined_unit), after this is called, units should be available for [get_defined_unit](Self::get_defined_unit)
     fn resolve_units(&mut self) {}
 
-    /// Called during formatting to get unit names.
+    /// Called during formatting to get unit names, [None] if the unit cancels out (eg. m/m).
     /// It should be expected that [cache_defined_unit](Self::cache_defined_unit) has been called for the given unit.
-    fn get_defined_unit(&self, unit: &DefinedUnit) -> String;
+    fn get_defined_unit(&self, unit: &DefinedUnit) -> Option<String>;
 }
 
 pub struct CalculationsBuilder<'a, Formatter: LanguageFormatter, Lib: UnitLibrary> {
@@ -263,9 +466,16 @@ pub struct CalculationsBuilder<'a, Formatter: LanguageFormatter, Lib: UnitLibrar
     eval_ctx: &'a mut EvaluationContext,
     unit_lib: &'a mut Lib,
     calculations: Calculations,
+    forced_unit: Option<Unit>,
 }
 
 impl<'a, F: LanguageFormatter, L: UnitLibrary> CalculationsBuilder<'a, F, L> {
+    /// Results of the following calculations are written with this unit instead of the computed one, eg. to label a dimensionless result.  
+    /// Variables keep the computed unit. [None] to use the computed unit again.
+    pub fn set_forced_unit(&mut self, unit: Option<Unit>) {
+        self.forced_unit = unit;
+    }
+
     pub fn add_single_calculation(
         &mut self,
         exp: &Expression,
@@ -275,13 +485,13 @@ impl<'a, F: LanguageFormatter, L: UnitLibrary> CalculationsBuilder<'a, F, L> {
         EvaluationError<<FormattableLibraryProvider<F> as LibraryProvider>::LibraryError>,
     > {
         let mut result = None;
-        if let ValueMode::NumbersWithUnit | ValueMode::NumbersNoUnit = value_mode {
+        if let ValueMode::NumbersWithUnit
+        | ValueMode::NumbersNoUnit
+        | ValueMode::NumbersResultUnitOnly
+        | ValueMode::NumbersPartial = value_mode
+        {
             // important that eval happens before generating fexp
-            let (value, unit) = exp.eval(self.lib, self.eval_ctx)?;
-            if let Unit::Defined(d) = &unit {
-                self.unit_lib.cache_defined_unit(d);
-            }
-            result = Some(UnresolvedFormattableExpression::Number { value, unit });
+            result = self.eval_result(exp, value_mode)?;
         }
         // okay to generate without evaluating if variable values are not needed
         let expr = self.lib.generate_formattable_expression(
@@ -290,26 +500,162 @@ impl<'a, F: LanguageFormatter, L: UnitLibrary> CalculationsBuilder<'a, F, L> {
             exp,
             value_mode,
             false,
-        );
+        )?;
         self.calculations
             .0
             .push(Calculation::Single { expr, result });
         Ok(self.calculations.0.len() - 1)
     }
 
+    /// Each expression is its own line, see [calculation_steps](Self::calculation_steps) for what a line contains.
     pub fn add_multi_calculation(
         &mut self,
         exps: &[Expression],
-        display_units: bool,
+        value_mode: ValueMode,
+        style: RowStyle,
     ) -> Result<
         usize,
         EvaluationError<<FormattableLibraryProvider<F> as LibraryProvider>::LibraryError>,
     > {
-        let val_mode = if display_units {
-            ValueMode::NumbersWithUnit
+        let rows = exps
+            .iter()
+            .map(|exp| self.calculation_steps(exp, value_mode, style))
+            .collect::<Result<Vec<_>, EvaluationError<_>>>()?;
+        self.calculations.0.push(Calculation::Multi { rows, style });
+        Ok(self.calculations.0.len() - 1)
+    }
+
+    /// Renders the expression with variable names, then with their values, then the result.  
+    /// For an assignment the variable name is written first, eg. `A = l w = 3 \cdot 4 = 12`.
+    pub fn add_substitution_calculation(
+        &mut self,
+        exp: &Expression,
+        value_mode: ValueMode,
+    ) -> Result<
+        usize,
+        EvaluationError<<FormattableLibraryProvider<F> as LibraryProvider>::LibraryError>,
+    > {
+        let steps = self.calculation_steps(exp, value_mode, RowStyle::Substitution)?;
+        self.calculations.0.push(Calculation::Steps(steps));
+        Ok(self.calculations.0.len() - 1)
+    }
+
+    /// Renders only the result, for an assignment after the variable name, eg. `A = 12`.
+    pub fn add_result_calculation(
+        &mut self,
+        exp: &Expression,
+        value_mode: ValueMode,
+    ) -> Result<
+        usize,
+        EvaluationError<<FormattableLibraryProvider<F> as LibraryProvider>::LibraryError>,
+    > {
+        let steps = self.calculation_steps(exp, value_mode, RowStyle::ResultOnly)?;
+        self.calculations.0.push(Calculation::Steps(steps));
+        Ok(self.calculations.0.len() - 1)
+    }
+
+    /// The expression followed by its result, the result is left out in the named modes.  
+    /// When substituting, the expression is written with names and then with values, and stages that look the same are skipped.  
+    /// With only the result, assignments keep their variable name.
+    fn calculation_steps(
+        &mut self,
+        exp: &Expression,
+        value_mode: ValueMode,
+        style: RowStyle,
+    ) -> Result<
+        Vec<UnresolvedFormattableExpression>,
+        EvaluationError<<FormattableLibraryProvider<F> as LibraryProvider>::LibraryError>,
+    > {
+        let result = self.eval_result(exp, value_mode)?;
+        if style == RowStyle::ResultOnly {
+            // a symbolic result is written as far as it is known
+            let result = match result {
+                Some(result) => result,
+                None => self.lib.generate_formattable_expression(self.eval_ctx, self.unit_lib, exp, value_mode, false)?,
+            };
+            return Ok(match exp {
+                Expression::VariableAssign { name, .. } => {
+                    vec![FormattableExpression::Variable(name.clone()), result]
+                }
+                _ => vec![result],
+            });
+        }
+        if style == RowStyle::Expression {
+            let expr = self.lib.generate_formattable_expression(
+                self.eval_ctx,
+                self.unit_lib,
+                exp,
+                value_mode,
+                false,
+            )?;
+            return Ok(match value_mode {
+                ValueMode::NamedLiteralUnit | ValueMode::NamedVariableUnit | ValueMode::NamedNoUnit => vec![expr],
+                _ => [expr].into_iter().chain(result).collect(),
+            });
+        }
+        let (named_mode, numbers_mode) = match value_mode {
+            ValueMode::NumbersWithUnit | ValueMode::NamedLiteralUnit => {
+                (ValueMode::NamedLiteralUnit, ValueMode::NumbersWithUnit)
+            }
+            ValueMode::NumbersPartial => (ValueMode::NamedLiteralUnit, ValueMode::NumbersPartial),
+            ValueMode::NamedVariableUnit => (ValueMode::NamedVariableUnit, ValueMode::NumbersWithUnit),
+            _ => (ValueMode::NamedNoUnit, ValueMode::NumbersNoUnit),
+        };
+        let mut steps = Vec::new();
+        if let Expression::VariableAssign { name, .. } = exp {
+            steps.push(FormattableExpression::Variable(name.clone()));
+        }
+        for mode in [named_mode, numbers_mode] {
+            steps.push(self.lib.generate_formattable_expression(
+                self.eval_ctx,
+                self.unit_lib,
+                exp,
+                mode,
+                false,
+            )?);
+        }
+        steps.extend(result);
+        // eg. a = 2 would otherwise be a = 2 = 2 = 2
+        steps.dedup();
+        Ok(steps)
+    }
+
+    /// Evaluates the expression for its result, [None] if it depends on unknown variables in [ValueMode::NumbersPartial].
+    fn eval_result(
+        &mut self,
+        exp: &Expression,
+        value_mode: ValueMode,
+    ) -> Result<
+        Option<UnresolvedFormattableExpression>,
+        EvaluationError<<FormattableLibraryProvider<F> as LibraryProvider>::LibraryError>,
+    > {
+        let evaluated = if value_mode == ValueMode::NumbersPartial {
+            exp.partial_eval(self.lib, self.eval_ctx)?
         } else {
-            ValueMode::NumbersNoUnit
+            Some(exp.eval(self.lib, self.eval_ctx)?)
         };
+        Ok(evaluated.map(|(value, unit)| {
+            let unit = if let ValueMode::NumbersNoUnit | ValueMode::NamedNoUnit = value_mode {
+                Unit::None
+            } else {
+                self.forced_unit.clone().unwrap_or(unit)
+            };
+            if let Unit::Defined(d) = &unit {
+                self.unit_lib.cache_defined_unit(d);
+            }
+            FormattableExpression::Number { value, unit }
+        }))
+    }
+
+    /// Evaluates the given assignments, and renders them as a table of variable names and values.  
+    /// Lines that are not assignments are rendered like [ValueMode::NamedLiteralUnit].
+    pub fn add_given_calculation(
+        &mut self,
+        exps: &[Expression],
+    ) -> Result<
+        usize,
+        EvaluationError<<FormattableLibraryProvider<F> as LibraryProvider>::LibraryError>,
+    > {
         #[rustfmt::skip]
         let fexps = exps
             .iter()
@@ -318,16 +664,24 @@ impl<'a, F: LanguageFormatter, L: UnitLibrary> CalculationsBuilder<'a, F, L> {
                 if let Unit::Defined(d) = &unit {
                     self.unit_lib.cache_defined_unit(d);
                 }
-                Ok((
-                    self.lib.generate_formattable_expression(self.eval_ctx, self.unit_lib, exp, val_mode, false),
-                    FormattableExpression::Number { value, unit },
-                ))
+                let name = match exp {
+                    Expression::VariableAssign { name, .. } => FormattableExpression::Variable(name.clone()),
+                    _ => self.lib.generate_formattable_expression(self.eval_ctx, self.unit_lib, exp, ValueMode::NamedLiteralUnit, false)?,
+                };
+                Ok(vec![name, FormattableExpression::Number { value, unit }])
             })
             .collect::<Result<Vec<_>, EvaluationError<_>>>()?;
-        self.calculations.0.push(Calculation::Multi(fexps));
+        self.calculations.0.push(Calculation::Multi {
+            rows: fexps,
+            style: RowStyle::Expression,
+        });
         Ok(self.calculations.0.len() - 1)
     }
 
+    pub fn eval_ctx(&mut self) -> &mut EvaluationContext {
+        self.eval_ctx
+    }
+
     pub fn finish(self) -> Calculations {
         self.calculations
     }
@@ -340,10 +694,60 @@ enum Calculation {
         expr: UnresolvedFormattableExpression,
         result: Option<UnresolvedFormattableExpression>,
     },
-    Multi(
-        Vec<(
-            UnresolvedFormattableExpression,
-            UnresolvedFormattableExpression,
-        )>,
-    ),
+    /// One row of steps per line, see [LanguageFormatter::format_multi].
+    Multi {
+        rows: Vec<Vec<UnresolvedFormattableExpression>>,
+        style: RowStyle,
+    },
+    Steps(Vec<UnresolvedFormattableExpression>),
+}
+
+/// Escapes the characters that are special in LaTeX, eg. so a `$` unit does not end the math block.  
+/// Works both in text and math mode.
+pub(crate) fn escape_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "$%&_#{}".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Renders a Markdown table of all variables in eval_ctx, sorted by name.  
+/// Names and values are written by the formatter, units are resolved through unit_lib.
+pub fn render_variable_table<F: LanguageFormatter>(
+    eval_ctx: &EvaluationContext,
+    unit_lib: &mut impl UnitLibrary,
+    lib: &FormattableLibraryProvider<F>,
+) -> String {
+    let mut variables: Vec<_> = eval_ctx.iter().collect();
+    variables.sort_by_key(|(name, _)| *name);
+    for (_, (_, unit)) in &variables {
+        if let Unit::Defined(d) = unit {
+            unit_lib.cache_defined_unit(d);
+        }
+    }
+    unit_lib.resolve_units();
+    let mut out = "| Variable | Value | Unit |\n|----------|-------|------|\n".to_string();
+    for (name, (value, unit)) in variables {
+        let unit = match unit {
+            Unit::Defined(d) => unit_lib.get_defined_unit(d),
+            Unit::Literal(l) => Some(l.clone()),
+            Unit::None => None,
+        };
+        out.push_str("| $");
+        lib.write_expression(&FormattableExpression::Variable(name.to_string()), &mut out);
+        out.push_str("$ | $");
+        lib.write_expression(
+            &FormattableExpression::Number {
+                value: *value,
+                unit: None,
+            },
+            &mut out,
+        );
+        out.push_str(&format!("$ | {} |\n", unit.unwrap_or_default()));
+    }
+    out
 }
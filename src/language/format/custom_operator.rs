@@ -0,0 +1,119 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// An operator defined at runtime, eg. from a config file.
+/// The evaluation is picked from [BuiltinEval], since arbitrary code can't be loaded.  
+/// Formatters opt in by implementing [FormattableOperator](super::FormattableOperator) for it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomOperator {
+    pub symbol: String,
+    pub precedence: u32,
+    pub associative: bool,
+    pub eval: BuiltinEval,
+    /// See [BasicOperator::FMT](super::BasicOperator::FMT)
+    pub fmt: String,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BuiltinEval {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+impl BuiltinEval {
+    pub fn eval(self, left: f64, right: f64) -> Result<f64, String> {
+        match self {
+            BuiltinEval::Add => Ok(left + right),
+            BuiltinEval::Sub => Ok(left - right),
+            BuiltinEval::Mul => Ok(left * right),
+            BuiltinEval::Div if right == 0. => Err("division by zero".to_string()),
+            BuiltinEval::Div => Ok(left / right),
+            BuiltinEval::Pow => Ok(left.powf(right)),
+        }
+    }
+}
+
+impl FromStr for BuiltinEval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "add" => Ok(BuiltinEval::Add),
+            "sub" => Ok(BuiltinEval::Sub),
+            "mul" => Ok(BuiltinEval::Mul),
+            "div" => Ok(BuiltinEval::Div),
+            "pow" => Ok(BuiltinEval::Pow),
+            _ => Err(format!("Unknown eval '{s}', expected one of add, sub, mul, div, pow")),
+        }
+    }
+}
+
+impl Display for BuiltinEval {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            BuiltinEval::Add => "add",
+            BuiltinEval::Sub => "sub",
+            BuiltinEval::Mul => "mul",
+            BuiltinEval::Div => "div",
+            BuiltinEval::Pow => "pow",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Rejects symbols the tokenizer would not read as one operator, and `=` which is used for assignments.
+pub(crate) fn check_operator_symbol(symbol: &str) -> Result<(), String> {
+    // a trailing - would be split off by the tokenizer, to allow eg. 2*-3
+    let invalid_char = |c: char| c.is_alphanumeric() || c.is_whitespace() || "_.,()\"".contains(c);
+    if symbol.is_empty() || symbol == "=" || symbol.chars().any(invalid_char) || (symbol.len() > 1 && symbol.ends_with('-')) {
+        return Err(format!("Invalid operator symbol '{symbol}'"));
+    }
+    Ok(())
+}
+
+/// A line is formatted as `symbol;precedence;associative;eval;fmt`, eg. `++;0;true;add;$0 \oplus $1`.
+/// The fmt is last, so it may contain `;`.
+impl FromStr for CustomOperator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.splitn(5, ';').collect();
+        let [symbol, precedence, associative, eval, fmt] = parts[..] else {
+            return Err(format!("Expected 5 fields separated by ';', got '{s}'"));
+        };
+        check_operator_symbol(symbol)?;
+        Ok(Self {
+            symbol: symbol.to_string(),
+            precedence: precedence
+                .parse()
+                .map_err(|_| format!("Invalid precedence '{precedence}'"))?,
+            associative: associative
+                .parse()
+                .map_err(|_| format!("Invalid associativity '{associative}', expected true or false"))?,
+            eval: eval.parse()?,
+            fmt: fmt.to_string(),
+        })
+    }
+}
+
+impl Display for CustomOperator {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{};{};{};{};{}",
+            self.symbol, self.precedence, self.associative, self.eval, self.fmt
+        )
+    }
+}
+
+/// Parses one [CustomOperator] per line, blank lines are skipped.
+pub fn parse_custom_operators(source: &str) -> Result<Vec<CustomOperator>, String> {
+    source
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
@@ -1,38 +1,231 @@
 use super::*;
 use crate::language::expression::{
-    DefinedUnit, EvaluationContext, Expression, LibraryProvider, Unit,
+    DefinedUnit, EvaluationContext, Expression, LibraryProvider, MAX_EXPANDED_SIZE, Unit,
+    UnitName, VariableExpression,
 };
+use crate::unit_lib::NoopUnitLibrary;
 use std::collections::HashMap;
+use std::rc::Rc;
 
-/// Implementation of [LibraryProvider] based on a [LanguageFormatter].  
-/// This way the only thing needed to implement an output language is [LanguageFormatter].  
-pub struct FormattableLibraryProvider<F: LanguageFormatter> {
-    functions: HashMap<String, Box<dyn FormattableFunction<F>>>,
-    operators: HashMap<String, Box<dyn FormattableOperator<F>>>,
-    formatter: F,
+/// The functions and operators of a [LanguageFormatter], built once and shared between providers.  
+/// Building depends on the formatter's settings, so only share it between providers with equally configured formatters.  
+pub struct FormattableLibrary<F: LanguageFormatter> {
+    functions: HashMap<String, Rc<dyn FormattableFunction<F>>>,
+    operators: HashMap<String, Rc<dyn FormattableOperator<F>>>,
+    unary_operators: HashMap<String, Rc<dyn FormattableUnaryOperator<F>>>,
 }
 
-impl<F: LanguageFormatter> FormattableLibraryProvider<F> {
-    pub fn new(formatter: F) -> Self {
-        let mut funcs = HashMap::new();
-        let mut ops = HashMap::new();
-        for f in formatter.build_functions() {
-            if let Some(v) = funcs.insert(f.name().to_string(), f) {
+impl<F: LanguageFormatter> FormattableLibrary<F> {
+    /// Everything the formatter provides, use [builder](Self::builder) to restrict it.
+    pub fn new(formatter: &F) -> Self {
+        Self::builder(formatter).build()
+    }
+
+    pub fn builder(formatter: &F) -> FormattableLibraryBuilder<F> {
+        FormattableLibraryBuilder {
+            functions: formatter.build_functions(),
+            operators: formatter.build_operators(),
+            unary_operators: formatter.build_unary_operators(),
+        }
+    }
+}
+
+/// Chooses which of the functions and operators of a formatter are available, eg. to disallow trigonometry in a sandbox.  
+/// Naming a function or operator the formatter does not provide is an error, so typos can not leave something available.
+pub struct FormattableLibraryBuilder<F: LanguageFormatter> {
+    functions: Vec<Box<dyn FormattableFunction<F>>>,
+    operators: Vec<Box<dyn FormattableOperator<F>>>,
+    unary_operators: Vec<Box<dyn FormattableUnaryOperator<F>>>,
+}
+
+impl<F: LanguageFormatter> FormattableLibraryBuilder<F> {
+    /// Keeps only the functions with the given names.
+    pub fn include_functions(mut self, names: &[&str]) -> Result<Self, String> {
+        self.check_functions(names)?;
+        self.functions.retain(|f| names.contains(&f.name()));
+        Ok(self)
+    }
+
+    pub fn exclude_functions(mut self, names: &[&str]) -> Result<Self, String> {
+        self.check_functions(names)?;
+        self.functions.retain(|f| !names.contains(&f.name()));
+        Ok(self)
+    }
+
+    /// Keeps only the operators with the given symbols.
+    pub fn include_operators(mut self, symbols: &[&str]) -> Result<Self, String> {
+        self.check_operators(symbols)?;
+        self.operators.retain(|o| symbols.contains(&o.symbol()));
+        Ok(self)
+    }
+
+    pub fn exclude_operators(mut self, symbols: &[&str]) -> Result<Self, String> {
+        self.check_operators(symbols)?;
+        self.operators.retain(|o| !symbols.contains(&o.symbol()));
+        Ok(self)
+    }
+
+    /// Keeps only the prefix operators with the given symbols, and `-` which is always required.
+    pub fn include_unary_operators(mut self, symbols: &[&str]) -> Result<Self, String> {
+        self.check_unary_operators(symbols)?;
+        self.unary_operators
+            .retain(|o| o.symbol() == "-" || symbols.contains(&o.symbol()));
+        Ok(self)
+    }
+
+    /// Excluding `-` is an error, since it is needed for negation.
+    pub fn exclude_unary_operators(mut self, symbols: &[&str]) -> Result<Self, String> {
+        self.check_unary_operators(symbols)?;
+        if symbols.contains(&"-") {
+            return Err("Can not exclude unary operator: -".to_string());
+        }
+        self.unary_operators.retain(|o| !symbols.contains(&o.symbol()));
+        Ok(self)
+    }
+
+    fn check_functions(&self, names: &[&str]) -> Result<(), String> {
+        match names.iter().find(|n| !self.functions.iter().any(|f| f.name() == **n)) {
+            Some(name) => Err(format!("Unknown function: {name}")),
+            None => Ok(()),
+        }
+    }
+
+    fn check_operators(&self, symbols: &[&str]) -> Result<(), String> {
+        match symbols.iter().find(|s| !self.operators.iter().any(|o| o.symbol() == **s)) {
+            Some(symbol) => Err(format!("Unknown operator: {symbol}")),
+            None => Ok(()),
+        }
+    }
+
+    fn check_unary_operators(&self, symbols: &[&str]) -> Result<(), String> {
+        match symbols.iter().find(|s| !self.unary_operators.iter().any(|o| o.symbol() == **s)) {
+            Some(symbol) => Err(format!("Unknown unary operator: {symbol}")),
+            None => Ok(()),
+        }
+    }
+
+    /// Panics on duplicate names or a missing `-` unary operator, since those are bugs in the formatter.
+    pub fn build(self) -> FormattableLibrary<F> {
+        let mut library = FormattableLibrary {
+            functions: HashMap::new(),
+            operators: HashMap::new(),
+            unary_operators: HashMap::new(),
+        };
+        for f in self.functions {
+            if let Some(v) = library.functions.insert(f.name().to_string(), Rc::from(f)) {
                 panic!("Duplicate function: {}", v.name());
             }
         }
-        for o in formatter.build_operators() {
-            if let Some(v) = ops.insert(o.symbol().to_string(), o) {
+        for o in self.operators {
+            if let Some(v) = library.operators.insert(o.symbol().to_string(), Rc::from(o)) {
                 panic!("Duplicate operator: {}", v.symbol());
             }
         }
+        for o in self.unary_operators {
+            if let Some(v) = library.unary_operators.insert(o.symbol().to_string(), Rc::from(o)) {
+                panic!("Duplicate unary operator: {}", v.symbol());
+            }
+        }
+        assert!(library.unary_operators.contains_key("-"), "Missing unary operator: -");
+        library
+    }
+}
+
+impl<F: LanguageFormatter> Clone for FormattableLibrary<F> {
+    fn clone(&self) -> Self {
+        Self {
+            functions: self.functions.clone(),
+            operators: self.operators.clone(),
+            unary_operators: self.unary_operators.clone(),
+        }
+    }
+}
+
+/// Implementation of [LibraryProvider] based on a [LanguageFormatter].  
+/// This way the only thing needed to implement an output language is [LanguageFormatter].  
+pub struct FormattableLibraryProvider<F: LanguageFormatter> {
+    library: Rc<FormattableLibrary<F>>,
+    formatter: F,
+    require_finite: bool,
+    strict_units: bool,
+    flatten_associative: bool,
+}
+
+impl<F: LanguageFormatter> FormattableLibraryProvider<F> {
+    /// Builds the functions and operators of `formatter`.  
+    /// When constructing many providers, build a [FormattableLibrary] once and use [with_library](Self::with_library) instead.
+    pub fn new(formatter: F) -> Self {
+        let library = Rc::new(FormattableLibrary::new(&formatter));
+        Self::with_library(formatter, library)
+    }
+
+    /// Uses an already built library, which must have been built from a formatter configured like `formatter`.  
+    /// Adding operators to a provider copies its library first, so other providers sharing it are unaffected.
+    pub fn with_library(formatter: F, library: Rc<FormattableLibrary<F>>) -> Self {
         Self {
-            functions: funcs,
-            operators: ops,
+            library,
             formatter,
+            require_finite: false,
+            strict_units: false,
+            flatten_associative: false,
+        }
+    }
+
+    /// When set, evaluating a function or operator to infinity or NaN is an error, eg. `tan(90)` or `10 ** 400`.  
+    /// Off by default, since infinity can be a legitimate result. `tan(90)` is then a huge number, since the angle is not exact.
+    pub fn set_require_finite(&mut self, require_finite: bool) {
+        self.require_finite = require_finite;
+    }
+
+    /// When set, functions like `sin` error on arguments with a defined unit, instead of ignoring the unit.  
+    /// Literal units are still allowed, since they are only for display.
+    pub fn set_strict_units(&mut self, strict_units: bool) {
+        self.strict_units = strict_units;
+    }
+
+    /// When set, chains of the same associative operator like `a + b + c` become one [OperatorChain](FormattableExpression::OperatorChain).  
+    /// This only changes the formattable tree, evaluation is unaffected.
+    pub fn set_flatten_associative(&mut self, flatten_associative: bool) {
+        self.flatten_associative = flatten_associative;
+    }
+
+    fn check_finite(&self, result: f64) -> Result<f64, String> {
+        if self.require_finite && !result.is_finite() {
+            Err(format!("result is not finite ({result})"))
+        } else {
+            Ok(result)
         }
     }
 
+    /// Adds an operator after construction, eg. one loaded from a config file.
+    /// Unlike [new](Self::new) this does not panic on duplicates, so user input can be reported.
+    /// Reserved symbols like `=`, `,` and `(` are also errors.
+    pub fn add_operator(&mut self, operator: Box<dyn FormattableOperator<F>>) -> Result<(), String> {
+        check_operator_symbol(operator.symbol())?;
+        if self.library.operators.contains_key(operator.symbol()) {
+            return Err(format!("Duplicate operator: {}", operator.symbol()));
+        }
+        Rc::make_mut(&mut self.library)
+            .operators
+            .insert(operator.symbol().to_string(), Rc::from(operator));
+        Ok(())
+    }
+
+    /// Like [add_operator](Self::add_operator), but for prefix operators.
+    pub fn add_unary_operator(
+        &mut self,
+        operator: Box<dyn FormattableUnaryOperator<F>>,
+    ) -> Result<(), String> {
+        check_operator_symbol(operator.symbol())?;
+        if self.library.unary_operators.contains_key(operator.symbol()) {
+            return Err(format!("Duplicate unary operator: {}", operator.symbol()));
+        }
+        Rc::make_mut(&mut self.library)
+            .unary_operators
+            .insert(operator.symbol().to_string(), Rc::from(operator));
+        Ok(())
+    }
+
     pub fn make_calculations<'a, Lib: UnitLibrary>(
         &'a self,
         eval_ctx: &'a mut EvaluationContext,
@@ -43,6 +236,7 @@ impl<F: LanguageFormatter> FormattableLibraryProvider<F> {
             eval_ctx,
             unit_lib,
             calculations: Calculations(Vec::new()),
+            forced_unit: None,
         }
     }
 
@@ -60,22 +254,53 @@ impl<F: LanguageFormatter> FormattableLibraryProvider<F> {
                     let result = result.map(|r| self.resolve_formattable_expression(unit_lib, r));
                     self.formatter.format_single(self, &expr, result.as_ref())
                 }
-                Calculation::Multi(v) => {
-                    let res: Vec<_> = v
+                Calculation::Multi { rows, .. } => {
+                    let res: Vec<_> = rows
                         .into_iter()
-                        .map(|(c, r)| {
-                            (
-                                self.resolve_formattable_expression(unit_lib, c),
-                                self.resolve_formattable_expression(unit_lib, r),
-                            )
+                        .map(|row| {
+                            row.into_iter()
+                                .map(|s| self.resolve_formattable_expression(unit_lib, s))
+                                .collect()
                         })
                         .collect();
                     self.formatter.format_multi(self, &res)
                 }
+                Calculation::Steps(v) => {
+                    let res: Vec<_> = v
+                        .into_iter()
+                        .map(|s| self.resolve_formattable_expression(unit_lib, s))
+                        .collect();
+                    self.formatter.format_steps(self, &res)
+                }
             })
             .collect()
     }
 
+    /// Formats only the result of a calculation, for multi calculations the result of the last line.  
+    /// [None] if the calculation has no result, eg. when variables are shown instead of values.
+    pub fn format_result(
+        &self,
+        unit_lib: &impl UnitLibrary,
+        calculations: &Calculations,
+        index: usize,
+    ) -> Option<String> {
+        let result = match &calculations.0[index] {
+            Calculation::Single { result, .. } => result.clone()?,
+            Calculation::Multi { rows, style } => {
+                let row = rows.last()?;
+                // a row without relation has no result, unless the row is only the result
+                if row.len() < 2 && *style != RowStyle::ResultOnly {
+                    return None;
+                }
+                row.last()?.clone()
+            }
+            Calculation::Steps(v) => v.last()?.clone(),
+        };
+        let result = self.resolve_formattable_expression(unit_lib, result);
+        Some(self.formatter.format_single(self, &result, None))
+    }
+
+    /// Fails with [EvaluationError::TooDeep] for expressions nested deeper than [EvaluationContext::max_depth].
     pub fn generate_formattable_expression(
         &self,
         eval_ctx: &EvaluationContext,
@@ -83,54 +308,95 @@ impl<F: LanguageFormatter> FormattableLibraryProvider<F> {
         exp: &Expression,
         value_mode: ValueMode,
         parenthesise: bool,
-    ) -> UnresolvedFormattableExpression {
+    ) -> Result<UnresolvedFormattableExpression, EvaluationError<String>> {
+        let mut ctx = GenerationContext {
+            eval_ctx,
+            expanding: Vec::new(),
+            expanded_size: 0,
+        };
+        self.generate_nested(&mut ctx, unit_lib, exp, value_mode, parenthesise, 0)
+    }
+
+    fn generate_nested(
+        &self,
+        ctx: &mut GenerationContext,
+        unit_lib: &mut impl UnitLibrary,
+        exp: &Expression,
+        value_mode: ValueMode,
+        parenthesise: bool,
+        depth: usize,
+    ) -> Result<UnresolvedFormattableExpression, EvaluationError<String>> {
+        let eval_ctx = ctx.eval_ctx;
+        if depth > eval_ctx.max_depth() {
+            return Err(EvaluationError::TooDeep {
+                max_depth: eval_ctx.max_depth(),
+            });
+        }
         if parenthesise {
-            return FormattableExpression::Parenthesis(Box::new(
-                self.generate_formattable_expression(eval_ctx, unit_lib, exp, value_mode, false),
-            ));
+            return Ok(FormattableExpression::Parenthesis(Box::new(
+                self.generate_nested(ctx, unit_lib, exp, value_mode, false, depth + 1)?,
+            )));
         }
-        match exp {
+        Ok(match exp {
             Expression::VariableAssign { child, .. } => {
-                self.generate_formattable_expression(eval_ctx, unit_lib, child, value_mode, false)
+                self.generate_nested(ctx, unit_lib, child, value_mode, false, depth + 1)?
             }
-            Expression::Operator {
-                operator,
-                left,
-                right,
-            } => {
-                let p_l = if let Expression::Operator { operator: l_op, .. } = left.as_ref() {
-                    self.operator_precedence(operator) > self.operator_precedence(l_op)
-                        && self.operators[operator].should_parenthesize_left()
-                } else {
-                    false
+            Expression::Convert { unit, child } => {
+                let unit = match unit {
+                    UnitName::Defined(n) => {
+                        let d = DefinedUnit::Defined(n.clone());
+                        unit_lib.cache_defined_unit(&d);
+                        Unit::Defined(d)
+                    }
+                    UnitName::Literal(n) => Unit::Literal(n.clone()),
                 };
-                let p_r = if let Expression::Operator { operator: r_op, .. } = right.as_ref() {
-                    self.operator_precedence(operator) > self.operator_precedence(r_op)
-                        && self.operators[operator].should_parenthesize_right()
-                } else {
-                    false
+                FormattableExpression::Conversion {
+                    child: Box::new(self.generate_nested(ctx, unit_lib, child, value_mode, false, depth + 1)?),
+                    unit,
+                }
+            }
+            Expression::Operator { .. } => {
+                // left operands are walked iteratively like in evaluation, so a long chain like a + b + c is not counted as nesting
+                let mut chain = vec![exp];
+                let (leaf, p_l) = loop {
+                    let Expression::Operator { operator, left, .. } = chain[chain.len() - 1] else {
+                        unreachable!("only operators are in the chain")
+                    };
+                    // references are expanded by their own arm, and only looked through for parentheses
+                    let p_l = self.parenthesise_left(operator, eval_ctx.referenced(left));
+                    if p_l || !matches!(left.as_ref(), Expression::Operator { .. }) {
+                        break (left, p_l);
+                    }
+                    chain.push(left);
                 };
-
-                let left =
-                    self.generate_formattable_expression(eval_ctx, unit_lib, left, value_mode, p_l);
-                let right = self
-                    .generate_formattable_expression(eval_ctx, unit_lib, right, value_mode, p_r);
-
-                FormattableExpression::Operator {
-                    operator: operator.clone(),
-                    left: Box::new(left),
-                    right: Box::new(right),
+                let mut res = self.generate_nested(ctx, unit_lib, leaf, value_mode, p_l, depth + 1)?;
+                let mut p_l = p_l;
+                for exp in chain.into_iter().rev() {
+                    let Expression::Operator { operator, right, .. } = exp else {
+                        unreachable!("only operators are in the chain")
+                    };
+                    let p_r = self.parenthesise_right(operator, eval_ctx.referenced(right));
+                    let right = self
+                        .generate_nested(ctx, unit_lib, right, value_mode, p_r, depth + 1)?;
+                    res = self.operator_expression(operator, res, p_l, right);
+                    // the operators of the chain are never parenthesised
+                    p_l = false;
                 }
+                res
             }
             Expression::FunctionCall { function, args } => {
+                // eg. par(a + b) * c, written with the same parentheses as (a + b) * c
+                if let [arg] = &args[..]
+                    && self.library.functions[function].is_grouping()
+                {
+                    return Ok(FormattableExpression::Parenthesis(Box::new(
+                        self.generate_nested(ctx, unit_lib, arg, value_mode, false, depth + 1)?,
+                    )));
+                }
                 let fargs = args
                     .iter()
-                    .map(|e| {
-                        self.generate_formattable_expression(
-                            eval_ctx, unit_lib, e, value_mode, false,
-                        )
-                    })
-                    .collect();
+                    .map(|e| self.generate_nested(ctx, unit_lib, e, value_mode, false, depth + 1))
+                    .collect::<Result<_, _>>()?;
                 FormattableExpression::Function {
                     name: function.clone(),
                     args: fargs,
@@ -145,20 +411,25 @@ impl<F: LanguageFormatter> FormattableLibraryProvider<F> {
                         Unit::Defined(d)
                     })
                     .unwrap_or(Unit::None);
-                self.handle_unit(eval_ctx, unit_lib, value_mode, unit, child)
+                self.handle_unit(ctx, unit_lib, value_mode, unit, child, depth)?
             }
             Expression::LiteralUnit { name, child } => self.handle_unit(
-                eval_ctx,
+                ctx,
                 unit_lib,
                 value_mode,
                 Unit::Literal(name.clone()),
                 child,
-            ),
+                depth,
+            )?,
             Expression::VariableRef(name) => match value_mode {
-                ValueMode::NumbersNoUnit | ValueMode::NumbersWithUnit => {
-                    let (value, unit) = eval_ctx
-                        .get_variable(name)
-                        .expect("variable not found, call eval and get Ok before formatting");
+                ValueMode::NumbersNoUnit
+                | ValueMode::NumbersWithUnit
+                | ValueMode::NumbersResultUnitOnly => {
+                    let (value, unit) = ctx.get_variable(name).ok_or_else(|| {
+                        EvaluationError::MissingVariable {
+                            name: name.clone(),
+                        }
+                    })?;
                     if value_mode == ValueMode::NumbersWithUnit {
                         if let Unit::Defined(d) = &unit {
                             unit_lib.cache_defined_unit(d);
@@ -171,40 +442,192 @@ impl<F: LanguageFormatter> FormattableLibraryProvider<F> {
                         }
                     }
                 }
+                ValueMode::NumbersPartial => match ctx.get_variable(name) {
+                    Some((value, unit)) => {
+                        if let Unit::Defined(d) = &unit {
+                            unit_lib.cache_defined_unit(d);
+                        }
+                        FormattableExpression::Number { value, unit }
+                    }
+                    None => FormattableExpression::Variable(name.to_string()),
+                },
+                ValueMode::NamedVariableUnit => match ctx.get_variable(name) {
+                    // variables without a value, eg. in previews, are only named
+                    Some((_, unit)) if unit != Unit::None => {
+                        if let Unit::Defined(d) = &unit {
+                            unit_lib.cache_defined_unit(d);
+                        }
+                        FormattableExpression::AnnotatedVariable {
+                            name: name.to_string(),
+                            unit,
+                        }
+                    }
+                    _ => FormattableExpression::Variable(name.to_string()),
+                },
                 ValueMode::NamedLiteralUnit | ValueMode::NamedNoUnit => {
                     FormattableExpression::Variable(name.to_string())
                 }
             },
+            Expression::ExpressionRef(name) => {
+                // variables stored without an assignment, eg. the bound variable of a sum, have no expression
+                let Some(exp) = eval_ctx.get_variable_expr(name) else {
+                    return self.generate_nested(
+                        ctx,
+                        unit_lib,
+                        &Expression::VariableRef(name.clone()),
+                        value_mode,
+                        false,
+                        depth + 1,
+                    );
+                };
+                ctx.expand(name, exp.clone())?;
+                let res = self.generate_nested(
+                    ctx,
+                    unit_lib,
+                    &exp.expression,
+                    value_mode,
+                    false,
+                    depth + 1,
+                )?;
+                ctx.expanding.pop();
+                res
+            }
             Expression::NumberLiteral(v) => FormattableExpression::Number {
                 value: *v,
                 unit: Unit::None,
             },
             Expression::Negate(child) => {
                 // handle operator parenthesis eg. -(a+b)
-                if let Expression::Operator { operator, .. } = child.as_ref()
-                    && self.operators[operator].should_parenthesize_left() {
-                        return FormattableExpression::Negate(Box::new(
-                            self.generate_formattable_expression(
-                                eval_ctx, unit_lib, child, value_mode, true,
-                            ),
-                        ));
+                if let Expression::Operator { operator, .. } = eval_ctx.referenced(child)
+                    && self.operator_precedence(operator) <= self.negation_precedence()
+                    && self.library.operators[operator].should_parenthesize_left() {
+                        return Ok(FormattableExpression::Negate(Box::new(
+                            self.generate_nested(ctx, unit_lib, child, value_mode, true, depth + 1)?,
+                        )));
                     }
                 FormattableExpression::Negate(Box::new(
-                    self.generate_formattable_expression(
-                        eval_ctx, unit_lib, child, value_mode, false,
-                    ),
+                    self.generate_nested(ctx, unit_lib, child, value_mode, false, depth + 1)?,
                 ))
             }
+            Expression::UnaryOperator { operator, child } => {
+                let parenthesise = matches!(eval_ctx.referenced(child), Expression::Operator { .. })
+                    && self.library.unary_operators[operator].should_parenthesize();
+                FormattableExpression::UnaryOperator {
+                    operator: operator.clone(),
+                    child: Box::new(self.generate_nested(
+                        ctx,
+                        unit_lib,
+                        child,
+                        value_mode,
+                        parenthesise, depth + 1)?),
+                }
+            }
+        })
+    }
+
+    /// Werther the left operand of `operator` is parenthesised, eg. (a + b) * c.
+    fn parenthesise_left(&self, operator: &str, left: &Expression) -> bool {
+        if let Expression::Operator { operator: l_op, .. } = left {
+            self.operator_precedence(operator) > self.operator_precedence(l_op)
+                && self.library.operators[operator].should_parenthesize_left()
+        } else {
+            false
+        }
+    }
+
+    /// Werther the right operand of `operator` is parenthesised, eg. a * (b + c).
+    fn parenthesise_right(&self, operator: &str, right: &Expression) -> bool {
+        if let Expression::Operator { operator: r_op, .. } = right {
+            let (precedence, r_precedence) =
+                (self.operator_precedence(operator), self.operator_precedence(r_op));
+            // operators group left to right, so a - (b - c) keeps its parentheses
+            (precedence > r_precedence
+                || precedence == r_precedence && !self.operator_associative(operator))
+                && self.library.operators[operator].should_parenthesize_right()
+        } else {
+            false
+        }
+    }
+
+    /// Combines generated operands, `p_l` being werther the left operand is already parenthesised.
+    fn operator_expression(
+        &self,
+        operator: &str,
+        left: UnresolvedFormattableExpression,
+        p_l: bool,
+        right: UnresolvedFormattableExpression,
+    ) -> UnresolvedFormattableExpression {
+        // a negative left operand must keep its parentheses, eg. (-2)^2
+        let left = if !p_l
+            && is_negative(&left)
+            && self.operator_precedence(operator) > self.negation_precedence()
+            && self.library.operators[operator].should_parenthesize_left()
+        {
+            FormattableExpression::Parenthesis(Box::new(left))
+        } else {
+            left
+        };
+
+        if self.flatten_associative
+            && self.library.operators[operator].is_associative()
+            && (is_operator(&left, operator) || is_operator(&right, operator))
+        {
+            let mut operands = Vec::new();
+            for e in [left, right] {
+                match e {
+                    FormattableExpression::Operator { operator: o, left, right }
+                        if o == operator =>
+                    {
+                        operands.extend([*left, *right])
+                    }
+                    FormattableExpression::OperatorChain { operator: o, operands: c }
+                        if o == operator =>
+                    {
+                        operands.extend(c)
+                    }
+                    e => operands.push(e),
+                }
+            }
+            return FormattableExpression::OperatorChain {
+                operator: operator.to_string(),
+                operands,
+            };
+        }
+
+        FormattableExpression::Operator {
+            operator: operator.to_string(),
+            left: Box::new(left),
+            right: Box::new(right),
         }
     }
 
+    /// Writes the expression without evaluating it, eg. for previews.  
+    /// Variables have no values, so value modes writing them as numbers fail with [EvaluationError::MissingVariable].
+    pub fn render_expression(
+        &self,
+        exp: &Expression,
+        value_mode: ValueMode,
+    ) -> Result<String, EvaluationError<String>> {
+        let mut unit_lib = NoopUnitLibrary;
+        let fexp = self.generate_formattable_expression(
+            &EvaluationContext::new(),
+            &mut unit_lib,
+            exp,
+            value_mode,
+            false,
+        )?;
+        let mut out = String::new();
+        self.write_expression(&self.resolve_formattable_expression(&unit_lib, fexp), &mut out);
+        Ok(out)
+    }
+
     pub fn resolve_formattable_expression(
         &self,
         unit_lib: &impl UnitLibrary,
         unresolved: UnresolvedFormattableExpression,
     ) -> ResolvedFormattableExpression {
         unresolved.map_unit(&mut |unit| match unit {
-            Unit::Defined(d) => Some(unit_lib.get_defined_unit(&d).to_string()),
+            Unit::Defined(d) => unit_lib.get_defined_unit(&d),
             Unit::Literal(l) => Some(l),
             Unit::None => None,
         })
@@ -217,57 +640,88 @@ impl<F: LanguageFormatter> FormattableLibraryProvider<F> {
                 left,
                 right,
             } => self
+                .library
                 .operators
                 .get(operator)
                 .expect("operator not found")
                 .write(self, out, left, right),
+            FormattableExpression::OperatorChain { operator, operands } => self
+                .library
+                .operators
+                .get(operator)
+                .expect("operator not found")
+                .write_chain(self, out, operands),
             FormattableExpression::Function { name, args } => self
+                .library
                 .functions
                 .get(name)
                 .expect("function not found")
                 .write(self, out, args),
 
-            FormattableExpression::Negate(child) => self.formatter.negate(self, child, out),
+            FormattableExpression::Negate(child) => self
+                .library
+                .unary_operators
+                .get("-")
+                .expect("checked in new")
+                .write(self, out, child),
+            FormattableExpression::UnaryOperator { operator, child } => self
+                .library
+                .unary_operators
+                .get(operator)
+                .expect("unary operator not found")
+                .write(self, out, child),
             FormattableExpression::Parenthesis(child) => {
                 self.formatter.parenthesise(self, child, out)
             }
             FormattableExpression::Variable(v) => self.formatter.write_variable(v, out),
+            FormattableExpression::AnnotatedVariable { name, unit } => match unit {
+                Some(unit) => self.formatter.write_annotated_variable(name, unit, out),
+                None => self.formatter.write_variable(name, out),
+            },
             FormattableExpression::Number { value, unit } => {
                 self.formatter
                     .write_number(*value, unit.as_ref().map(|s| s.as_str()), out)
             }
+            FormattableExpression::Conversion { child, unit } => match unit {
+                Some(unit) => self.formatter.write_conversion(self, child, unit, out),
+                None => self.write_expression(child, out),
+            },
         }
     }
     
     fn handle_unit(
         &self,
-        eval_ctx: &EvaluationContext,
+        ctx: &mut GenerationContext,
         unit_lib: &mut impl UnitLibrary,
         value_mode: ValueMode,
         unit: Unit,
         child: &Expression,
-    ) -> UnresolvedFormattableExpression {
-        if let ValueMode::NamedNoUnit | ValueMode::NumbersNoUnit = value_mode {
-            return self
-                .generate_formattable_expression(eval_ctx, unit_lib, child, value_mode, false);
+        depth: usize,
+    ) -> Result<UnresolvedFormattableExpression, EvaluationError<String>> {
+        if let ValueMode::NamedNoUnit | ValueMode::NumbersNoUnit | ValueMode::NumbersResultUnitOnly =
+            value_mode
+        {
+            return self.generate_nested(ctx, unit_lib, child, value_mode, false, depth + 1);
         };
         let value = if let Expression::NumberLiteral(v) = child {
             *v
         } else if let (Expression::VariableRef(var_name), ValueMode::NumbersWithUnit) =
             (child, value_mode)
         {
-            eval_ctx
-                .get_variable(var_name)
+            ctx.get_variable(var_name)
                 .expect("variable not found, call eval and get Ok before formatting")
                 .0
+        } else if let (Expression::VariableRef(var_name), ValueMode::NumbersPartial) = (child, value_mode)
+            && let Some((value, _)) = ctx.get_variable(var_name)
+        {
+            value
         } else {
-            return self
-                .generate_formattable_expression(eval_ctx, unit_lib, child, value_mode, false);
+            return self.generate_nested(ctx, unit_lib, child, value_mode, false, depth + 1);
         };
         if let Unit::Defined(d) = &unit {
             unit_lib.cache_defined_unit(d);
         }
-        FormattableExpression::Number { value, unit }
+        Ok(FormattableExpression::Number { value, unit })
     }
 
     /// Appends fmt to out, where $n becomes the formatted result of args\[n\].  
@@ -309,21 +763,45 @@ impl<F: LanguageFormatter> LibraryProvider for FormattableLibraryProvider<F> {
     type LibraryError = String;
 
     fn function_exists(&self, name: &str, param_c: usize) -> bool {
-        self.functions
+        self.library.functions
             .get(name)
             .is_some_and(|f| f.supports_arg_count(param_c))
     }
 
     fn operator_exists(&self, symbol: &str) -> bool {
-        self.operators.contains_key(symbol)
+        self.library.operators.contains_key(symbol)
+    }
+
+    fn function_unit(&self, name: &str, arg_units: &[Unit]) -> Result<Unit, Self::LibraryError> {
+        let function = self
+            .library
+            .functions
+            .get(name)
+            .expect("should call function_exists before evaluating function");
+        if self.strict_units
+            && function.dimensionless_args()
+            && arg_units.iter().any(|u| matches!(u, Unit::Defined(_)))
+        {
+            return Err(format!("{name} can not take an argument with a unit"));
+        }
+        Ok(function.result_unit(arg_units))
+    }
+
+    fn unary_operator_exists(&self, symbol: &str) -> bool {
+        self.library.unary_operators.contains_key(symbol)
     }
 
     fn eval_function(&self, name: &str, params: &[f64]) -> Result<f64, Self::LibraryError> {
-        self.functions
+        let function = self.library.functions
             .get(name)
-            .expect("should call function_exists before evaluating function")
-            .as_ref()
-            .eval(params)
+            .expect("should call function_exists before evaluating function");
+        function.eval(params).and_then(|r| {
+            if self.require_finite && function.undefined_at(params) {
+                self.check_finite(f64::NAN)
+            } else {
+                self.check_finite(r)
+            }
+        })
     }
 
     fn eval_operator(
@@ -332,23 +810,95 @@ impl<F: LanguageFormatter> LibraryProvider for FormattableLibraryProvider<F> {
         left: f64,
         right: f64,
     ) -> Result<f64, Self::LibraryError> {
-        self.operators
+        self.library.operators
             .get(symbol)
             .expect("should call operator_exists before evaluating operator")
             .eval(left, right)
+            .and_then(|r| self.check_finite(r))
+    }
+
+    fn eval_unary_operator(&self, symbol: &str, value: f64) -> Result<f64, Self::LibraryError> {
+        self.library.unary_operators
+            .get(symbol)
+            .expect("should call unary_operator_exists before evaluating unary operator")
+            .eval(value)
+            .and_then(|r| self.check_finite(r))
     }
 
     fn operator_associative(&self, symbol: &str) -> bool {
-        self.operators
+        self.library.operators
             .get(symbol)
             .expect("should call operator_exists before accessing operator")
             .is_associative()
     }
 
+    fn operator_requires_equal_units(&self, symbol: &str) -> bool {
+        self.library.operators
+            .get(symbol)
+            .expect("should call operator_exists before accessing operator")
+            .requires_equal_units()
+    }
+
     fn operator_precedence(&self, symbol: &str) -> u32 {
-        self.operators
+        self.library.operators
             .get(symbol)
             .expect("should call operator_exists before accessing operator")
             .precedence()
     }
+
+    fn negation_precedence(&self) -> u32 {
+        self.formatter.negation_precedence()
+    }
+}
+
+fn is_operator<U>(exp: &FormattableExpression<U>, symbol: &str) -> bool {
+    match exp {
+        FormattableExpression::Operator { operator, .. }
+        | FormattableExpression::OperatorChain { operator, .. } => operator == symbol,
+        _ => false,
+    }
+}
+
+fn is_negative<U>(exp: &FormattableExpression<U>) -> bool {
+    match exp {
+        FormattableExpression::Negate(_) => true,
+        FormattableExpression::Number { value, .. } => *value < 0.,
+        _ => false,
+    }
+}
+
+/// The state of generating a formattable expression.
+struct GenerationContext<'a> {
+    eval_ctx: &'a EvaluationContext,
+    /// The [ExpressionRef](Expression::ExpressionRef)s being expanded, outermost first.
+    expanding: Vec<(String, Rc<VariableExpression>)>,
+    /// How many expressions the references have expanded to so far.
+    expanded_size: usize,
+}
+
+impl GenerationContext<'_> {
+    /// Fails if `name` is already being expanded, or if the expanded expressions get larger than [MAX_EXPANDED_SIZE].
+    fn expand(&mut self, name: &str, exp: Rc<VariableExpression>) -> Result<(), EvaluationError<String>> {
+        if let Some(start) = self.expanding.iter().position(|(n, _)| n == name) {
+            let mut names: Vec<_> = self.expanding[start..].iter().map(|(n, _)| n.clone()).collect();
+            names.push(name.to_string());
+            return Err(EvaluationError::CyclicReference { names });
+        }
+        self.expanded_size += exp.expression.size();
+        if self.expanded_size > MAX_EXPANDED_SIZE {
+            return Err(EvaluationError::TooLarge {
+                max_size: MAX_EXPANDED_SIZE,
+            });
+        }
+        self.expanding.push((name.to_string(), exp));
+        Ok(())
+    }
+
+    /// Inside an expanded reference, the value the variable had when the referenced expression was assigned.
+    fn get_variable(&self, name: &str) -> Option<(f64, Unit)> {
+        match self.expanding.last() {
+            Some((_, exp)) => exp.values.get(name).cloned(),
+            None => self.eval_ctx.get_variable(name),
+        }
+    }
 }
@@ -0,0 +1,1156 @@
+use crate::language::expression::{DefinedUnit, EvaluationContext, Expression, LibraryProvider, Unit};
+use crate::language::format::{
+    parse_custom_operators, BuiltinEval, CustomOperator, FormattableLibrary, FormattableLibraryProvider,
+    FormattableUnaryOperator, LanguageFormatter, ResolvedFormattableExpression, RowStyle, UnitLibrary, ValueMode,
+    render_variable_table,
+};
+use crate::language::latex_impl::{AngleMode, LatexFormatter, MultiplicationSymbol, Rounding, SiPrefixes};
+use crate::language::parse::tokenize;
+use std::rc::Rc;
+
+/// Real one would be [CLIUnitLib](crate::unit_lib::CLIUnitLib)
+struct MockUnitLibrary;
+impl UnitLibrary for MockUnitLibrary {
+    fn cache_defined_unit(&mut self, _unit: &DefinedUnit) {}
+
+    fn get_defined_unit(&self, unit: &DefinedUnit) -> Option<String> {
+        match unit {
+            DefinedUnit::Defined(name) => Some(name.clone()),
+            DefinedUnit::Implicit { .. } => panic!("mock does not resolve implicit units"),
+        }
+    }
+}
+
+fn render(source: &str, value_mode: ValueMode) -> String {
+    render_with(LatexFormatter::default(), source, value_mode)
+}
+
+fn render_with(formatter: LatexFormatter, source: &str, value_mode: ValueMode) -> String {
+    render_lib(&FormattableLibraryProvider::new(formatter), source, value_mode)
+}
+
+fn render_lib(
+    lib: &FormattableLibraryProvider<LatexFormatter>,
+    source: &str,
+    value_mode: ValueMode,
+) -> String {
+    let mut eval_ctx = EvaluationContext::new();
+    let mut unit_lib = MockUnitLibrary;
+    let exp = Expression::new(tokenize(source).unwrap(), lib).unwrap();
+    let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+    cb.add_single_calculation(&exp, value_mode).unwrap();
+    let calculations = cb.finish();
+    lib.format_calculations(&unit_lib, calculations).remove(0)
+}
+
+#[test]
+fn negate_power() {
+    assert_eq!(render("-2**2", ValueMode::NumbersWithUnit), "$-2^{2} = -4$");
+    assert_eq!(render("(-2)**2", ValueMode::NumbersWithUnit), "$\\left(-2\\right)^{2} = 4$");
+    assert_eq!(render("-(2+3)", ValueMode::NumbersWithUnit), "$-\\left(2 + 3\\right) = -5$");
+    assert_eq!(render("-a**2", ValueMode::NamedNoUnit), "$-\\mathit{a}^{2}$");
+    assert_eq!(render("(-a)**2", ValueMode::NamedNoUnit), "$\\left(-\\mathit{a}\\right)^{2}$");
+}
+
+#[test]
+fn si_prefixes() {
+    let render = |source| {
+        let formatter = LatexFormatter {
+            si_prefixes: Some(SiPrefixes::default()),
+            ..Default::default()
+        };
+        render_with(formatter, source, ValueMode::NumbersWithUnit)
+    };
+    let km = "1.5\\small\\text{ km}\\normalsize";
+    assert_eq!(render("1500 \"m\""), format!("${km} = {km}$"));
+    let mm = "2\\small\\text{ mm}\\normalsize";
+    assert_eq!(render(".002 \"m\""), format!("${mm} = {mm}$"));
+    let m = "5\\small\\text{ m}\\normalsize";
+    assert_eq!(render("5 \"m\""), format!("${m} = {m}$"));
+    // not an SI unit
+    let ft = "1500\\small\\text{ ft}\\normalsize";
+    assert_eq!(render("1500 \"ft\""), format!("${ft} = {ft}$"));
+}
+
+#[test]
+fn sized_delimiters() {
+    let frac = "\\dfrac{\\mathit{a}}{\\mathit{b}}";
+    let render = |source| render(source, ValueMode::NamedNoUnit);
+    assert_eq!(render("abs(a/b)"), format!("$\\left|{frac}\\right|$"));
+    assert_eq!(render("floor(a/b)"), format!("$\\left\\lfloor {frac} \\right\\rfloor$"));
+    assert_eq!(render("ceil(a/b)"), format!("$\\left\\lceil {frac} \\right\\rceil$"));
+    assert_eq!(render("par(a/b)"), format!("$\\left({frac}\\right)$"));
+}
+
+#[test]
+fn explicit_parentheses() {
+    assert_eq!(
+        render("par(a + b) * c", ValueMode::NamedNoUnit),
+        "$\\left(\\mathit{a} + \\mathit{b}\\right) \\cdot \\mathit{c}$"
+    );
+    assert_eq!(
+        render("par(1 + 2) ** 2", ValueMode::NumbersNoUnit),
+        "$\\left(1 + 2\\right)^{2} = 9$"
+    );
+    // not needed for the result, but still written
+    assert_eq!(render("2 * par(3 * 4)", ValueMode::NumbersNoUnit), "$2 \\cdot \\left(3 \\cdot 4\\right) = 24$");
+    // the argument is already grouped
+    assert_eq!(
+        render("log10(par(a + b))", ValueMode::NamedNoUnit),
+        "$\\log_{10}{\\left(\\mathit{a} + \\mathit{b}\\right)}$"
+    );
+}
+
+#[test]
+fn sized_parentheses() {
+    assert_eq!(
+        render("(1/2 + 3) * 4", ValueMode::NumbersWithUnit),
+        "$\\left(\\dfrac{1}{2} + 3\\right) \\cdot 4 = 14$"
+    );
+}
+
+#[test]
+fn multiplication_symbol() {
+    let times = LatexFormatter {
+        multiplication_symbol: MultiplicationSymbol::Times,
+        ..Default::default()
+    };
+    assert_eq!(render("2 * 3", ValueMode::NumbersNoUnit), "$2 \\cdot 3 = 6$");
+    assert_eq!(render_with(times, "2 * 3", ValueMode::NumbersNoUnit), "$2 \\times 3 = 6$");
+    let juxtaposed = LatexFormatter {
+        multiplication_symbol: MultiplicationSymbol::Times,
+        juxtapose_products: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        render_with(juxtaposed, "2 * a * 3", ValueMode::NamedNoUnit),
+        "$2 \\mathit{a} \\times 3$"
+    );
+}
+
+#[test]
+fn juxtapose_products() {
+    let juxtaposed = |source| {
+        let formatter = LatexFormatter {
+            juxtapose_products: true,
+            ..Default::default()
+        };
+        render_with(formatter, source, ValueMode::NamedNoUnit)
+    };
+    assert_eq!(juxtaposed("a * b"), "$\\mathit{a} \\mathit{b}$");
+    assert_eq!(juxtaposed("2 * a"), "$2 \\mathit{a}$");
+    assert_eq!(juxtaposed("2 * 3"), "$2 \\cdot 3$");
+    assert_eq!(juxtaposed("a * 2"), "$\\mathit{a} \\cdot 2$");
+    // off by default
+    assert_eq!(
+        render("a * b", ValueMode::NamedNoUnit),
+        "$\\mathit{a} \\cdot \\mathit{b}$"
+    );
+}
+
+#[test]
+fn rounding() {
+    let render = |source, rounding| {
+        let formatter = LatexFormatter {
+            precision: 0,
+            rounding,
+            ..Default::default()
+        };
+        render_with(formatter, source, ValueMode::NumbersWithUnit)
+    };
+    assert_eq!(render("2.5", Some(Rounding::HalfUp)), "$3 = 3$");
+    assert_eq!(render("2.5", Some(Rounding::HalfEven)), "$2 = 2$");
+    assert_eq!(render("3.5", Some(Rounding::HalfUp)), "$4 = 4$");
+    assert_eq!(render("3.5", Some(Rounding::HalfEven)), "$4 = 4$");
+    assert_eq!(render("-2.5", Some(Rounding::HalfUp)), "$-3 = -3$");
+    // ties to even when writing numbers by default, but away from zero in p
+    assert_eq!(render("2.5", None), "$2 = 2$");
+    assert_eq!(render("p(25, 10)", None), "$25 = 30$");
+    // p uses the same rounding
+    assert_eq!(render("p(25, 10)", Some(Rounding::HalfUp)), "$25 = 30$");
+    assert_eq!(render("p(25, 10)", Some(Rounding::HalfEven)), "$25 = 20$");
+    assert_eq!(render("p(35, 10)", Some(Rounding::HalfEven)), "$35 = 40$");
+}
+
+#[test]
+fn snapping() {
+    let render = |source, snap_epsilon| {
+        let formatter = LatexFormatter {
+            precision: 20,
+            snap_epsilon,
+            ..Default::default()
+        };
+        render_with(formatter, source, ValueMode::NumbersWithUnit)
+    };
+    assert_eq!(render("0.1 + 0.2", Some(1e-15)), "$0.1 + 0.2 = 0.3$");
+    assert_eq!(render("4.000000000000001", Some(1e-15)), "$4 = 4$");
+    assert_eq!(render("1/3", Some(1e-15)), "$\\dfrac{1}{3} = 0.333333333333333$");
+    assert_ne!(render("0.1 + 0.2", None), "$0.1 + 0.2 = 0.3$");
+}
+
+#[test]
+fn unit_placement() {
+    let render = |source, unit_prefix| {
+        let formatter = LatexFormatter {
+            unit_prefix,
+            ..Default::default()
+        };
+        render_with(formatter, source, ValueMode::NumbersWithUnit)
+    };
+    let prefixed = r"-\small\text{USD}\normalsize 5";
+    assert_eq!(render(r#"-5 "USD""#, true), format!("${prefixed} = {prefixed}$"));
+    let suffixed = r"-5\small\text{ USD}\normalsize";
+    assert_eq!(render(r#"-5 "USD""#, false), format!("${suffixed} = {suffixed}$"));
+    assert_eq!(render("-5", true), "$-5 = -5$");
+}
+
+#[test]
+fn escaped_units() {
+    let formatter = LatexFormatter {
+        unit_prefix: true,
+        ..Default::default()
+    };
+    let dollars = r"-\small\text{\$}\normalsize 5";
+    assert_eq!(
+        render_with(formatter, r#"-5 "$""#, ValueMode::NumbersWithUnit),
+        format!("${dollars} = {dollars}$")
+    );
+    assert_eq!(
+        render(r#"50 "%" / 2"#, ValueMode::NumbersWithUnit),
+        r"$\dfrac{50\small\text{ \%}\normalsize}{2} = 25\small\text{ \%}\normalsize$"
+    );
+    assert_eq!(
+        render(r#"3 "kg&m{}""#, ValueMode::NumbersWithUnit),
+        r"$3\small\text{ kg\&m\{\}}\normalsize = 3\small\text{ kg\&m\{\}}\normalsize$"
+    );
+}
+
+#[test]
+fn bare_unit() {
+    let formatter = LatexFormatter::default();
+    let mut out = String::new();
+    formatter.write_unit("kg&m", &mut out);
+    assert_eq!(out, r"\small\text{kg\&m}\normalsize");
+}
+
+#[test]
+fn escaped_variables() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut out = String::new();
+    lib.write_expression(&ResolvedFormattableExpression::Variable("a#b_c%".into()), &mut out);
+    assert_eq!(out, r"\mathit{a\#b_{c\%}}");
+    // subscripts still work
+    assert_eq!(render("v_max_2", ValueMode::NamedNoUnit), r"$\mathit{v_{max_{2}}}$");
+}
+
+#[test]
+fn shared_library() {
+    let formatter = || LatexFormatter {
+        juxtapose_products: true,
+        ..Default::default()
+    };
+    let library = Rc::new(FormattableLibrary::new(&formatter()));
+    let built = FormattableLibraryProvider::new(formatter());
+    let shared = FormattableLibraryProvider::with_library(formatter(), library.clone());
+    for source in ["2 * 3 \"m\"", "-sqrt(9) / 3 ** 2", "max(1, 2 \"m\") + pi()", "floor(2.5) * 3"] {
+        for mode in [ValueMode::NumbersWithUnit, ValueMode::NamedNoUnit] {
+            assert_eq!(render_lib(&shared, source, mode), render_lib(&built, source, mode));
+        }
+    }
+    // adding operators copies the library instead of changing the shared one
+    let mut custom = FormattableLibraryProvider::with_library(formatter(), library.clone());
+    custom.add_operator(Box::new("<+>;0;true;add;$0 \\oplus $1".parse::<CustomOperator>().unwrap())).unwrap();
+    assert_eq!(render_lib(&custom, "1 <+> 2", ValueMode::NumbersWithUnit), "$1 \\oplus 2 = 3$");
+    assert!(Expression::new(tokenize("1 <+> 2").unwrap(), &shared).is_err());
+}
+
+#[test]
+fn restricted_library() {
+    let formatter = LatexFormatter::default();
+    let library = FormattableLibrary::builder(&formatter)
+        .include_functions(&[])
+        .unwrap()
+        .include_operators(&["+", "*"])
+        .unwrap()
+        .build();
+    let lib = FormattableLibraryProvider::with_library(formatter, Rc::new(library));
+    assert!(!lib.function_exists("sin", 1));
+    assert!(lib.operator_exists("*"));
+    assert!(!lib.operator_exists("/"));
+    assert_eq!(render_lib(&lib, "-1 + 2 * 3", ValueMode::NumbersWithUnit), "$-1 + 2 \\cdot 3 = 5$");
+    assert!(Expression::new(tokenize("sin(1)").unwrap(), &lib).is_err());
+    assert!(Expression::new(tokenize("1 - 2").unwrap(), &lib).is_err());
+
+    let formatter = LatexFormatter::default();
+    let library = FormattableLibrary::builder(&formatter)
+        .exclude_functions(&["sin", "cos", "tan"])
+        .unwrap()
+        .build();
+    let lib = FormattableLibraryProvider::with_library(formatter, Rc::new(library));
+    assert!(!lib.function_exists("cos", 1));
+    assert!(lib.function_exists("sqrt", 1));
+    assert!(lib.operator_exists("/"));
+}
+
+#[test]
+fn restricted_library_typo() {
+    let formatter = LatexFormatter::default();
+    let err = FormattableLibrary::builder(&formatter).exclude_functions(&["sine"]).err();
+    assert_eq!(err.as_deref(), Some("Unknown function: sine"));
+    let err = FormattableLibrary::builder(&formatter).include_operators(&["+", "%%"]).err();
+    assert_eq!(err.as_deref(), Some("Unknown operator: %%"));
+}
+
+#[test]
+fn restricted_unary_operators() {
+    let formatter = LatexFormatter::default();
+    let library = FormattableLibrary::builder(&formatter)
+        .include_unary_operators(&[])
+        .unwrap()
+        .build();
+    let lib = FormattableLibraryProvider::with_library(formatter, Rc::new(library));
+    assert!(lib.unary_operator_exists("-"));
+    assert_eq!(render_lib(&lib, "-2", ValueMode::NumbersWithUnit), "$-2 = -2$");
+
+    let formatter = LatexFormatter::default();
+    let err = FormattableLibrary::builder(&formatter).exclude_unary_operators(&["-"]).err();
+    assert_eq!(err.as_deref(), Some("Can not exclude unary operator: -"));
+    let err = FormattableLibrary::builder(&formatter).include_unary_operators(&["~"]).err();
+    assert_eq!(err.as_deref(), Some("Unknown unary operator: ~"));
+}
+
+#[test]
+fn expression_ref() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    let mut unit_lib = MockUnitLibrary;
+    let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+    let mut add = |source: &str, value_mode| {
+        let exp = Expression::new(tokenize(source).unwrap(), &lib).unwrap();
+        cb.add_single_calculation(&exp, value_mode).unwrap()
+    };
+    for source in ["l = 3", "w = 4", "area = l * w", "s = l + w"] {
+        add(source, ValueMode::NumbersWithUnit);
+    }
+    let named = add("2 * !area", ValueMode::NamedNoUnit);
+    let numbers = add("2 * !area", ValueMode::NumbersWithUnit);
+    let grouped = add("!s ** 2", ValueMode::NamedNoUnit);
+    let nested = add("v = !area * 5", ValueMode::NumbersNoUnit);
+    let inlined = add("!v", ValueMode::NamedNoUnit);
+    let calculations = cb.finish();
+    let formatted = lib.format_calculations(&unit_lib, calculations);
+    assert_eq!(formatted[named], "$2 \\cdot \\mathit{l} \\cdot \\mathit{w}$");
+    assert_eq!(formatted[numbers], "$2 \\cdot 3 \\cdot 4 = 24$");
+    assert_eq!(formatted[grouped], "$\\left(\\mathit{l} + \\mathit{w}\\right)^{2}$");
+    assert_eq!(formatted[nested], "$3 \\cdot 4 \\cdot 5 = 60$");
+    // references in stored expressions are expanded
+    assert_eq!(formatted[inlined], "$\\mathit{l} \\cdot \\mathit{w} \\cdot 5$");
+}
+
+#[test]
+fn expression_ref_reassigned_operand() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    let mut unit_lib = MockUnitLibrary;
+    let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+    let mut add = |source: &str, value_mode| {
+        let exp = Expression::new(tokenize(source).unwrap(), &lib).unwrap();
+        cb.add_single_calculation(&exp, value_mode).unwrap()
+    };
+    for source in ["l = 3", "w = 4", "area = l * w", "double = !area * 2", "l = 10"] {
+        add(source, ValueMode::NumbersWithUnit);
+    }
+    // the operands keep the values they had when area was assigned
+    let numbers = add("2 * !area", ValueMode::NumbersWithUnit);
+    let nested = add("!double", ValueMode::NumbersNoUnit);
+    let named = add("2 * !area", ValueMode::NamedNoUnit);
+    let calculations = cb.finish();
+    let formatted = lib.format_calculations(&unit_lib, calculations);
+    assert_eq!(formatted[numbers], "$2 \\cdot 3 \\cdot 4 = 24$");
+    assert_eq!(formatted[nested], "$3 \\cdot 4 \\cdot 2 = 24$");
+    assert_eq!(formatted[named], "$2 \\cdot \\mathit{l} \\cdot \\mathit{w}$");
+}
+
+#[test]
+fn expression_ref_limits() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    let mut unit_lib = MockUnitLibrary;
+    let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+    let mut add = |source: &str| {
+        let exp = Expression::new(tokenize(source).unwrap(), &lib).unwrap();
+        cb.add_single_calculation(&exp, ValueMode::NumbersNoUnit)
+    };
+    for source in ["a = 2", "b = !a * 3", "b = !a + 1"] {
+        add(source).unwrap();
+    }
+    // evaluated before formatting, so a already refers to b
+    let err = add("a = !b").unwrap_err();
+    assert_eq!(format!("{err:?}"), "cyclic variable reference: b -> a -> b");
+    let err = add("!a").unwrap_err();
+    assert_eq!(format!("{err:?}"), "cyclic variable reference: a -> b -> a");
+    add("x = 1").unwrap();
+    let err = add("x = !x + !x").unwrap_err();
+    assert_eq!(format!("{err:?}"), "cyclic variable reference: x -> x");
+    // every definition doubles the size of the expanded expression
+    add("y0 = 1").unwrap();
+    for i in 1..20 {
+        let res = add(&format!("y{i} = !y{} + !y{}", i - 1, i - 1));
+        assert_eq!(res.is_ok(), i < 12);
+    }
+    let err = add("!y19").unwrap_err();
+    assert_eq!(format!("{err:?}"), "Variable references expand to more than 10000 expressions");
+}
+
+#[test]
+fn custom_operators() {
+    let operators = parse_custom_operators("<+>;0;true;add;$0 \\oplus $1\n\n<*>;1;true;mul;$0 \\otimes $1").unwrap();
+    assert_eq!(
+        operators[0],
+        CustomOperator {
+            symbol: "<+>".into(),
+            precedence: 0,
+            associative: true,
+            eval: BuiltinEval::Add,
+            fmt: "$0 \\oplus $1".into(),
+        }
+    );
+    let mut lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    for operator in operators {
+        lib.add_operator(Box::new(operator)).unwrap();
+    }
+    let exp = Expression::new(tokenize("(1 <+> 2) <*> 3 <+> 4").unwrap(), &lib).unwrap();
+    let mut eval_ctx = EvaluationContext::new();
+    let mut unit_lib = MockUnitLibrary;
+    let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+    cb.add_single_calculation(&exp, ValueMode::NumbersWithUnit).unwrap();
+    let calculations = cb.finish();
+    assert_eq!(
+        lib.format_calculations(&unit_lib, calculations).remove(0),
+        "$\\left(1 \\oplus 2\\right) \\otimes 3 \\oplus 4 = 13$"
+    );
+
+    let duplicate = "+;0;true;add;$0 + $1".parse::<CustomOperator>().unwrap();
+    assert!(lib.add_operator(Box::new(duplicate)).is_err());
+    assert!("a;0;true;add;$0".parse::<CustomOperator>().is_err());
+    assert!("%%;0;true;mod;$0".parse::<CustomOperator>().is_err());
+    assert!("=;0;true;add;$0".parse::<CustomOperator>().is_err());
+    for symbol in ["=", ",", "(", ""] {
+        let reserved = CustomOperator {
+            symbol: symbol.into(),
+            precedence: 0,
+            associative: true,
+            eval: BuiltinEval::Add,
+            fmt: "$0 + $1".into(),
+        };
+        assert_eq!(
+            lib.add_operator(Box::new(reserved)),
+            Err(format!("Invalid operator symbol '{symbol}'"))
+        );
+    }
+}
+
+struct Sqrt;
+
+impl FormattableUnaryOperator<LatexFormatter> for Sqrt {
+    fn symbol(&self) -> &str {
+        "√"
+    }
+
+    fn should_parenthesize(&self) -> bool {
+        false
+    }
+
+    fn eval(&self, value: f64) -> Result<f64, String> {
+        Ok(value.sqrt())
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        child: &ResolvedFormattableExpression,
+    ) {
+        lib.fmt_expression("\\sqrt{$0}", &[child], out);
+    }
+}
+
+#[test]
+fn custom_unary_operator() {
+    let mut lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    lib.add_unary_operator(Box::new(Sqrt)).unwrap();
+    assert!(lib.add_unary_operator(Box::new(Sqrt)).is_err());
+    let render = |source| {
+        let exp = Expression::new(tokenize(source).unwrap(), &lib).unwrap();
+        let mut eval_ctx = EvaluationContext::new();
+        let mut unit_lib = MockUnitLibrary;
+        let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+        cb.add_single_calculation(&exp, ValueMode::NumbersWithUnit).unwrap();
+        let calculations = cb.finish();
+        lib.format_calculations(&unit_lib, calculations).remove(0)
+    };
+    assert_eq!(render("√9 + 1"), "$\\sqrt{9} + 1 = 4$");
+    assert_eq!(render("-√(7 + 9)"), "$-\\sqrt{7 + 9} = -4$");
+    // not registered on a default provider
+    let default_lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    assert!(Expression::new(tokenize("√9").unwrap(), &default_lib).is_err());
+}
+
+#[test]
+fn vector() {
+    assert_eq!(
+        render("vec(1, 2, a)", ValueMode::NamedNoUnit),
+        "$\\begin{pmatrix}1 \\\\ 2 \\\\ \\mathit{a}\\end{pmatrix}$"
+    );
+    // has no scalar value
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let exp = Expression::new(tokenize("vec(1, 2, 3) * 2").unwrap(), &lib).unwrap();
+    let mut eval_ctx = EvaluationContext::new();
+    let mut unit_lib = MockUnitLibrary;
+    let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+    assert!(cb.add_single_calculation(&exp, ValueMode::NumbersWithUnit).is_err());
+    assert!(cb.add_single_calculation(&exp, ValueMode::NumbersNoUnit).is_err());
+}
+
+#[test]
+fn variable_table() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    eval_ctx.store_variable("speed", (6., Unit::Defined(DefinedUnit::Defined("m/s".into()))));
+    eval_ctx.store_variable("count", (3., Unit::None));
+    eval_ctx.store_variable("price", (2.5, Unit::Literal("USD".into())));
+    assert_eq!(
+        render_variable_table(&eval_ctx, &mut MockUnitLibrary, &lib),
+        "| Variable | Value | Unit |\n\
+         |----------|-------|------|\n\
+         | $\\mathit{count}$ | $3$ |  |\n\
+         | $\\mathit{price}$ | $2.5$ | USD |\n\
+         | $\\mathit{speed}$ | $6$ | m/s |\n"
+    );
+}
+
+#[test]
+fn conversion_shows_target_unit() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    eval_ctx.add_conversion("h", "s", 3600.);
+    let mut unit_lib = MockUnitLibrary;
+    let exp = Expression::new(tokenize("to(7200 \"s\", \"h\")").unwrap(), &lib).unwrap();
+    let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+    cb.add_single_calculation(&exp, ValueMode::NumbersWithUnit).unwrap();
+    let calculations = cb.finish();
+    assert_eq!(
+        lib.format_calculations(&unit_lib, calculations).remove(0),
+        "$7200\\small\\text{ s}\\normalsize \\to \\small\\text{h}\\normalsize = 2\\small\\text{ h}\\normalsize$"
+    );
+}
+
+fn eval(source: &str) -> Result<f64, String> {
+    eval_with(&FormattableLibraryProvider::new(LatexFormatter::default()), source)
+}
+
+fn eval_with(lib: &FormattableLibraryProvider<LatexFormatter>, source: &str) -> Result<f64, String> {
+    let exp = Expression::new(tokenize(source).unwrap(), lib).unwrap();
+    exp.eval(lib, &mut EvaluationContext::new())
+        .map(|(v, _)| v)
+        .map_err(|e| format!("{e:?}"))
+}
+
+#[test]
+fn percent_error() {
+    assert!((eval("perr(102, 100)").unwrap() - 2.).abs() < 1e-9);
+    assert_eq!(
+        render("perr(a, b)", ValueMode::NamedNoUnit),
+        "$\\dfrac{\\mathit{a} - \\mathit{b}}{\\mathit{b}}\\cdot 100\\%$"
+    );
+    assert!(eval("perr(5, 0)").is_err());
+    assert_eq!(eval("reldiff(3, 1)"), Ok(1.));
+    assert_eq!(eval("reldiff(1, 3)"), Ok(1.));
+    assert!(eval("reldiff(0, 0)").is_err());
+    // compound arguments keep their grouping
+    assert_eq!(
+        render("perr(1 + 2, 3 - 1)", ValueMode::NumbersWithUnit),
+        "$\\dfrac{\\left(1 + 2\\right) - \\left(3 - 1\\right)}{3 - 1}\\cdot 100\\% = 50$"
+    );
+    assert_eq!(
+        render("reldiff(a - b, c)", ValueMode::NamedNoUnit),
+        "$\\dfrac{\\left|\\left(\\mathit{a} - \\mathit{b}\\right) - \\mathit{c}\\right|}\
+         {\\frac{\\left|\\mathit{a} - \\mathit{b}\\right| + \\left|\\mathit{c}\\right|}{2}}$"
+    );
+}
+
+#[test]
+fn log2() {
+    assert_eq!(eval("log2(8)"), Ok(3.));
+    assert!(eval("log2(0)").is_err());
+    assert_eq!(render("log2(a)", ValueMode::NamedNoUnit), "$\\log_2{\\mathit{a}}$");
+}
+
+#[test]
+fn cbrt() {
+    assert_eq!(eval("cbrt(27)"), Ok(3.));
+    assert_eq!(eval("cbrt(-8)"), Ok(-2.));
+    assert_eq!(render("cbrt(a)", ValueMode::NamedNoUnit), "$\\sqrt[3]{\\mathit{a}}$");
+}
+
+#[test]
+fn zero_arg_calls() {
+    assert_eq!(eval("pi() * 2"), Ok(std::f64::consts::TAU));
+    assert_eq!(eval("e()"), Ok(std::f64::consts::E));
+    assert_eq!(render("2 * pi()", ValueMode::NamedNoUnit), "$2 \\cdot \\pi$");
+}
+
+#[test]
+fn nested_argument_values() {
+    assert_eq!(eval("max((1 + 2), 2)"), Ok(3.));
+    assert_eq!(eval("max(min(4, 5), (2 * 3), abs(-1))"), Ok(6.));
+}
+
+#[test]
+fn hex_and_binary_literals() {
+    assert_eq!(eval("0xFF"), Ok(255.));
+    assert_eq!(eval("0b1010"), Ok(10.));
+    assert_eq!(eval("0x10 + 0b11"), Ok(19.));
+    assert_eq!(eval("1_000_000"), Ok(1000000.));
+    assert_eq!(eval("0b1111_0000 + 1_0.5"), Ok(250.5));
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    for invalid in ["0b102", "0x", "0x1.5"] {
+        assert!(Expression::new(tokenize(invalid).unwrap(), &lib).is_err());
+    }
+}
+
+#[test]
+fn tau() {
+    assert_eq!(eval("tau()"), Ok(2. * std::f64::consts::PI));
+    assert_eq!(render("tau()", ValueMode::NumbersWithUnit), "$\\tau = 6.28319$");
+}
+
+#[test]
+fn require_finite() {
+    let mut lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    assert!(eval_with(&lib, "tan(90)").unwrap() > 1e15);
+    assert!(eval_with(&lib, "10 ** 400").unwrap().is_infinite());
+    lib.set_require_finite(true);
+    assert!(eval_with(&lib, "tan(90)").unwrap_err().contains("not finite"));
+    assert!(eval_with(&lib, "tan(-90)").is_err());
+    assert!(eval_with(&lib, "10 ** 400").is_err());
+    assert_eq!(eval_with(&lib, "tan(45)").unwrap().round(), 1.);
+}
+
+#[test]
+fn tan_of_right_angle() {
+    let mut lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    // plain tan, where to_radians is not exact
+    for angle in ["tan(90)", "tan(-90)", "tan(270)"] {
+        assert!(eval_with(&lib, angle).unwrap().abs() > 1e15, "{angle}");
+    }
+    lib.set_require_finite(true);
+    for angle in ["tan(90)", "tan(-90)", "tan(270)"] {
+        assert!(eval_with(&lib, angle).is_err(), "{angle}");
+    }
+    assert!(eval_with(&lib, "tan(89.99)").unwrap().is_finite());
+}
+
+#[test]
+fn angle_mode() {
+    let radians = FormattableLibraryProvider::new(LatexFormatter {
+        angle_mode: AngleMode::Radians,
+        ..Default::default()
+    });
+    assert!(eval_with(&radians, "sin(pi())").unwrap().abs() < 1e-12);
+    assert!((eval_with(&radians, "acos(0)").unwrap() - std::f64::consts::FRAC_PI_2).abs() < 1e-12);
+    // degrees by default
+    assert_eq!(eval("sin(90)"), Ok(1.));
+    assert!((eval("acos(0)").unwrap() - 90.).abs() < 1e-12);
+}
+
+#[test]
+fn grouped_function_arguments() {
+    let render = |source| render(source, ValueMode::NamedNoUnit);
+    let product = "\\left(\\mathit{x} \\cdot \\mathit{y}\\right)";
+    assert_eq!(render("log(x*y, 2)"), format!("$\\log_{{2}}{{{product}}}$"));
+    assert_eq!(render("sin(x*y)"), format!("$\\sin{{{product}}}$"));
+    assert_eq!(render("log10(x)"), "$\\log_{10}{\\mathit{x}}$");
+    // the division line already groups
+    assert_eq!(
+        render("log2(x/y)"),
+        "$\\log_2{\\dfrac{\\mathit{x}}{\\mathit{y}}}$"
+    );
+    // braces are enough inside the root
+    assert_eq!(
+        render("sqrt(log(x + 1, 10))"),
+        "$\\sqrt{\\log_{10}{\\left(\\mathit{x} + 1\\right)}}$"
+    );
+}
+
+#[test]
+fn sign() {
+    assert_eq!(eval("sign(-3)"), Ok(-1.));
+    assert_eq!(eval("sign(0)"), Ok(0.));
+    assert_eq!(eval("sign(5)"), Ok(1.));
+    assert_eq!(render("sign(x)", ValueMode::NamedNoUnit), "$\\operatorname{sgn}\\left(\\mathit{x}\\right)$");
+}
+
+#[test]
+fn trunc_frac() {
+    assert_eq!(eval("trunc(-2.7)"), Ok(-2.));
+    assert!((eval("frac(2.7)").unwrap() - 0.7).abs() < 1e-12);
+    assert!((eval("frac(-2.7)").unwrap() + 0.7).abs() < 1e-12);
+    assert_eq!(render("trunc(x)", ValueMode::NamedNoUnit), "$\\operatorname{trunc}\\left(\\mathit{x}\\right)$");
+}
+
+#[test]
+fn hypot() {
+    assert_eq!(eval("hypot(3, 4)"), Ok(5.));
+    assert_eq!(eval("hypot(-3, 4)"), Ok(5.));
+    assert_eq!(render("hypot(a, b)", ValueMode::NamedNoUnit), "$\\sqrt{\\mathit{a}^{2} + \\mathit{b}^{2}}$");
+    assert_eq!(
+        render("hypot(a + b, c)", ValueMode::NamedNoUnit),
+        "$\\sqrt{\\left(\\mathit{a} + \\mathit{b}\\right)^{2} + \\mathit{c}^{2}}$"
+    );
+    assert_eq!(
+        render("hypot(-3, 4)", ValueMode::NumbersWithUnit),
+        "$\\sqrt{\\left(-3\\right)^{2} + 4^{2}} = 5$"
+    );
+}
+
+#[test]
+fn choose() {
+    assert_eq!(eval("choose(5, 2)"), Ok(10.));
+    assert_eq!(eval("choose(5, 0)"), Ok(1.));
+    assert_eq!(eval("choose(60, 30)"), Ok(118264581564861424.));
+    assert!(eval("choose(2, 5)").is_err());
+    assert!(eval("choose(-1, 0)").is_err());
+    assert!(eval("choose(1000, 500)").unwrap_err().contains("too large"));
+    assert!(eval("choose(10 ** 400 - 10 ** 400, 0)").unwrap_err().contains("non-finite"));
+    assert!(eval("choose(10 ** 400, 1)").unwrap_err().contains("non-finite"));
+    assert_eq!(render("choose(n, k)", ValueMode::NamedNoUnit), "$\\binom{\\mathit{n}}{\\mathit{k}}$");
+}
+
+#[test]
+fn permutations() {
+    assert_eq!(eval("perm(5, 2)"), Ok(20.));
+    assert_eq!(eval("perm(5, 0)"), Ok(1.));
+    assert!(eval("perm(3, 5)").is_err());
+    assert!(eval("perm(1000, 500)").unwrap_err().contains("too large"));
+    assert_eq!(render("perm(n, k)", ValueMode::NamedNoUnit), "${}^{\\mathit{n}}P_{\\mathit{k}}$");
+}
+
+fn eval_unit(source: &str) -> Option<String> {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let exp = Expression::new(tokenize(source).unwrap(), &lib).unwrap();
+    let (_, unit) = exp.eval(&lib, &mut EvaluationContext::new()).unwrap();
+    match unit {
+        Unit::Defined(d) => MockUnitLibrary.get_defined_unit(&d),
+        Unit::Literal(l) => Some(l),
+        Unit::None => None,
+    }
+}
+
+#[test]
+fn function_units() {
+    assert_eq!(eval_unit("abs(-5 Meter)"), Some("Meter".into()));
+    assert_eq!(eval_unit("abs(-5 \"m\")"), Some("m".into()));
+    assert_eq!(eval_unit("min(3, 2 Meter, 4 Meter)"), Some("Meter".into()));
+    assert_eq!(eval_unit("max(3 \"s\", 4 \"s\")"), Some("s".into()));
+    assert_eq!(eval_unit("sqrt(4 Meter * 4 Meter)"), Some("Meter".into()));
+    assert_eq!(eval_unit("sqrt(4 Meter)"), None);
+    assert_eq!(eval_unit("floor(4.5 Meter)"), None);
+    assert_eq!(eval("max(3, 7, 5)"), Ok(7.));
+    assert_eq!(eval("min(3, -7, 5)"), Ok(-7.));
+    assert_eq!(render("min(a, b)", ValueMode::NamedNoUnit), "$\\min\\left(\\mathit{a}, \\mathit{b}\\right)$");
+}
+
+#[test]
+fn strict_units() {
+    let mut lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    assert_eq!(eval_with(&lib, "sin(90 Meter)"), Ok(1.));
+    lib.set_strict_units(true);
+    assert!(eval_with(&lib, "sin(5 Meter)").unwrap_err().contains("unit"));
+    assert!(eval_with(&lib, "tan(5 Meter * 2)").is_err());
+    assert_eq!(eval_with(&lib, "sin(90)"), Ok(1.));
+    // literal units are only for display
+    assert_eq!(eval_with(&lib, "sin(90 \"deg\")"), Ok(1.));
+    // other functions are unaffected
+    assert_eq!(eval_with(&lib, "floor(2.5 Meter)"), Ok(2.));
+}
+
+#[test]
+fn precision_function() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter {
+        precision: 2,
+        ..Default::default()
+    });
+    assert_eq!(eval_with(&lib, "p(2.71828)"), Ok(2.72));
+    assert_eq!(eval_with(&lib, "p(2.71828, 0.5)"), Ok(2.5));
+    assert_eq!(eval("p(1.234567)"), Ok(1.23457));
+    assert_eq!(eval("p(17, 5)"), Ok(15.));
+    assert_eq!(render("p(x)", ValueMode::NamedNoUnit), "$\\mathit{x}$");
+}
+
+#[test]
+fn show_as() {
+    assert_eq!(eval("show_as(2, 3)"), Ok(2.));
+    assert_eq!(eval_unit("show_as(2 Meter, 3)"), Some("Meter".into()));
+    assert_eq!(eval_unit("disp(2 Meter, 3)"), Some("Meter".into()));
+    let m = |n| format!("{n}\\small\\text{{ Meter}}\\normalsize");
+    assert_eq!(
+        render("show_as(200 Meter, 2 Meter * 100)", ValueMode::NumbersWithUnit),
+        format!("${} \\cdot 100 = {}$", m(2), m(200))
+    );
+    assert_eq!(
+        render("show_as(200 Meter, 2 Meter * 100)", ValueMode::NamedNoUnit),
+        "$2 \\cdot 100$"
+    );
+    // shown is still evaluated
+    assert!(eval("show_as(1, 1 / 0)").is_err());
+    assert!(eval("show_as(1, unknown)").is_err());
+}
+
+#[test]
+fn flatten_associative() {
+    let mut lib = FormattableLibraryProvider::new(LatexFormatter {
+        juxtapose_products: true,
+        ..Default::default()
+    });
+    lib.set_flatten_associative(true);
+    let sources = [
+        ("1 + 2 + 3 + 4", "$1 + 2 + 3 + 4 = 10$"),
+        ("1 + (2 + 3) + 4", "$1 + 2 + 3 + 4 = 10$"),
+        ("1 + 2 * 3 * 4 + 5", "$1 + 2 \\cdot 3 \\cdot 4 + 5 = 30$"),
+        ("10 - 4 - 3 + 1", "$10 - 4 - 3 + 1 = 4$"),
+        ("2 * (1 + 2 + 3)", "$2 \\cdot \\left(1 + 2 + 3\\right) = 12$"),
+        ("log10(1 + 2 + 7)", "$\\log_{10}{\\left(1 + 2 + 7\\right)} = 1$"),
+    ];
+    for (source, expected) in sources {
+        assert_eq!(render_lib(&lib, source, ValueMode::NumbersWithUnit), expected);
+    }
+    // operands keep their order, and products render as before
+    let formatter = LatexFormatter {
+        juxtapose_products: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        render_lib(&lib, "2 * a * b", ValueMode::NamedNoUnit),
+        render_with(formatter, "2 * a * b", ValueMode::NamedNoUnit)
+    );
+    assert_eq!(eval_with(&lib, "100 / 10 / 2 * 3 * 4"), Ok(60.));
+}
+
+#[test]
+fn substitution_steps() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    let mut unit_lib = MockUnitLibrary;
+    let exps: Vec<_> = ["l = 3", "w = 4", "A = l * w"]
+        .iter()
+        .map(|s| Expression::new(tokenize(s).unwrap(), &lib).unwrap())
+        .collect();
+    let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+    cb.add_multi_calculation(&exps[..2], ValueMode::NumbersNoUnit, RowStyle::Expression).unwrap();
+    let index = cb.add_substitution_calculation(&exps[2], ValueMode::NumbersNoUnit).unwrap();
+    let calculations = cb.finish();
+    assert_eq!(lib.format_result(&unit_lib, &calculations, index), Some("$12$".into()));
+    assert_eq!(
+        lib.format_calculations(&unit_lib, calculations)[index],
+        "$\\mathit{A} = \\mathit{l} \\cdot \\mathit{w} = 3 \\cdot 4 = 12$"
+    );
+}
+
+#[test]
+fn result_unit_only() {
+    let source = "2 Meter * 3";
+    assert_eq!(
+        render(source, ValueMode::NumbersWithUnit),
+        "$2\\small\\text{ Meter}\\normalsize \\cdot 3 = 6\\small\\text{ Meter}\\normalsize$"
+    );
+    assert_eq!(
+        render(source, ValueMode::NumbersResultUnitOnly),
+        "$2 \\cdot 3 = 6\\small\\text{ Meter}\\normalsize$"
+    );
+    assert_eq!(render(source, ValueMode::NumbersNoUnit), "$2 \\cdot 3 = 6$");
+}
+
+#[test]
+fn multi_alignment() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    let mut unit_lib = MockUnitLibrary;
+    let exps: Vec<_> = ["l = 3", "w = 4", "A = l * w"]
+        .iter()
+        .map(|s| Expression::new(tokenize(s).unwrap(), &lib).unwrap())
+        .collect();
+    let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+    let substituted = cb.add_multi_calculation(&exps, ValueMode::NumbersNoUnit, RowStyle::Substitution).unwrap();
+    let named = cb.add_multi_calculation(&exps[2..], ValueMode::NamedNoUnit, RowStyle::Expression).unwrap();
+    let calculations = cb.finish();
+    assert_eq!(lib.format_result(&unit_lib, &calculations, named), None);
+    let formatted = lib.format_calculations(&unit_lib, calculations);
+    // every line is aligned on its first =
+    assert_eq!(
+        formatted[substituted],
+        "$$ \\begin{align*}\n \\mathit{l} &= 3\\\\ \\\\\n\\mathit{w} &= 4\\\\ \\\\\n\
+         \\mathit{A} &= \\mathit{l} \\cdot \\mathit{w} = 3 \\cdot 4 = 12\n\\end{align*} $$"
+    );
+    // lines without a relation are left-aligned
+    assert_eq!(
+        formatted[named],
+        "$$ \\begin{align*}\n &\\mathit{l} \\cdot \\mathit{w}\n\\end{align*} $$"
+    );
+}
+
+#[test]
+fn multi_spacing() {
+    let render_multi = |double_spacing| {
+        let lib = FormattableLibraryProvider::new(LatexFormatter {
+            double_spacing,
+            ..Default::default()
+        });
+        let mut eval_ctx = EvaluationContext::new();
+        let mut unit_lib = MockUnitLibrary;
+        let exps: Vec<_> = ["1", "2"]
+            .iter()
+            .map(|s| Expression::new(tokenize(s).unwrap(), &lib).unwrap())
+            .collect();
+        let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+        cb.add_multi_calculation(&exps, ValueMode::NumbersNoUnit, RowStyle::Expression).unwrap();
+        let calculations = cb.finish();
+        lib.format_calculations(&unit_lib, calculations).remove(0)
+    };
+    let double = render_multi(true);
+    assert_eq!(double, "$$ \\begin{align*}\n 1 &= 1\\\\ \\\\\n2 &= 2\n\\end{align*} $$");
+    assert!(!double.contains("2\\\\"));
+    assert_eq!(
+        render_multi(false),
+        "$$ \\begin{align*}\n 1 &= 1\\\\\n2 &= 2\n\\end{align*} $$"
+    );
+}
+
+#[test]
+fn render_without_eval() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let exp = Expression::new(tokenize("a + b * c").unwrap(), &lib).unwrap();
+    assert_eq!(
+        lib.render_expression(&exp, ValueMode::NamedNoUnit).unwrap(),
+        r"\mathit{a} + \mathit{b} \cdot \mathit{c}"
+    );
+    let exp = Expression::new(tokenize("x = 2 Meter").unwrap(), &lib).unwrap();
+    assert_eq!(
+        lib.render_expression(&exp, ValueMode::NamedLiteralUnit).unwrap(),
+        r"2\small\text{ Meter}\normalsize"
+    );
+    // variables have no values to write
+    let exp = Expression::new(tokenize("a + 1").unwrap(), &lib).unwrap();
+    let Err(err) = lib.render_expression(&exp, ValueMode::NumbersNoUnit) else {
+        panic!("variables can not be written as numbers");
+    };
+    assert_eq!(format!("{err:?}"), "Variable 'a' not found");
+    let exp = Expression::new(tokenize("2 + 1").unwrap(), &lib).unwrap();
+    assert_eq!(lib.render_expression(&exp, ValueMode::NumbersNoUnit).unwrap(), "2 + 1");
+}
+
+#[test]
+fn render_too_deep() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut nested = Expression::NumberLiteral(1.);
+    for _ in 0..300 {
+        nested = Expression::Negate(Box::new(nested));
+    }
+    let Err(err) = lib.render_expression(&nested, ValueMode::NamedNoUnit) else {
+        panic!("deeply nested expression should fail");
+    };
+    assert_eq!(format!("{err:?}"), "Expression is nested deeper than 128 levels");
+}
+
+#[test]
+fn render_long_operator_chain() {
+    let sum = format!("1{}", " + 1".repeat(999));
+    let rendered = render(&sum, ValueMode::NumbersWithUnit);
+    assert!(rendered.starts_with("$1 + 1 + 1"), "{rendered}");
+    assert!(rendered.ends_with("1 + 1 = 1000$"), "{rendered}");
+}
+
+#[test]
+fn fraction_operands() {
+    let render = |source| render(source, ValueMode::NamedNoUnit);
+    // the fraction bar groups its operands, so they are never parenthesized
+    assert_eq!(
+        render("(a + b) / (c - d)"),
+        "$\\dfrac{\\mathit{a} + \\mathit{b}}{\\mathit{c} - \\mathit{d}}$"
+    );
+    assert_eq!(render("a / (b / c)"), "$\\dfrac{\\mathit{a}}{\\dfrac{\\mathit{b}}{\\mathit{c}}}$");
+    assert_eq!(
+        render("-(a + b) / c"),
+        "$\\dfrac{-\\left(\\mathit{a} + \\mathit{b}\\right)}{\\mathit{c}}$"
+    );
+    assert_eq!(
+        render("(a + b) / (c - d) ** 2"),
+        "$\\dfrac{\\mathit{a} + \\mathit{b}}{\\left(\\mathit{c} - \\mathit{d}\\right)^{2}}$"
+    );
+    // a fraction as the base of a power still is
+    assert_eq!(
+        render("((a + b) / (c - d)) ** 2"),
+        "$\\left(\\dfrac{\\mathit{a} + \\mathit{b}}{\\mathit{c} - \\mathit{d}}\\right)^{2}$"
+    );
+    assert_eq!(
+        render_with(LatexFormatter::default(), "(1 + 2) / (4 - 1)", ValueMode::NumbersWithUnit),
+        "$\\dfrac{1 + 2}{4 - 1} = 1$"
+    );
+}
+
+#[test]
+fn non_associative_grouping() {
+    let render = |source| render(source, ValueMode::NamedNoUnit);
+    assert_eq!(render("a - (b - c)"), "$\\mathit{a} - \\left(\\mathit{b} - \\mathit{c}\\right)$");
+    assert_eq!(render("a - (b + c)"), "$\\mathit{a} - \\left(\\mathit{b} + \\mathit{c}\\right)$");
+    assert_eq!(render("a // (b // c)"), "$\\mathit{a}\\div \\left(\\mathit{b}\\div \\mathit{c}\\right)$");
+    assert_eq!(render("a // (b * c)"), "$\\mathit{a}\\div \\left(\\mathit{b} \\cdot \\mathit{c}\\right)$");
+    // left to right needs no parentheses, and neither do associative operators
+    assert_eq!(render("(a - b) - c"), "$\\mathit{a} - \\mathit{b} - \\mathit{c}$");
+    assert_eq!(render("a + (b - c)"), "$\\mathit{a} + \\mathit{b} - \\mathit{c}$");
+    assert_eq!(
+        render_with(LatexFormatter::default(), "10 - (4 - 1)", ValueMode::NumbersWithUnit),
+        "$10 - \\left(4 - 1\\right) = 7$"
+    );
+}
+
+#[test]
+fn annotated_variables() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    let mut unit_lib = MockUnitLibrary;
+    for source in ["v = 3 \"m/s\"", "t = 2 \"s\"", "k = 2"] {
+        let exp = Expression::new(tokenize(source).unwrap(), &lib).unwrap();
+        exp.eval(&lib, &mut eval_ctx).unwrap();
+    }
+    let exp = Expression::new(tokenize("v * t * k").unwrap(), &lib).unwrap();
+    let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+    cb.add_single_calculation(&exp, ValueMode::NamedVariableUnit).unwrap();
+    // the unitless k is only named
+    assert_eq!(
+        lib.format_calculations(&MockUnitLibrary, cb.finish()).remove(0),
+        "$\\mathit{v}\\,[\\small\\text{m/s}\\normalsize] \\cdot \\mathit{t}\\,[\\small\\text{s}\\normalsize] \\cdot \\mathit{k}$"
+    );
+    // number literals keep their unit like with NamedLiteralUnit
+    assert_eq!(render("2 \"m\"", ValueMode::NamedVariableUnit), "$2\\small\\text{ m}\\normalsize$");
+}
+
+#[test]
+fn partial_evaluation() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    eval_ctx.store_variable("a", (3., Unit::None));
+    eval_ctx.store_variable("l", (2., Unit::Defined(DefinedUnit::Defined("Meter".into()))));
+    let mut unit_lib = MockUnitLibrary;
+    let mut cb = lib.make_calculations(&mut eval_ctx, &mut unit_lib);
+    for source in ["a + b", "l * w + 2 * a", "a * l", "c = a + b"] {
+        let exp = Expression::new(tokenize(source).unwrap(), &lib).unwrap();
+        cb.add_single_calculation(&exp, ValueMode::NumbersPartial).unwrap();
+    }
+    // unknown variables stay unknown after an assignment
+    assert!(cb.eval_ctx().get_variable("c").is_none());
+    let calculations = cb.finish();
+    assert_eq!(
+        lib.format_calculations(&unit_lib, calculations),
+        [
+            "$3 + \\mathit{b}$",
+            "$2\\small\\text{ Meter}\\normalsize \\cdot \\mathit{w} + 2 \\cdot 3$",
+            "$3 \\cdot 2\\small\\text{ Meter}\\normalsize = 6\\small\\text{ Meter}\\normalsize$",
+            "$3 + \\mathit{b}$",
+        ]
+    );
+}
+
+#[test]
+fn inline_division() {
+    assert_eq!(render("a ÷ b", ValueMode::NamedNoUnit), "$\\mathit{a}/\\mathit{b}$");
+    assert_eq!(render("a / b", ValueMode::NamedNoUnit), "$\\dfrac{\\mathit{a}}{\\mathit{b}}$");
+    assert_eq!(render("(1 + 2) ÷ 4", ValueMode::NumbersNoUnit), "$\\left(1 + 2\\right)/4 = 0.75$");
+    assert_eq!(render("8 ÷ (4 ÷ 2)", ValueMode::NumbersNoUnit), "$8/\\left(4/2\\right) = 4$");
+}
+
+#[test]
+fn exact_fractions() {
+    let exact = |source, snap_epsilon| {
+        let formatter = LatexFormatter {
+            exact_fractions: Some(100),
+            snap_epsilon,
+            ..Default::default()
+        };
+        render_with(formatter, source, ValueMode::NumbersWithUnit)
+    };
+    assert_eq!(exact("0.5", None), "$0.5 = 0.5 = \\frac{1}{2}$");
+    assert_eq!(exact("1 // 3", None), "$1\\div 3 = 0.33333 = \\frac{1}{3}$");
+    assert_eq!(exact("0.3333", Some(1e-4)), "$0.3333 = 0.3333 = \\frac{1}{3}$");
+    assert_eq!(
+        exact("-7 // 4 \"m\"", None),
+        "$-7\\div 4\\small\\text{ m}\\normalsize = -1.75\\small\\text{ m}\\normalsize = -\\frac{7}{4}\\small\\text{ m}\\normalsize$"
+    );
+    // irrational, integer, and denominator above the limit
+    assert_eq!(exact("pi()", None), "$\\pi = 3.14159$");
+    assert_eq!(exact("6 // 3", None), "$6\\div 3 = 2$");
+    assert_eq!(exact("1 // 101", None), "$1\\div 101 = 0.0099$");
+    // off by default
+    assert_eq!(render("0.5", ValueMode::NumbersWithUnit), "$0.5 = 0.5$");
+}
+
+#[test]
+fn named_constants() {
+    let constants = |source| {
+        let formatter = LatexFormatter {
+            named_constants: true,
+            ..Default::default()
+        };
+        render_with(formatter, source, ValueMode::NumbersWithUnit)
+    };
+    assert_eq!(constants("3.14159"), "$3.14159 = \\pi$");
+    assert_eq!(constants("1 - 4.14159"), "$1 - 4.14159 = -\\pi$");
+    assert_eq!(constants("(1 + sqrt(5)) / 2"), "$\\dfrac{1 + \\sqrt{5}}{2} = \\varphi$");
+    assert_eq!(constants("sqrt(2) \"m\""), "$\\sqrt{2} = \\sqrt{2}\\small\\text{ m}\\normalsize$");
+    // only results are replaced, and only when written the same
+    assert_eq!(constants("3.1416"), "$3.1416 = 3.1416$");
+    assert_eq!(render("3.14159", ValueMode::NumbersWithUnit), "$3.14159 = 3.14159$");
+}
+
+#[test]
+fn statistics() {
+    assert_eq!(eval("mean(2, 4, 6)"), Ok(4.));
+    assert_eq!(eval("mean(5)"), Ok(5.));
+    // sample standard deviation of 2, 4, 4, 4, 5, 5, 7, 9 is sqrt(32 / 7)
+    let stddev = eval("stddev(2, 4, 4, 4, 5, 5, 7, 9)").unwrap();
+    assert!((stddev - (32f64 / 7.).sqrt()).abs() < 1e-12);
+    assert_eq!(eval("stddev(3, 3)"), Ok(0.));
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    assert!(Expression::new(tokenize("stddev(3)").unwrap(), &lib).is_err());
+    assert_eq!(eval_unit("mean(2 Meter, 4 Meter)"), Some("Meter".into()));
+    assert_eq!(eval_unit("stddev(2 \"s\", 4 \"s\")"), Some("s".into()));
+    assert_eq!(
+        render("mean(2, 4, 6)", ValueMode::NumbersNoUnit),
+        "$\\bar{x}\\left(2, 4, 6\\right) = 4$"
+    );
+    assert_eq!(
+        render("stddev(a, b)", ValueMode::NamedNoUnit),
+        "$s\\left(\\mathit{a}, \\mathit{b}\\right)$"
+    );
+}
+
+#[test]
+fn weighted_average() {
+    assert_eq!(eval("wavg(80, 1, 90, 3)"), Ok(87.5));
+    assert_eq!(eval("wavg(4, 2)"), Ok(4.));
+    assert_eq!(eval("wavg(1, 0, 2, 0)"), Err("\"the total weight of wavg is zero\"".into()));
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    assert!(Expression::new(tokenize("wavg(1, 2, 3)").unwrap(), &lib).is_err());
+    assert_eq!(eval_unit("wavg(2 Meter, 1 Second, 4 Meter, 1 Second)"), Some("Meter".into()));
+    assert_eq!(
+        render("wavg(a, x, b + c, y)", ValueMode::NamedNoUnit),
+        "$\\dfrac{\\mathit{a} \\cdot \\mathit{x} + \\left(\\mathit{b} + \\mathit{c}\\right) \\cdot \\mathit{y}}{\\mathit{x} + \\mathit{y}}$"
+    );    let times = LatexFormatter {
+        multiplication_symbol: MultiplicationSymbol::Times,
+        ..Default::default()
+    };
+    assert_eq!(
+        render_with(times, "wavg(a, x, b, y)", ValueMode::NamedNoUnit),
+        "$\\dfrac{\\mathit{a} \\times \\mathit{x} + \\mathit{b} \\times \\mathit{y}}{\\mathit{x} + \\mathit{y}}$"
+    );
+}
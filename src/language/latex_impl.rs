@@ -2,44 +2,322 @@ mod functions;
 mod operators;
 
 use crate::language::format::{
-    FormattableFunction, FormattableLibraryProvider, FormattableOperator, LanguageFormatter,
-    ResolvedFormattableExpression,
+    FormattableExpression, FormattableFunction, FormattableLibraryProvider, FormattableOperator, FormattableUnaryOperator,
+    LanguageFormatter,
+    ResolvedFormattableExpression, escape_text,
 };
 
 pub struct LatexFormatter {
     pub precision: usize,
+    /// Rescale numbers with SI units to use prefixes, eg. 1500 m becomes 1.5 km.
+    pub si_prefixes: Option<SiPrefixes>,
+    /// Write products of variables without a dot, eg. ab and 2a instead of a·b and 2·a
+    pub juxtapose_products: bool,
+    /// Used for products that are not juxtaposed, [MultiplicationSymbol::Dot] by default.
+    pub multiplication_symbol: MultiplicationSymbol,
+    /// Used both when writing numbers and in the `p` function.  
+    /// By default numbers are written with ties to even and `p` rounds ties away from zero, like [f64::round].
+    pub rounding: Option<Rounding>,
+    /// Numbers within this (relative) distance of a number with fewer decimals are written as that number.  
+    /// This hides floating point noise like 0.1 + 0.2 = 0.30000000000000004 when using a high precision.
+    pub snap_epsilon: Option<f64>,
+    /// Results that are a simple fraction are also written as the fraction, eg. 0.33333 = ⅓.  
+    /// Only fractions with a denominator up to this are found, off by default.
+    pub exact_fractions: Option<u64>,
+    /// Results that are written the same as a known constant, like π or √2, are written as the constant, off by default.
+    pub named_constants: bool,
+    /// Write units before numbers, eg. for currencies. The sign is still written first, eg. -\$5
+    pub unit_prefix: bool,
+    /// Used by the trigonometric functions, [AngleMode::Degrees] by default.  
+    /// Functions are built from the formatter, so changing this after building a library has no effect.
+    pub angle_mode: AngleMode,
+    /// Leave an empty line between the equations of multi calculations, on by default.
+    pub double_spacing: bool,
 }
 
-impl LanguageFormatter for LatexFormatter {
-    fn parenthesise(
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AngleMode {
+    #[default]
+    Degrees,
+    Radians,
+}
+
+impl AngleMode {
+    /// Converts an angle in this mode to radians.
+    pub fn to_radians(self, angle: f64) -> f64 {
+        match self {
+            AngleMode::Degrees => angle.to_radians(),
+            AngleMode::Radians => angle,
+        }
+    }
+
+    /// Converts an angle in radians to this mode.
+    pub fn from_radians(self, angle: f64) -> f64 {
+        match self {
+            AngleMode::Degrees => angle.to_degrees(),
+            AngleMode::Radians => angle,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MultiplicationSymbol {
+    /// a·b
+    #[default]
+    Dot,
+    /// a×b
+    Times,
+}
+
+impl MultiplicationSymbol {
+    pub fn latex(self) -> &'static str {
+        match self {
+            MultiplicationSymbol::Dot => "\\cdot",
+            MultiplicationSymbol::Times => "\\times",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rounding {
+    /// Ties are rounded away from zero, eg. 2.5 becomes 3 and -2.5 becomes -3
+    HalfUp,
+    /// Ties are rounded to the nearest even number, eg. 2.5 becomes 2 and 3.5 becomes 4
+    HalfEven,
+}
+
+impl Rounding {
+    /// Rounds to an integer.
+    pub fn round(self, value: f64) -> f64 {
+        match self {
+            Rounding::HalfUp => value.round(),
+            Rounding::HalfEven => value.round_ties_even(),
+        }
+    }
+}
+
+impl Default for LatexFormatter {
+    fn default() -> Self {
+        Self {
+            precision: 5,
+            si_prefixes: None,
+            juxtapose_products: false,
+            multiplication_symbol: MultiplicationSymbol::default(),
+            rounding: None,
+            snap_epsilon: None,
+            exact_fractions: None,
+            named_constants: false,
+            unit_prefix: false,
+            angle_mode: AngleMode::default(),
+            double_spacing: true,
+        }
+    }
+}
+
+/// Used to display numbers with SI units using a prefix. Only affects how numbers are displayed.
+pub struct SiPrefixes {
+    /// Display names of the units that can be prefixed.
+    pub units: Vec<String>,
+    /// Prefixes and their factors, an empty prefix with factor 1 should be included to allow keeping the base unit.
+    pub prefixes: Vec<(String, f64)>,
+    /// The largest prefix where the rescaled value is at least this is picked.
+    pub threshold: f64,
+}
+
+impl Default for SiPrefixes {
+    fn default() -> Self {
+        let units = ["m", "g", "s", "A", "K", "mol", "cd", "N", "J", "W", "Pa", "Hz", "V", "C"];
+        let prefixes = [
+            ("G", 1e9),
+            ("M", 1e6),
+            ("k", 1e3),
+            ("", 1.),
+            ("m", 1e-3),
+            ("µ", 1e-6),
+            ("n", 1e-9),
+        ];
+        Self {
+            units: units.into_iter().map(String::from).collect(),
+            prefixes: prefixes.into_iter().map(|(p, f)| (p.to_string(), f)).collect(),
+            threshold: 1.,
+        }
+    }
+}
+
+impl SiPrefixes {
+    /// None if the unit should not be prefixed.
+    fn scale(&self, number: f64, unit: &str) -> Option<(f64, String)> {
+        if number == 0. || !self.units.iter().any(|u| u == unit) {
+            return None;
+        }
+        let (prefix, factor) = self
+            .prefixes
+            .iter()
+            .filter(|(_, f)| number.abs() / f >= self.threshold)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            // smaller than all thresholds, so use the smallest prefix
+            .or_else(|| self.prefixes.iter().min_by(|(_, a), (_, b)| a.total_cmp(b)))?;
+        Some((number / factor, format!("{prefix}{unit}")))
+    }
+}
+
+/// Used by [LatexFormatter::named_constants].
+const CONSTANTS: [(&str, f64); 4] = [
+    ("\\pi", std::f64::consts::PI),
+    ("e", std::f64::consts::E),
+    ("\\varphi", 1.618_033_988_749_895),
+    ("\\sqrt{2}", std::f64::consts::SQRT_2),
+];
+
+/// The fraction closest to number with a denominator up to max_denominator, from the continued fraction of number.  
+/// [None] for integers, and if no such fraction is within tolerance.
+fn simple_fraction(number: f64, max_denominator: u64, tolerance: f64) -> Option<(f64, f64)> {
+    if !number.is_finite() || number.fract() == 0. {
+        return None;
+    }
+    let max_denominator = max_denominator as f64;
+    // the two previous convergents
+    let (mut p0, mut q0, mut p1, mut q1) = (0., 1., 1., 0.);
+    let mut x = number.abs();
+    loop {
+        let a = x.floor();
+        let (p, q) = (a * p1 + p0, a * q1 + q0);
+        if q > max_denominator {
+            return None;
+        }
+        if (p / q - number.abs()).abs() <= tolerance {
+            return (q > 1.).then_some((p.copysign(number), q));
+        }
+        (p0, q0, p1, q1) = (p1, q1, p, q);
+        x = 1. / (x - a);
+    }
+}
+
+impl LatexFormatter {
+    /// Rescales the number with [si_prefixes](Self::si_prefixes), if it applies to the unit.
+    fn scaled(&self, number: f64, unit: Option<&str>) -> (f64, Option<String>) {
+        let scaled = self
+            .si_prefixes
+            .as_ref()
+            .zip(unit)
+            .and_then(|(si, unit)| si.scale(number, unit));
+        match scaled {
+            Some((number, unit)) => (number, Some(unit)),
+            None => (number, unit.map(str::to_string)),
+        }
+    }
+
+    /// Writes an already formatted number with its unit.
+    fn write_with_unit(&self, num: &str, unit: Option<&str>, out: &mut String) {
+        match unit {
+            Some(u) if self.unit_prefix => {
+                let (sign, num) = num.strip_prefix('-').map_or(("", num), |n| ("-", n));
+                out.push_str(sign);
+                self.write_unit(u, out);
+                out.push(' ');
+                out.push_str(num);
+            }
+            Some(u) => {
+                out.push_str(num);
+                self.write_unit(&format!(" {u}"), out);
+            }
+            None => out.push_str(num),
+        }
+    }
+
+    /// Writes the result of a calculation, as a constant if it is one, see [named_constants](Self::named_constants).
+    fn write_result(
         &self,
         lib: &FormattableLibraryProvider<Self>,
-        expr: &ResolvedFormattableExpression,
+        result: &ResolvedFormattableExpression,
         out: &mut String,
     ) {
-        lib.fmt_expression("($0)", &[expr], out);
+        if let FormattableExpression::Number { value, unit } = result
+            && self.named_constants
+        {
+            let (value, unit) = self.scaled(*value, unit.as_deref());
+            // the same when written with the precision
+            let tolerance = 0.5 * 10f64.powi(-(self.precision as i32));
+            let constant = CONSTANTS.iter().find(|(_, c)| (value.abs() - c).abs() < tolerance);
+            if let Some((name, _)) = constant {
+                let sign = if value < 0. { "-" } else { "" };
+                self.write_with_unit(&format!("{sign}{name}"), unit.as_deref(), out);
+                return;
+            }
+        }
+        lib.write_expression(result, out);
+        self.write_exact_result(result, out);
+    }
+
+    /// Appends ` = ` and the result as a fraction, if it is a number that is a simple fraction, see [exact_fractions](Self::exact_fractions).
+    fn write_exact_result(&self, result: &ResolvedFormattableExpression, out: &mut String) {
+        let (Some(max_denominator), FormattableExpression::Number { value, unit }) =
+            (self.exact_fractions, result)
+        else {
+            return;
+        };
+        let (value, unit) = self.scaled(*value, unit.as_deref());
+        let tolerance = self.snap_epsilon.unwrap_or(1e-9) * value.abs().max(1.);
+        let Some((p, q)) = simple_fraction(value, max_denominator, tolerance) else {
+            return;
+        };
+        let sign = if p < 0. { "-" } else { "" };
+        out.push_str(" = ");
+        self.write_with_unit(&format!("{sign}\\frac{{{}}}{{{q}}}", p.abs()), unit.as_deref(), out);
     }
 
-    fn negate(
+    /// The fewest decimals that can be used to write the number, see [snap_epsilon](Self::snap_epsilon).
+    fn snapped_decimals(&self, number: f64, epsilon: f64) -> Option<usize> {
+        let tolerance = epsilon * number.abs().max(1.);
+        (0..self.precision).find(|&decimals| {
+            let factor = 10f64.powi(decimals as i32);
+            ((number * factor).round() / factor - number).abs() <= tolerance
+        })
+    }
+}
+
+impl LanguageFormatter for LatexFormatter {
+    fn parenthesise(
         &self,
         lib: &FormattableLibraryProvider<Self>,
         expr: &ResolvedFormattableExpression,
         out: &mut String,
     ) {
-        lib.fmt_expression("-$0", &[expr], out);
+        lib.fmt_expression("\\left($0\\right)", &[expr], out);
     }
 
     fn write_number(&self, number: f64, unit: Option<&str>, out: &mut String) {
-        let num = format!("{:.*}", self.precision, number);
-        let num = num.trim_end_matches('0').trim_end_matches('.');
-        let unit = unit
-            .map(|u| format!("\\small\\text{{ {u}}}\\normalsize"))
-            .unwrap_or_default();
-        out.push_str(&format!("{num}{unit}"))
+        let (number, unit) = self.scaled(number, unit);
+        let decimals = self
+            .snap_epsilon
+            .and_then(|epsilon| self.snapped_decimals(number, epsilon))
+            .unwrap_or(self.precision);
+        // formatting rounds ties to even, so round first to respect the rounding mode
+        let number = match self.rounding {
+            Some(rounding) => {
+                let factor = 10f64.powi(decimals as i32);
+                let rounded = rounding.round(number * factor) / factor;
+                if rounded.is_finite() { rounded } else { number }
+            }
+            None => number,
+        };
+        let num = format!("{:.*}", decimals, number);
+        let num = if num.contains('.') {
+            num.trim_end_matches('0').trim_end_matches('.')
+        } else {
+            &num
+        };
+        self.write_with_unit(num, unit.as_deref(), out);
+    }
+
+    fn negation_precedence(&self) -> u32 {
+        // -a * b is the same either way, only power binds tighter
+        1
     }
 
     fn write_variable(&self, variable: &str, out: &mut String) {
-        let parts: Vec<_> = variable.split('_').collect();
+        // _ is a subscript, so only the parts are escaped
+        let parts: Vec<_> = variable.split('_').map(escape_text).collect();
         let mut r = parts.join("_{");
         r.push_str(&"}".repeat(parts.len()-1));
         out.push_str(&format!("\\mathit{{{}}}", &r));
@@ -53,7 +331,9 @@ impl LanguageFormatter for LatexFormatter {
     ) -> String {
         let mut res = String::new();
         if let Some(result) = result {
-            lib.fmt_expression("$$$0 = $1$$", &[expr, result], &mut res);
+            lib.fmt_expression("$$$0 = ", &[expr], &mut res);
+            self.write_result(lib, result, &mut res);
+            res.push('$');
         } else {
             lib.fmt_expression("$$$0$$", &[expr], &mut res);
         }
@@ -63,21 +343,68 @@ impl LanguageFormatter for LatexFormatter {
     fn format_multi(
         &self,
         lib: &FormattableLibraryProvider<Self>,
-        expr: &[(ResolvedFormattableExpression, ResolvedFormattableExpression)],
+        rows: &[Vec<ResolvedFormattableExpression>],
     ) -> String {
         let mut out = "$$ \\begin{align*}\n ".to_string();
-        for (exp, res) in expr {
-            lib.fmt_expression("$0 &= $1\\\\ \\\\\n", &[exp, res], &mut out);
+        let separator = if self.double_spacing {
+            "\\\\ \\\\\n"
+        } else {
+            "\\\\\n"
+        };
+        for (i, row) in rows.iter().enumerate() {
+            if i > 0 {
+                out.push_str(separator);
+            }
+            match &row[..] {
+                // no relation to align on
+                [exp] => lib.fmt_expression("&$0", &[exp], &mut out),
+                [first, rest @ ..] => {
+                    lib.write_expression(first, &mut out);
+                    for (i, step) in rest.iter().enumerate() {
+                        out.push_str(if i == 0 { " &= " } else { " = " });
+                        if i == rest.len() - 1 {
+                            self.write_result(lib, step, &mut out);
+                        } else {
+                            lib.write_expression(step, &mut out);
+                        }
+                    }
+                }
+                [] => {}
+            }
         }
-        out.push_str("\\end{align*} $$");
+        out.push_str("\n\\end{align*} $$");
+        out
+    }
+
+    fn format_steps(
+        &self,
+        lib: &FormattableLibraryProvider<Self>,
+        steps: &[ResolvedFormattableExpression],
+    ) -> String {
+        let mut out = "$".to_string();
+        for (i, step) in steps.iter().enumerate() {
+            if i > 0 {
+                out.push_str(" = ");
+            }
+            if i == steps.len() - 1 {
+                self.write_result(lib, step, &mut out);
+            } else {
+                lib.write_expression(step, &mut out);
+            }
+        }
+        out.push('$');
         out
     }
 
     fn build_operators(&self) -> Vec<Box<dyn FormattableOperator<Self>>> {
-        operators::operators()
+        operators::operators(self)
     }
 
     fn build_functions(&self) -> Vec<Box<dyn FormattableFunction<Self>>> {
-        functions::functions()
+        functions::functions(self)
+    }
+
+    fn build_unary_operators(&self) -> Vec<Box<dyn FormattableUnaryOperator<Self>>> {
+        operators::unary_operators()
     }
 }
@@ -1,28 +1,68 @@
 use crate::language::format::BasicFunction;
 use crate::language::format::FormattableFunction;
-use crate::language::latex_impl::LatexFormatter;
+use crate::language::format::{
+    FormattableExpression, FormattableLibraryProvider, ResolvedFormattableExpression,
+};
+use crate::language::expression::{DefinedUnit, Unit};
+use crate::language::latex_impl::{AngleMode, LatexFormatter, MultiplicationSymbol, Rounding};
 
-pub fn functions() -> Vec<Box<dyn FormattableFunction<LatexFormatter>>> {
+pub fn functions(formatter: &LatexFormatter) -> Vec<Box<dyn FormattableFunction<LatexFormatter>>> {
     vec![
         Box::new(Pi),
         Box::new(E),
+        Box::new(Tau),
         Box::new(Parenthesize),
         Box::new(Floor),
         Box::new(Ceil),
+        Box::new(Trunc),
+        Box::new(Frac),
         Box::new(Abs),
+        Box::new(Sign),
+        Box::new(Extremum { max: false }),
+        Box::new(Extremum { max: true }),
+        Box::new(Mean),
+        Box::new(StdDev),
+        Box::new(WeightedAverage {
+            multiplication_symbol: formatter.multiplication_symbol,
+        }),
         Box::new(Sqrt),
         Box::new(NRoot),
-        Box::new(Log10),
-        Box::new(Log),
-        Box::new(Sin),
-        Box::new(Cos),
-        Box::new(Tan),
-        Box::new(Atan),
-        Box::new(Asin),
-        Box::new(Acos),
+        Box::new(Cbrt),
+        Box::new(Hypot),
+        Box::new(Grouped(Log10)),
+        Box::new(Grouped(Log2)),
+        Box::new(Grouped(Log)),
+        Box::new(Grouped(Sin {
+            angle_mode: formatter.angle_mode,
+        })),
+        Box::new(Grouped(Cos {
+            angle_mode: formatter.angle_mode,
+        })),
+        Box::new(Grouped(Tan {
+            angle_mode: formatter.angle_mode,
+        })),
+        Box::new(Grouped(Atan {
+            angle_mode: formatter.angle_mode,
+        })),
+        Box::new(Grouped(Asin {
+            angle_mode: formatter.angle_mode,
+        })),
+        Box::new(Grouped(Acos {
+            angle_mode: formatter.angle_mode,
+        })),
         Box::new(Modulo),
-        Box::new(Precision),
-        Box::new(Display),
+        Box::new(Choose),
+        Box::new(Permutations),
+        Box::new(Precision {
+            rounding: formatter.rounding.unwrap_or(Rounding::HalfUp),
+            precision: formatter.precision,
+        }),
+        Box::new(ShowAs { name: "show_as" }),
+        // older name of show_as
+        Box::new(ShowAs { name: "disp" }),
+        Box::new(PercentError),
+        Box::new(RelativeDifference),
+        Box::new(Vector),
     ]
 }
 
@@ -36,6 +76,86 @@ macro_rules! impl_basic_function {
             fn eval(&self, $args: &[f64]) -> Result<f64, String> $eval
         }
     };
+    ($type:ty, $name:expr, $arg_count:expr, $fmt:expr, |$args:ident| $eval:block, |$units:ident| $unit:block) => {
+        impl BasicFunction<LatexFormatter> for $type {
+            const NAME: &'static str = $name;
+            const ARG_COUNT: usize = $arg_count;
+            const FMT: &'static str = $fmt;
+
+            fn eval(&self, $args: &[f64]) -> Result<f64, String> $eval
+
+            fn result_unit(&self, $units: &[Unit]) -> Unit $unit
+        }
+    };
+}
+
+/// Like [impl_basic_function], for single argument functions that depend on the [AngleMode] of the formatter.
+macro_rules! impl_angle_function {
+    ($type:ty, $name:expr, $fmt:expr, |$mode:ident, $args:ident| $eval:block) => {
+        impl BasicFunction<LatexFormatter> for $type {
+            const NAME: &'static str = $name;
+            const ARG_COUNT: usize = 1;
+            const FMT: &'static str = $fmt;
+
+            fn eval(&self, $args: &[f64]) -> Result<f64, String> {
+                let $mode = self.angle_mode;
+                $eval
+            }
+
+            fn dimensionless_args(&self) -> bool {
+                true
+            }
+        }
+    };
+}
+
+/// Parenthesises the first argument of a function written without parentheses, when it is an operator.  
+/// Without this `log(x*y, 2)` is written as log₂x·y, where it looks like only x is the argument.  
+/// Division is left alone, since the division line already groups it.
+struct Grouped<T>(T);
+
+impl<T: BasicFunction<LatexFormatter>> FormattableFunction<LatexFormatter> for Grouped<T> {
+    fn name(&self) -> &str {
+        T::NAME
+    }
+
+    fn supports_arg_count(&self, argc: usize) -> bool {
+        argc == T::ARG_COUNT
+    }
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        self.0.eval(args)
+    }
+
+    fn result_unit(&self, arg_units: &[Unit]) -> Unit {
+        self.0.result_unit(arg_units)
+    }
+
+    fn dimensionless_args(&self) -> bool {
+        self.0.dimensionless_args()
+    }
+
+    fn undefined_at(&self, args: &[f64]) -> bool {
+        self.0.undefined_at(args)
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        args: &[ResolvedFormattableExpression],
+    ) {
+        let mut refs: Vec<_> = args.iter().collect();
+        let grouped;
+        if let FormattableExpression::Operator { operator, .. }
+        | FormattableExpression::OperatorChain { operator, .. } = &args[0]
+            && operator != "/"
+        {
+            grouped = FormattableExpression::Parenthesis(Box::new(args[0].clone()));
+            refs[0] = &grouped;
+        }
+        lib.fmt_expression(T::FMT, &refs, out);
+    }
 }
 
 struct Pi;
@@ -44,17 +164,50 @@ impl_basic_function!(Pi, "pi", 0, "\\pi", |_args| { Ok(std::f64::consts::PI) });
 struct E;
 impl_basic_function!(E, "e", 0, "e", |_args| { Ok(std::f64::consts::E) });
 
+struct Tau;
+impl_basic_function!(Tau, "tau", 0, "\\tau", |_args| { Ok(std::f64::consts::TAU) });
+
 struct Parenthesize;
-impl_basic_function!(Parenthesize, "par", 1, "( $0 )", |args| { Ok(args[0]) });
+impl BasicFunction<LatexFormatter> for Parenthesize {
+    const NAME: &'static str = "par";
+    const ARG_COUNT: usize = 1;
+    const FMT: &'static str = "\\left($0\\right)";
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        Ok(args[0])
+    }
+
+    fn is_grouping(&self) -> bool {
+        true
+    }
+}
 
 struct Floor;
-impl_basic_function!(Floor, "floor", 1, "\\lfloor $0 \\rfloor", |args| { Ok(args[0].floor()) });
+impl_basic_function!(Floor, "floor", 1, "\\left\\lfloor $0 \\right\\rfloor", |args| { Ok(args[0].floor()) });
 
 struct Ceil;
-impl_basic_function!(Ceil, "ceil", 1, "\\lceil $0 \\rceil", |args| { Ok(args[0].ceil()) });
+impl_basic_function!(Ceil, "ceil", 1, "\\left\\lceil $0 \\right\\rceil", |args| { Ok(args[0].ceil()) });
+
+struct Trunc;
+impl_basic_function!(Trunc, "trunc", 1, "\\operatorname{trunc}\\left($0\\right)", |args| { Ok(args[0].trunc()) });
+
+struct Frac;
+impl_basic_function!(Frac, "frac", 1, "\\operatorname{frac}\\left($0\\right)", |args| { Ok(args[0].fract()) });
 
 struct Abs;
-impl_basic_function!(Abs, "abs", 1, "|$0|", |args| { Ok(args[0].abs()) });
+impl_basic_function!(Abs, "abs", 1, "\\left|$0\\right|", |args| { Ok(args[0].abs()) }, |units| {
+    units[0].clone()
+});
+
+struct Sign;
+impl_basic_function!(Sign, "sign", 1, "\\operatorname{sgn}\\left($0\\right)", |args| {
+    // signum is 1 for 0
+    if args[0] == 0.0 {
+        Ok(0.0)
+    } else {
+        Ok(args[0].signum())
+    }
+});
 
 struct Sqrt;
 impl_basic_function!(Sqrt, "sqrt", 1, "\\sqrt{$0}", |args| {
@@ -63,6 +216,16 @@ impl_basic_function!(Sqrt, "sqrt", 1, "\\sqrt{$0}", |args| {
     } else {
         Ok(args[0].sqrt())
     }
+}, |units| {
+    // only the square of a named unit has a root, eg. m*m becomes m
+    match &units[0] {
+        Unit::Defined(DefinedUnit::Implicit { operator, left, right, .. })
+            if operator == "*" && left == right =>
+        {
+            Unit::Defined(left.as_ref().clone())
+        }
+        _ => Unit::None,
+    }
 });
 
 struct NRoot;
@@ -74,6 +237,47 @@ impl_basic_function!(NRoot, "nroot", 2, "\\sqrt[$1]{$0}", |args| {
     }
 });
 
+struct Cbrt;
+impl_basic_function!(Cbrt, "cbrt", 1, "\\sqrt[3]{$0}", |args| { Ok(args[0].cbrt()) });
+
+struct Hypot;
+
+impl FormattableFunction<LatexFormatter> for Hypot {
+    fn name(&self) -> &str {
+        "hypot"
+    }
+
+    fn supports_arg_count(&self, argc: usize) -> bool {
+        argc == 2
+    }
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        Ok(args[0].hypot(args[1]))
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        args: &[ResolvedFormattableExpression],
+    ) {
+        // unlike grouped, everything that ^{2} would not apply to as a whole is parenthesised, eg. -3 and 3 m
+        let base = |arg: &ResolvedFormattableExpression| match arg {
+            FormattableExpression::Operator { .. }
+            | FormattableExpression::OperatorChain { .. }
+            | FormattableExpression::Negate(_)
+            | FormattableExpression::UnaryOperator { .. } => {
+                FormattableExpression::Parenthesis(Box::new(arg.clone()))
+            }
+            FormattableExpression::Number { value, unit } if *value < 0. || unit.is_some() => {
+                FormattableExpression::Parenthesis(Box::new(arg.clone()))
+            }
+            _ => arg.clone(),
+        };
+        lib.fmt_expression("\\sqrt{$0^{2} + $1^{2}}", &[&base(&args[0]), &base(&args[1])], out);
+    }
+}
+
 struct Log10;
 impl_basic_function!(Log10, "log10", 1, "\\log_{10}{$0}", |args| {
     if args[0] <= 0.0 {
@@ -83,6 +287,15 @@ impl_basic_function!(Log10, "log10", 1, "\\log_{10}{$0}", |args| {
     }
 });
 
+struct Log2;
+impl_basic_function!(Log2, "log2", 1, "\\log_2{$0}", |args| {
+    if args[0] <= 0.0 {
+        Err("log2 of non-positive number".into())
+    } else {
+        Ok(args[0].log2())
+    }
+});
+
 struct Log;
 impl_basic_function!(Log, "log", 2, "\\log_{$1}{$0}", |args| {
     if args[0] <= 0.0 || args[1] <= 0.0 {
@@ -92,33 +305,62 @@ impl_basic_function!(Log, "log", 2, "\\log_{$1}{$0}", |args| {
     }
 });
 
-struct Sin;
-impl_basic_function!(Sin, "sin", 1, "\\sin{$0}", |args| { Ok(args[0].to_radians().sin()) });
+struct Sin {
+    angle_mode: AngleMode,
+}
+impl_angle_function!(Sin, "sin", "\\sin{$0}", |mode, args| { Ok(mode.to_radians(args[0]).sin()) });
 
-struct Cos;
-impl_basic_function!(Cos, "cos", 1, "\\cos{$0}", |args| { Ok(args[0].to_radians().cos()) });
+struct Cos {
+    angle_mode: AngleMode,
+}
+impl_angle_function!(Cos, "cos", "\\cos{$0}", |mode, args| { Ok(mode.to_radians(args[0]).cos()) });
 
-struct Tan;
-impl_basic_function!(Tan, "tan", 1, "\\tan{$0}", |args| { Ok(args[0].to_radians().tan()) });
+struct Tan {
+    angle_mode: AngleMode,
+}
+impl BasicFunction<LatexFormatter> for Tan {
+    const NAME: &'static str = "tan";
+    const ARG_COUNT: usize = 1;
+    const FMT: &'static str = "\\tan{$0}";
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        Ok(self.angle_mode.to_radians(args[0]).tan())
+    }
 
-struct Atan;
-impl_basic_function!(Atan, "atan", 1, "\\tan^{-1}{$0}", |args| { Ok(args[0].atan().to_degrees()) });
+    fn dimensionless_args(&self) -> bool {
+        true
+    }
+
+    // to_radians is not exact, so tan(90) is a huge number instead of infinity
+    fn undefined_at(&self, args: &[f64]) -> bool {
+        self.angle_mode == AngleMode::Degrees && (args[0] - 90.0) % 180.0 == 0.0
+    }
+}
+
+struct Atan {
+    angle_mode: AngleMode,
+}
+impl_angle_function!(Atan, "atan", "\\tan^{-1}{$0}", |mode, args| { Ok(mode.from_radians(args[0].atan())) });
 
-struct Asin;
-impl_basic_function!(Asin, "asin", 1, "\\sin^{-1}{$0}", |args| {
+struct Asin {
+    angle_mode: AngleMode,
+}
+impl_angle_function!(Asin, "asin", "\\sin^{-1}{$0}", |mode, args| {
     if args[0].abs() > 1.0 {
         Err("asin domain error".into())
     } else {
-        Ok(args[0].asin().to_degrees())
+        Ok(mode.from_radians(args[0].asin()))
     }
 });
 
-struct Acos;
-impl_basic_function!(Acos, "acos", 1, "\\cos^{-1}{$0}", |args| {
+struct Acos {
+    angle_mode: AngleMode,
+}
+impl_angle_function!(Acos, "acos", "\\cos^{-1}{$0}", |mode, args| {
     if args[0].abs() > 1.0 {
         Err("acos domain error".into())
     } else {
-        Ok(args[0].acos().to_degrees())
+        Ok(mode.from_radians(args[0].acos()))
     }
 });
 
@@ -131,13 +373,396 @@ impl_basic_function!(Modulo, "mod", 2, "$0\\bmod$1", |args| {
     }
 });
 
-struct Precision;
-impl_basic_function!(Precision, "p", 2, "$0", |args| {
-    Ok((args[0] / args[1]).round() * args[1])
+struct Choose;
+impl_basic_function!(Choose, "choose", 2, "\\binom{$0}{$1}", |args| {
+    let (n, k) = combinatoric_args("choose", args)?;
+    let k = k.min(n - k);
+    // exact at every step, since the partial results are binomial coefficients themselves
+    (0..k)
+        .try_fold(1u128, |acc, i| Some(acc.checked_mul(n - i)? / (i + 1)))
+        .map(|r| r as f64)
+        .ok_or_else(|| "choose result is too large".into())
 });
 
-struct Display;
-impl_basic_function!(Display, "disp", 2, "$1", |args| {
-   Ok(args[0])
+struct Permutations;
+impl_basic_function!(Permutations, "perm", 2, "{}^{$0}P_{$1}", |args| {
+    let (n, k) = combinatoric_args("perm", args)?;
+    (n - k + 1..=n)
+        .try_fold(1u128, |acc, i| acc.checked_mul(i))
+        .map(|r| r as f64)
+        .ok_or_else(|| "perm result is too large".into())
 });
 
+/// Rounds n and k to integers, and checks that they are finite and 0 <= k <= n.
+fn combinatoric_args(name: &str, args: &[f64]) -> Result<(u128, u128), String> {
+    let (n, k) = (args[0].round(), args[1].round());
+    // NaN would pass the comparisons below
+    if !n.is_finite() || !k.is_finite() {
+        Err(format!("{name} of non-finite number"))
+    } else if n < 0.0 || k < 0.0 {
+        Err(format!("{name} of negative number"))
+    } else if k > n {
+        Err(format!("{name} with k larger than n"))
+    } else {
+        Ok((n as u128, k as u128))
+    }
+}
+
+/// `p(x, step)` rounds to the nearest step, `p(x)` to the precision of the formatter.
+struct Precision {
+    rounding: Rounding,
+    precision: usize,
+}
+
+impl FormattableFunction<LatexFormatter> for Precision {
+    fn name(&self) -> &str {
+        "p"
+    }
+
+    fn supports_arg_count(&self, argc: usize) -> bool {
+        argc == 1 || argc == 2
+    }
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        if let [value, step] = args {
+            Ok(self.rounding.round(value / step) * step)
+        } else {
+            // dividing by the factor is more exact than multiplying by a step like 0.01
+            let factor = 10f64.powi(self.precision as i32);
+            Ok(self.rounding.round(args[0] * factor) / factor)
+        }
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        args: &[ResolvedFormattableExpression],
+    ) {
+        lib.write_expression(&args[0], out);
+    }
+}
+
+/// `show_as(value, shown)` evaluates to value with its unit, but is written as shown.  
+/// shown is written in the current value mode like any other argument.  
+/// Like every argument it is evaluated too, so it must be valid on its own, eg. `show_as(1, 1 / 0)` fails.
+struct ShowAs {
+    name: &'static str,
+}
+
+impl FormattableFunction<LatexFormatter> for ShowAs {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn supports_arg_count(&self, argc: usize) -> bool {
+        argc == 2
+    }
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        Ok(args[0])
+    }
+
+    fn result_unit(&self, arg_units: &[Unit]) -> Unit {
+        arg_units[0].clone()
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        args: &[ResolvedFormattableExpression],
+    ) {
+        lib.write_expression(&args[1], out);
+    }
+}
+
+struct PercentError;
+
+impl FormattableFunction<LatexFormatter> for PercentError {
+    fn name(&self) -> &str {
+        "perr"
+    }
+
+    fn supports_arg_count(&self, argc: usize) -> bool {
+        argc == 2
+    }
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        if args[1] == 0.0 {
+            Err("percent error with actual value 0".into())
+        } else {
+            Ok((args[0] - args[1]) / args[1] * 100.0)
+        }
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        args: &[ResolvedFormattableExpression],
+    ) {
+        let (measured, actual) = (grouped(&args[0]), grouped(&args[1]));
+        lib.fmt_expression("\\dfrac{$0 - $1}{$2}\\cdot 100\\%", &[&measured, &actual, &args[1]], out);
+    }
+}
+
+/// Relative to the mean of the absolute values, so the order of arguments does not matter.
+struct RelativeDifference;
+
+impl FormattableFunction<LatexFormatter> for RelativeDifference {
+    fn name(&self) -> &str {
+        "reldiff"
+    }
+
+    fn supports_arg_count(&self, argc: usize) -> bool {
+        argc == 2
+    }
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        let mean = (args[0].abs() + args[1].abs()) / 2.0;
+        if mean == 0.0 {
+            Err("relative difference of 0 and 0".into())
+        } else {
+            Ok((args[0] - args[1]).abs() / mean)
+        }
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        args: &[ResolvedFormattableExpression],
+    ) {
+        let (a, b) = (grouped(&args[0]), grouped(&args[1]));
+        lib.fmt_expression(
+            "\\dfrac{\\left|$0 - $1\\right|}{\\frac{\\left|$2\\right| + \\left|$3\\right|}{2}}",
+            &[&a, &b, &args[0], &args[1]],
+            out,
+        );
+    }
+}
+
+/// Column vector, only for display since it has no scalar value. Use it with the v and u flags.
+struct Vector;
+
+impl FormattableFunction<LatexFormatter> for Vector {
+    fn name(&self) -> &str {
+        "vec"
+    }
+
+    fn supports_arg_count(&self, argc: usize) -> bool {
+        argc > 0
+    }
+
+    fn eval(&self, _args: &[f64]) -> Result<f64, String> {
+        Err("vec can not be evaluated, only displayed with the v and u flags".into())
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        args: &[ResolvedFormattableExpression],
+    ) {
+        out.push_str("\\begin{pmatrix}");
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                out.push_str(" \\\\ ");
+            }
+            lib.write_expression(arg, out);
+        }
+        out.push_str("\\end{pmatrix}");
+    }
+}
+
+/// min and max of any number of arguments.
+struct Extremum {
+    max: bool,
+}
+
+impl FormattableFunction<LatexFormatter> for Extremum {
+    fn name(&self) -> &str {
+        if self.max { "max" } else { "min" }
+    }
+
+    fn supports_arg_count(&self, argc: usize) -> bool {
+        argc > 0
+    }
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        let extremum = if self.max { f64::max } else { f64::min };
+        Ok(args.iter().copied().reduce(extremum).expect("at least one argument"))
+    }
+
+    fn result_unit(&self, arg_units: &[Unit]) -> Unit {
+        shared_unit(arg_units)
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        args: &[ResolvedFormattableExpression],
+    ) {
+        write_arg_list(lib, out, if self.max { "\\max" } else { "\\min" }, args);
+    }
+}
+
+/// Arithmetic mean of any number of arguments.
+struct Mean;
+
+impl FormattableFunction<LatexFormatter> for Mean {
+    fn name(&self) -> &str {
+        "mean"
+    }
+
+    fn supports_arg_count(&self, argc: usize) -> bool {
+        argc > 0
+    }
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        Ok(args.iter().sum::<f64>() / args.len() as f64)
+    }
+
+    fn result_unit(&self, arg_units: &[Unit]) -> Unit {
+        shared_unit(arg_units)
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        args: &[ResolvedFormattableExpression],
+    ) {
+        write_arg_list(lib, out, "\\bar{x}", args);
+    }
+}
+
+/// Sample standard deviation, so at least 2 arguments are needed.
+struct StdDev;
+
+impl FormattableFunction<LatexFormatter> for StdDev {
+    fn name(&self) -> &str {
+        "stddev"
+    }
+
+    fn supports_arg_count(&self, argc: usize) -> bool {
+        argc > 1
+    }
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        let n = args.len() as f64;
+        let mean = args.iter().sum::<f64>() / n;
+        let squares: f64 = args.iter().map(|a| (a - mean).powi(2)).sum();
+        Ok((squares / (n - 1.)).sqrt())
+    }
+
+    fn result_unit(&self, arg_units: &[Unit]) -> Unit {
+        shared_unit(arg_units)
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        args: &[ResolvedFormattableExpression],
+    ) {
+        write_arg_list(lib, out, "s", args);
+    }
+}
+
+/// Weighted average of alternating values and weights, eg. `wavg(a, x, b, y)`.
+struct WeightedAverage {
+    multiplication_symbol: MultiplicationSymbol,
+}
+
+impl FormattableFunction<LatexFormatter> for WeightedAverage {
+    fn name(&self) -> &str {
+        "wavg"
+    }
+
+    fn supports_arg_count(&self, argc: usize) -> bool {
+        argc >= 2 && argc.is_multiple_of(2)
+    }
+
+    fn eval(&self, args: &[f64]) -> Result<f64, String> {
+        let weights: f64 = args.chunks(2).map(|pair| pair[1]).sum();
+        if weights == 0. {
+            return Err("the total weight of wavg is zero".into());
+        }
+        Ok(args.chunks(2).map(|pair| pair[0] * pair[1]).sum::<f64>() / weights)
+    }
+
+    /// The unit of the values, the unit of the weights cancels out.
+    fn result_unit(&self, arg_units: &[Unit]) -> Unit {
+        let values: Vec<_> = arg_units.iter().step_by(2).cloned().collect();
+        shared_unit(&values)
+    }
+
+    /// Written as the sum of the weighted values over the sum of the weights.
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        args: &[ResolvedFormattableExpression],
+    ) {
+        out.push_str("\\dfrac{");
+        for (i, pair) in args.chunks(2).enumerate() {
+            if i > 0 {
+                out.push_str(" + ");
+            }
+            let fmt = format!("$0 {} $1", self.multiplication_symbol.latex());
+            lib.fmt_expression(&fmt, &[&grouped(&pair[0]), &grouped(&pair[1])], out);
+        }
+        out.push_str("}{");
+        for (i, pair) in args.chunks(2).enumerate() {
+            if i > 0 {
+                out.push_str(" + ");
+            }
+            lib.write_expression(&pair[1], out);
+        }
+        out.push('}');
+    }
+}
+
+/// Parenthesises an argument that is an operator, so it stays together when written next to another operator.  
+/// Division is left alone, since the division line already groups it.
+fn grouped(arg: &ResolvedFormattableExpression) -> ResolvedFormattableExpression {
+    match arg {
+        FormattableExpression::Operator { operator, .. }
+        | FormattableExpression::OperatorChain { operator, .. }
+            if operator != "/" =>
+        {
+            FormattableExpression::Parenthesis(Box::new(arg.clone()))
+        }
+        _ => arg.clone(),
+    }
+}
+
+/// The unit of the first argument with a unit, since the arguments are expected to share it.
+fn shared_unit(arg_units: &[Unit]) -> Unit {
+    arg_units
+        .iter()
+        .find(|u| !matches!(u, Unit::None))
+        .cloned()
+        .unwrap_or(Unit::None)
+}
+
+/// Writes a function like `\max\left(a, b\right)`.
+fn write_arg_list(
+    lib: &FormattableLibraryProvider<LatexFormatter>,
+    out: &mut String,
+    name: &str,
+    args: &[ResolvedFormattableExpression],
+) {
+    out.push_str(name);
+    out.push_str("\\left(");
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        lib.write_expression(arg, out);
+    }
+    out.push_str("\\right)");
+}
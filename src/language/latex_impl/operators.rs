@@ -1,21 +1,33 @@
-use crate::language::format::{BasicOperator, FormattableOperator};
-use crate::language::latex_impl::LatexFormatter;
+use crate::language::format::{
+    BasicOperator, BuiltinEval, CustomOperator, FormattableExpression, FormattableLibraryProvider, FormattableOperator,
+    FormattableUnaryOperator, ResolvedFormattableExpression,
+};
+use crate::language::latex_impl::{LatexFormatter, MultiplicationSymbol};
 
-pub fn operators() -> Vec<Box<dyn FormattableOperator<LatexFormatter>>> {
+pub fn operators(formatter: &LatexFormatter) -> Vec<Box<dyn FormattableOperator<LatexFormatter>>> {
     vec![
         Box::new(Add),
         Box::new(Sub),
-        Box::new(Mul),
+        Box::new(Mul {
+            juxtapose: formatter.juxtapose_products,
+            symbol: formatter.multiplication_symbol,
+        }),
         Box::new(Div),
         Box::new(DivSymbol),
+        Box::new(DivInline),
         Box::new(Pow),
     ]
 }
 
+pub fn unary_operators() -> Vec<Box<dyn FormattableUnaryOperator<LatexFormatter>>> {
+    vec![Box::new(Negate)]
+}
+
 struct Add;
 impl BasicOperator<LatexFormatter> for Add {
     const PRECEDENCE: u32 = 0;
     const ASSOCIATIVE: bool = true;
+    const REQUIRES_EQUAL_UNITS: bool = true;
     const SHOULD_PARENTHESIZE_LEFT: bool = true;
     const SHOULD_PARENTHESIZE_RIGHT: bool = true;
     const SYMBOL: &'static str = "+";
@@ -31,6 +43,7 @@ struct Sub;
 impl BasicOperator<LatexFormatter> for Sub {
     const PRECEDENCE: u32 = 0;
     const ASSOCIATIVE: bool = false;
+    const REQUIRES_EQUAL_UNITS: bool = true;
     const SHOULD_PARENTHESIZE_LEFT: bool = true;
     const SHOULD_PARENTHESIZE_RIGHT: bool = true;
     const SYMBOL: &'static str = "-";
@@ -41,19 +54,60 @@ impl BasicOperator<LatexFormatter> for Sub {
     }
 }
 
-struct Mul;
+struct Mul {
+    /// Leave out the dot between variables, eg. ab and 2a
+    juxtapose: bool,
+    symbol: MultiplicationSymbol,
+}
 
-impl BasicOperator<LatexFormatter> for Mul {
-    const PRECEDENCE: u32 = 1;
-    const ASSOCIATIVE: bool = true;
-    const SHOULD_PARENTHESIZE_LEFT: bool = true;
-    const SHOULD_PARENTHESIZE_RIGHT: bool = true;
-    const SYMBOL: &'static str = "*";
-    const FMT: &'static str = "$0 \\cdot $1";
+impl FormattableOperator<LatexFormatter> for Mul {
+    fn precedence(&self) -> u32 {
+        1
+    }
+
+    fn is_associative(&self) -> bool {
+        true
+    }
+
+    fn should_parenthesize_left(&self) -> bool {
+        true
+    }
+
+    fn should_parenthesize_right(&self) -> bool {
+        true
+    }
+
+    fn symbol(&self) -> &str {
+        "*"
+    }
 
     fn eval(&self, left: f64, right: f64) -> Result<f64, String> {
         Ok(left * right)
     }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        left: &ResolvedFormattableExpression,
+        right: &ResolvedFormattableExpression,
+    ) {
+        let juxtapose = self.juxtapose
+            && matches!(
+                (left, right),
+                (
+                    FormattableExpression::Variable(_)
+                        | FormattableExpression::Number { unit: None, .. },
+                    FormattableExpression::Variable(_)
+                )
+            );
+        let fmt = if juxtapose {
+            "$0 $1".to_string()
+        } else {
+            format!("$0 {} $1", self.symbol.latex())
+        };
+        lib.fmt_expression(&fmt, &[left, right], out);
+    }
 }
 
 struct Div;
@@ -86,6 +140,22 @@ impl BasicOperator<LatexFormatter> for DivSymbol {
     }
 }
 
+/// A fraction that fits in running text, eg. a/b
+struct DivInline;
+
+impl BasicOperator<LatexFormatter> for DivInline {
+    const PRECEDENCE: u32 = 1;
+    const ASSOCIATIVE: bool = false;
+    const SHOULD_PARENTHESIZE_LEFT: bool = true;
+    const SHOULD_PARENTHESIZE_RIGHT: bool = true;
+    const SYMBOL: &'static str = "÷";
+    const FMT: &'static str = "$0/$1";
+
+    fn eval(&self, left: f64, right: f64) -> Result<f64, String> {
+        div(left, right)
+    }
+}
+
 fn div(left: f64, right: f64) -> Result<f64, String> {
     if right == 0. {
         Err("division by zero".to_string())
@@ -108,3 +178,68 @@ impl BasicOperator<LatexFormatter> for Pow {
         Ok(left.powf(right))
     }
 }
+
+struct Negate;
+
+impl FormattableUnaryOperator<LatexFormatter> for Negate {
+    fn symbol(&self) -> &str {
+        "-"
+    }
+
+    fn should_parenthesize(&self) -> bool {
+        true
+    }
+
+    fn eval(&self, value: f64) -> Result<f64, String> {
+        Ok(-value)
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        child: &ResolvedFormattableExpression,
+    ) {
+        lib.fmt_expression("-$0", &[child], out);
+    }
+}
+
+impl FormattableOperator<LatexFormatter> for CustomOperator {
+    fn precedence(&self) -> u32 {
+        self.precedence
+    }
+
+    fn is_associative(&self) -> bool {
+        self.associative
+    }
+
+    fn requires_equal_units(&self) -> bool {
+        matches!(self.eval, BuiltinEval::Add | BuiltinEval::Sub)
+    }
+
+    fn should_parenthesize_left(&self) -> bool {
+        true
+    }
+
+    fn should_parenthesize_right(&self) -> bool {
+        true
+    }
+
+    fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    fn eval(&self, left: f64, right: f64) -> Result<f64, String> {
+        self.eval.eval(left, right)
+    }
+
+    fn write(
+        &self,
+        lib: &FormattableLibraryProvider<LatexFormatter>,
+        out: &mut String,
+        left: &ResolvedFormattableExpression,
+        right: &ResolvedFormattableExpression,
+    ) {
+        lib.fmt_expression(&self.fmt, &[left, right], out);
+    }
+}
@@ -1,4 +1,6 @@
+use crate::language::expression::DEFAULT_MAX_DEPTH;
 use std::cmp::PartialEq;
+use std::collections::HashSet;
 use std::fmt::{Debug, Display, Formatter};
 
 #[derive(Debug, PartialEq, Eq)]
@@ -24,8 +26,20 @@ pub enum TokenTree {
         args: Vec<TokenTree>,
     },
     VariableRef(String),
+    /// `!name`, renders the expression the variable was assigned instead of its value.
+    ExpressionRef(String),
     NumberLiteral(String),
     Negate(Box<TokenTree>),
+    /// A prefix operator other than `-`, eg. `√x`. Binds tighter than any binary operator.
+    UnaryOperator {
+        operator: String,
+        child: Box<TokenTree>,
+    },
+    /// A string outside of unit position, only valid as the unit in `to(value, "unit")`.
+    String(String),
+    /// A parenthesized negation like `(-2)`.  
+    /// Other parentheses are dropped, but this one is kept so `(-2) ** 2` is not read as `-(2 ** 2)`.
+    Group(Box<TokenTree>),
 }
 
 impl Display for TokenTree {
@@ -54,20 +68,22 @@ impl Display for TokenTree {
                 format!("{} \"{}\"", child, name)
             }
             TokenTree::FunctionCall { name, args } => {
-                let mut r = name.clone() + "(";
-                for arg in args {
-                    r += &arg.to_string();
-                    r += ", ";
-                }
-                r.pop();
-                r.pop();
-                r + ")"
+                let args: Vec<_> = args.iter().map(ToString::to_string).collect();
+                format!("{}({})", name, args.join(", "))
             }
             TokenTree::VariableRef(name) => name.clone(),
+            TokenTree::ExpressionRef(name) => format!("!{}", name),
             TokenTree::NumberLiteral(n) => n.clone(),
             TokenTree::Negate(child) => {
                 format!("-{}", child)
             }
+            TokenTree::UnaryOperator { operator, child } => {
+                format!("{}{}", operator, child)
+            }
+            TokenTree::String(s) => format!("\"{}\"", s),
+            TokenTree::Group(child) => {
+                format!("({})", child)
+            }
         };
         write!(f, "{}", r)
     }
@@ -82,22 +98,85 @@ impl Debug for TokenizationError {
     }
 }
 
+/// Options for [tokenize_with].
+pub struct ParseOptions {
+    /// A number followed by a name or ( is multiplied, eg. `2x` becomes `2 * x` and `3(a)` becomes `3 * a`.
+    pub implicit_multiplication: bool,
+    /// Names that are always read as units, even with implicit multiplication.
+    pub units: HashSet<String>,
+    /// Expressions nested deeper than this are an error instead of overflowing the stack, [DEFAULT_MAX_DEPTH] by default.  
+    /// Each parenthesis, function call, prefix operator and unit counts as a level.
+    pub max_depth: usize,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            implicit_multiplication: false,
+            units: HashSet::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+/// The token tree of source as text, for debugging.  
+/// Operators are not grouped by precedence yet, see [Expression](crate::language::expression::Expression) for that.
+pub fn dump_token_tree(source: &str) -> Result<String, TokenizationError> {
+    tokenize(source).map(|tree| tree.to_string())
+}
+
 pub fn tokenize(source: &str) -> Result<TokenTree, TokenizationError> {
+    tokenize_with(source, &ParseOptions::default())
+}
+
+pub fn tokenize_with(source: &str, options: &ParseOptions) -> Result<TokenTree, TokenizationError> {
     if source.trim().is_empty() {
         return Err(TokenizationError("Empty expression".into()));
     }
-    let source_tokens = tokenize_source(source)?;
-    let (tree, i) = gen_tree(&source_tokens, 0)?;
+    let (source_tokens, positions) = tokenize_source(source)?;
+    let (tree, i) = gen_tree(&source_tokens, 0, options, 0)?;
     if i == source_tokens.len() - 1 {
         Ok(tree)
     } else {
-        Err(TokenizationError("unexpected ) or ,".to_string()))
+        let token = if source_tokens[i + 1] == SourceToken::Parentheses(true) { ")" } else { "," };
+        Err(TokenizationError(format!(
+            "unexpected '{token}' at position {}",
+            positions[i + 1] + 1
+        )))
+    }
+}
+/// Replaces the variable names in source for which rename returns a name, keeping the rest of the source as is.  
+/// Function names, strings and everything else are left alone.
+pub fn rename_variables(
+    source: &str,
+    rename: impl Fn(&str) -> Option<String>,
+) -> Result<String, TokenizationError> {
+    let (tokens, positions) = tokenize_source(source)?;
+    let chars: Vec<char> = source.chars().collect();
+    let mut res = String::new();
+    let mut end = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        let SourceToken::Name(name) = token else {
+            continue;
+        };
+        if tokens.get(i + 1) == Some(&SourceToken::Parentheses(false)) {
+            continue;
+        }
+        if let Some(renamed) = rename(name) {
+            res.extend(&chars[end..positions[i]]);
+            res.push_str(&renamed);
+            end = positions[i] + name.chars().count();
+        }
     }
+    res.extend(&chars[end..]);
+    Ok(res)
 }
+
 #[derive(Eq, PartialEq)]
 /// The most basic type of token, directly encodes source code.  
 enum SourceToken {
-    /// Any sequence of numeric chars.  
+    /// Any sequence of numeric chars, or a hexadecimal or binary literal like `0xFF` or `0b1010`.  
+    /// Digits may be separated by single `_`, eg. `1_000`.  
     Number(String),
     /// Any sequence of non-alphanumeric, non-whitespace chars.
     Operator(String),
@@ -109,7 +188,34 @@ enum SourceToken {
     Parentheses(bool),
 }
 
-fn gen_tree(expr: &[SourceToken], start: usize) -> Result<(TokenTree, usize), TokenizationError> {
+/// A number literal, possibly behind prefix operators like `-2`.
+fn is_number(tree: &TokenTree) -> bool {
+    match tree {
+        TokenTree::NumberLiteral(_) => true,
+        TokenTree::Negate(child) | TokenTree::UnaryOperator { child, .. } => is_number(child),
+        _ => false,
+    }
+}
+
+/// Fails if the levels of nesting are more than [ParseOptions::max_depth].
+fn check_depth(levels: usize, options: &ParseOptions) -> Result<(), TokenizationError> {
+    if levels > options.max_depth {
+        Err(TokenizationError(format!(
+            "Expression is nested deeper than {} levels",
+            options.max_depth
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+fn gen_tree(
+    expr: &[SourceToken],
+    start: usize,
+    options: &ParseOptions,
+    depth: usize,
+) -> Result<(TokenTree, usize), TokenizationError> {
+    check_depth(depth, options)?;
     let is_end = |i: usize| {
         i >= expr.len()
             || expr[i] == SourceToken::Parentheses(true)
@@ -118,30 +224,60 @@ fn gen_tree(expr: &[SourceToken], start: usize) -> Result<(TokenTree, usize), To
     let mut tokens = Vec::new();
     let mut operators = Vec::new();
     let mut expect_expr = true;
-    let mut neg = false;
+    // prefix operators before the current expression, innermost last
+    let mut prefixes: Vec<String> = Vec::new();
+    // units of the current expression, each wraps it in another level
+    let mut units = 0;
     let mut i = start;
     while !is_end(i) {
         if expect_expr {
-            let n = neg;
-            if expr[i] == SourceToken::Operator('-'.to_string()) {
-                if neg {
-                    return Err(TokenizationError(
-                        "Double negation is not allowed".to_string(),
-                    ));
+            if let SourceToken::Operator(o) = &expr[i] {
+                // the tokenizer joins operator chars, so -√ must be split here
+                let split = o.strip_prefix('-').filter(|rest| !rest.is_empty());
+                let ops = match split {
+                    Some(rest) => vec!["-", rest],
+                    None => vec![o.as_str()],
+                };
+                for o in ops {
+                    if o == "-" && prefixes.last().is_some_and(|p| p == "-") {
+                        return Err(TokenizationError(
+                            "Double negation is not allowed".to_string(),
+                        ));
+                    }
+                    prefixes.push(o.to_string());
                 }
-                neg = true;
+                check_depth(depth + prefixes.len(), options)?;
                 i += 1;
                 continue;
-            } else {
-                neg = false;
             }
-            let r = handle_expr(expr, &mut i)?;
-            if n {
-                tokens.push(TokenTree::Negate(Box::new(r)));
-            } else {
-                tokens.push(r);
+            let mut r = handle_expr(expr, &mut i, options, depth + prefixes.len() + 1)?;
+            units = 0;
+            for operator in prefixes.drain(..).rev() {
+                r = if operator == "-" {
+                    TokenTree::Negate(Box::new(r))
+                } else if let ("!", TokenTree::VariableRef(name)) = (operator.as_str(), &r) {
+                    TokenTree::ExpressionRef(name.clone())
+                } else {
+                    TokenTree::UnaryOperator {
+                        operator,
+                        child: Box::new(r),
+                    }
+                };
             }
+            tokens.push(r);
             expect_expr = false;
+        } else if options.implicit_multiplication
+            && tokens.last().is_some_and(is_number)
+            && match &expr[i] {
+                SourceToken::Name(name) => !options.units.contains(name),
+                SourceToken::Parentheses(closing) => !closing,
+                _ => false,
+            }
+        {
+            // handle the name or ( as the next expression
+            operators.push("*".to_string());
+            expect_expr = true;
+            continue;
         } else {
             match &expr[i] {
                 SourceToken::Operator(o) => {
@@ -156,6 +292,8 @@ fn gen_tree(expr: &[SourceToken], start: usize) -> Result<(TokenTree, usize), To
                         name: name.clone(),
                         child: Box::new(t),
                     });
+                    units += 1;
+                    check_depth(depth + units, options)?;
                 }
                 SourceToken::String(name) => {
                     let t = tokens
@@ -165,6 +303,8 @@ fn gen_tree(expr: &[SourceToken], start: usize) -> Result<(TokenTree, usize), To
                         name: name.clone(),
                         child: Box::new(t),
                     });
+                    units += 1;
+                    check_depth(depth + units, options)?;
                 }
                 SourceToken::Number(n) => {
                     return Err(TokenizationError(format!(
@@ -182,10 +322,12 @@ fn gen_tree(expr: &[SourceToken], start: usize) -> Result<(TokenTree, usize), To
 
         i += 1;
     }
-    i -= 1;
     if tokens.is_empty() {
-        Err(TokenizationError("Expected expression".to_string()))
-    } else if operators.len() != tokens.len() - 1 {
+        // i is still start, eg. for ) or an expression of only prefix operators
+        return Err(TokenizationError("Expected expression".to_string()));
+    }
+    i -= 1;
+    if operators.len() != tokens.len() - 1 {
         Err(TokenizationError(
             "Expected expression after operator".to_string(),
         ))
@@ -203,7 +345,12 @@ fn gen_tree(expr: &[SourceToken], start: usize) -> Result<(TokenTree, usize), To
 }
 
 /// to handle expressions for gen_tree
-fn handle_expr(expr: &[SourceToken], i: &mut usize) -> Result<TokenTree, TokenizationError> {
+fn handle_expr(
+    expr: &[SourceToken],
+    i: &mut usize,
+    options: &ParseOptions,
+    depth: usize,
+) -> Result<TokenTree, TokenizationError> {
     match &expr[*i] {
         SourceToken::Number(num) => Ok(TokenTree::NumberLiteral(num.clone())),
         SourceToken::Operator(op) => Err(TokenizationError(format!(
@@ -213,7 +360,7 @@ fn handle_expr(expr: &[SourceToken], i: &mut usize) -> Result<TokenTree, Tokeniz
         SourceToken::Name(name) => {
             if expr.get(*i + 1) == Some(&SourceToken::Operator("=".to_string())) {
                 // handle VarAssign
-                let (child, ii) = gen_tree(expr, *i + 2)?;
+                let (child, ii) = gen_tree(expr, *i + 2, options, depth)?;
                 *i = ii;
                 Ok(TokenTree::VariableAssign {
                     name: name.clone(),
@@ -227,11 +374,14 @@ fn handle_expr(expr: &[SourceToken], i: &mut usize) -> Result<TokenTree, Tokeniz
                     if expr.get(*i) == Some(&SourceToken::Parentheses(true)) {
                         break;
                     }
-                    let (arg, ii) = gen_tree(expr, *i)?;
+                    let (arg, ii) = gen_tree(expr, *i, options, depth)?;
                     args.push(arg);
                     *i = ii + 1;
                     if expr.get(*i) == Some(&SourceToken::Operator(','.to_string())) {
                         *i += 1;
+                        if expr.get(*i) == Some(&SourceToken::Parentheses(true)) {
+                            return Err(TokenizationError(format!("Expected argument after , in function '{name}'")));
+                        }
                     } else if expr.get(*i) != Some(&SourceToken::Parentheses(true)) {
                         return Err(TokenizationError(format!("Expected ) or , in function '{name}' after argument")));
                     }
@@ -245,18 +395,17 @@ fn handle_expr(expr: &[SourceToken], i: &mut usize) -> Result<TokenTree, Tokeniz
                 Ok(TokenTree::VariableRef(name.clone()))
             }
         }
-        SourceToken::String(s) => Err(TokenizationError(format!(
-            "Expected token, got string \"{}\"",
-            s
-        ))),
+        SourceToken::String(s) => Ok(TokenTree::String(s.clone())),
         SourceToken::Parentheses(v) => {
             // handle (
             // if closing then it will be caught by is_end
             assert!(!v);
-            let (token, ii) = gen_tree(expr, *i + 1)?;
+            let (token, ii) = gen_tree(expr, *i + 1, options, depth)?;
             *i = ii + 1;
             if expr.get(*i) != Some(&SourceToken::Parentheses(true)) {
                 Err(TokenizationError("Expected ) after (".to_string()))
+            } else if let TokenTree::Negate(_) = token {
+                Ok(TokenTree::Group(Box::new(token)))
             } else {
                 Ok(token)
             }
@@ -264,35 +413,49 @@ fn handle_expr(expr: &[SourceToken], i: &mut usize) -> Result<TokenTree, Tokeniz
     }
 }
 
-fn tokenize_source(expr: &str) -> Result<Vec<SourceToken>, TokenizationError> {
+/// Also returns the char index each token starts at, for error messages.
+/// Werther a `_` after num starts a name instead of being a digit separator, eg. the unit in `1_m`.
+fn is_unit_start(num: &str, next: Option<&char>) -> bool {
+    let hex = num.starts_with("0x");
+    next.is_some_and(|n| n.is_alphabetic() && !(hex && n.is_ascii_hexdigit()))
+}
+
+fn tokenize_source(expr: &str) -> Result<(Vec<SourceToken>, Vec<usize>), TokenizationError> {
     let mut tokens: Vec<SourceToken> = Vec::new();
+    let mut positions = Vec::new();
     let mut current = None;
-    // Takes the token as argument, to not perm borrow
-    let mut push_token = |token: &mut Option<SourceToken>| {
+    // where current starts
+    let mut start = 0;
+    // Takes the token as argument, to not perm borrow, end is the index after the token
+    let mut push_token = |token: &mut Option<SourceToken>, start: usize, end: usize| {
         if let Some(token) = token.take() {
             match &token {
                 SourceToken::Operator(op) if op.ends_with("-") && op.len() > 1 => {
                     tokens.push(SourceToken::Operator(op[..op.len() - 1].to_string()));
                     tokens.push(SourceToken::Operator(op[op.len() - 1..op.len()].to_string()));
+                    positions.extend([start, end - 1]);
                 },
                 _ => {
                     tokens.push(token);
+                    positions.push(start);
                 }
             }
         }
     };
-    for c in expr.chars() {
+    let chars: Vec<_> = expr.chars().collect();
+    for (i, &c) in chars.iter().enumerate() {
         // currently in string, overrides all
         if let Some(SourceToken::String(s)) = &mut current {
             match c {
-                '"' => push_token(&mut current),
+                '"' => push_token(&mut current, start, i + 1),
                 _ => s.push(c),
             }
         } else if c.is_whitespace() {
-            push_token(&mut current);
+            push_token(&mut current, start, i);
         } else if c == '"' {
-            push_token(&mut current);
+            push_token(&mut current, start, i);
             current = Some(SourceToken::String(String::new()));
+            start = i;
         } else if c.is_numeric() || c == '.' {
             if let Some(SourceToken::Name(name)) = &mut current {
                 name.push(c);
@@ -301,29 +464,52 @@ fn tokenize_source(expr: &str) -> Result<Vec<SourceToken>, TokenizationError> {
             if let Some(SourceToken::Number(num)) = &mut current {
                 num.push(c);
             } else {
-                push_token(&mut current);
+                push_token(&mut current, start, i);
                 current = Some(SourceToken::Number(c.to_string()));
+                start = i;
+            }
+        } else if let (Some(SourceToken::Number(num)), '_') = (&mut current, c)
+            && !is_unit_start(num, chars.get(i + 1))
+        {
+            // digit separator, eg. 1_000_000
+            let hex = num.starts_with("0x");
+            let next = chars.get(i + 1);
+            if !next.is_some_and(|n| n.is_ascii_digit() || hex && n.is_ascii_hexdigit()) {
+                return Err(TokenizationError(format!(
+                    "Invalid digit separator at position {}",
+                    i + 1
+                )));
             }
+            num.push(c);
+        } else if let Some(SourceToken::Number(num)) = &mut current
+            && (num == "0" && (c == 'x' || c == 'b')
+                || num.starts_with("0x") && c.is_ascii_hexdigit())
+        {
+            // hexadecimal and binary literals, eg. 0xFF and 0b1010
+            num.push(c);
         } else if c.is_alphabetic() || c == '_' {
             if let Some(SourceToken::Name(name)) = &mut current {
                 name.push(c);
             } else {
-                push_token(&mut current);
+                push_token(&mut current, start, i);
                 current = Some(SourceToken::Name(c.to_string()));
+                start = i;
             }
         } else if c == '(' || c == ')' {
-            push_token(&mut current);
+            push_token(&mut current, start, i);
             current = Some(SourceToken::Parentheses(c == ')'));
+            start = i;
         } else if let Some(SourceToken::Operator(op)) = &mut current {
             op.push(c);
         } else {
-            push_token(&mut current);
+            push_token(&mut current, start, i);
             current = Some(SourceToken::Operator(c.to_string()));
+            start = i;
         }
     }
     if let Some(SourceToken::String(_)) = &current {
         return Err(TokenizationError("Expected end of string".to_string()));
     }
-    push_token(&mut current);
-    Ok(tokens)
+    push_token(&mut current, start, chars.len());
+    Ok((tokens, positions))
 }
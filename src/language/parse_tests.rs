@@ -52,6 +52,41 @@ fn negate() {
         Ok(Negate(Box::new(VariableRef("variable".into()))))
     );
     assert!(tokenize("--5").is_err());
+    assert_eq!(
+        tokenize("(-2)"),
+        Ok(Group(Box::new(Negate(Box::new(NumberLiteral("2".into()))))))
+    );
+}
+
+#[test]
+fn unary_operators() {
+    assert_eq!(
+        tokenize("√x"),
+        Ok(UnaryOperator {
+            operator: "√".into(),
+            child: Box::new(VariableRef("x".into()))
+        })
+    );
+    assert_eq!(
+        tokenize("-√(a + b)"),
+        Ok(Negate(Box::new(UnaryOperator {
+            operator: "√".into(),
+            child: Box::new(tokenize("a + b").unwrap())
+        })))
+    );
+    assert_eq!(
+        tokenize("2 * √-x"),
+        Ok(OperatorSequence {
+            operators: vec!["*".into()],
+            children: vec![
+                NumberLiteral("2".into()),
+                UnaryOperator {
+                    operator: "√".into(),
+                    child: Box::new(Negate(Box::new(VariableRef("x".into()))))
+                },
+            ]
+        })
+    );
 }
 
 #[test]
@@ -146,6 +181,63 @@ fn functions() {
     );
 }
 
+#[test]
+fn strings() {
+    assert_eq!(
+        tokenize("to(1 Hour, \"s\")"),
+        Ok(FunctionCall {
+            name: "to".into(),
+            args: vec![
+                DefinedUnit {
+                    name: "Hour".into(),
+                    child: Box::new(NumberLiteral("1".into()))
+                },
+                String("s".into()),
+            ]
+        })
+    );
+}
+
+#[test]
+fn implicit_multiplication() {
+    let options = ParseOptions {
+        implicit_multiplication: true,
+        units: ["Meter".to_string()].into(),
+        ..Default::default()
+    };
+    let mul = |a, b| {
+        Ok(OperatorSequence {
+            operators: vec!["*".into()],
+            children: vec![a, b],
+        })
+    };
+    assert_eq!(
+        tokenize_with("2x", &options),
+        mul(NumberLiteral("2".into()), VariableRef("x".into()))
+    );
+    assert_eq!(
+        tokenize_with("3(a)", &options),
+        mul(NumberLiteral("3".into()), VariableRef("a".into()))
+    );
+    assert_eq!(tokenize_with("3(a + 1)", &options), tokenize("3 * (a + 1)"));
+    assert_eq!(tokenize_with("2sqrt(a)", &options), tokenize("2 * sqrt(a)"));
+    // also after prefix operators, -(2 * x) once negation is grouped by precedence
+    assert_eq!(tokenize_with("-2x", &options), tokenize("-2 * x"));
+    assert_eq!(tokenize_with("-2x^2", &options), tokenize("-2 * x^2"));
+    assert_eq!(tokenize_with("√4(a)", &options), tokenize("√4 * a"));
+    // known units are still units
+    assert_eq!(tokenize_with("2 Meter", &options), tokenize("2 Meter"));
+    // off by default
+    assert_eq!(
+        tokenize("2x"),
+        Ok(DefinedUnit {
+            name: "x".into(),
+            child: Box::new(NumberLiteral("2".into()))
+        })
+    );
+    assert!(tokenize("3(a)").is_err());
+}
+
 #[test]
 fn advanced() {
     assert_eq!(
@@ -205,3 +297,194 @@ fn advanced() {
         })
     );
 }
+
+#[test]
+fn dump() {
+    assert_eq!(dump_token_tree("1 + 2 * -3"), Ok("(1 + 2 * -3)".into()));
+    assert!(dump_token_tree("1 +").is_err());
+}
+
+#[test]
+fn zero_arg_functions() {
+    let call = |name: &str| FunctionCall {
+        name: name.into(),
+        args: Vec::new(),
+    };
+    assert_eq!(tokenize("pi()"), Ok(call("pi")));
+    assert_eq!(tokenize("my_const( )"), Ok(call("my_const")));
+    assert_eq!(dump_token_tree("e() * pi()"), Ok("(e() * pi())".into()));
+    // without parentheses it is a variable
+    assert_eq!(tokenize("pi"), Ok(VariableRef("pi".into())));
+    assert!(tokenize("pi(,)").is_err());
+}
+
+#[test]
+fn nested_arguments() {
+    let args = |source: &str| match tokenize(source) {
+        Ok(FunctionCall { args, .. }) => args.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        other => panic!("expected function call, got {other:?}"),
+    };
+    assert_eq!(args("f((a + b), c)"), ["(a + b)", "c"]);
+    assert_eq!(args("f(g(x), y)"), ["g(x)", "y"]);
+    assert_eq!(args("f(g(h(x), (y)), ((z)))"), ["g(h(x), y)", "z"]);
+    assert_eq!(args("f(a, (b * (c + d)) / 2, g())"), ["a", "((b * (c + d)) / 2)", "g()"]);
+    assert!(tokenize("f(a,)").is_err());
+    assert!(tokenize("f(g(a), (b)").is_err());
+}
+
+#[test]
+fn stray_closing() {
+    let err = |source| format!("{:?}", tokenize(source).unwrap_err());
+    assert_eq!(err("1 + 2)"), "tokenizer error: unexpected ')' at position 6");
+    assert_eq!(err("(a*-b)), 2"), "tokenizer error: unexpected ')' at position 7");
+    assert_eq!(err("2 \"µs\", 1"), "tokenizer error: unexpected ',' at position 7");
+}
+
+#[test]
+fn hex_and_binary() {
+    assert_eq!(tokenize("0xFF"), Ok(NumberLiteral("0xFF".into())));
+    assert_eq!(tokenize("0b1010"), Ok(NumberLiteral("0b1010".into())));
+    // a space makes x a unit
+    assert_eq!(
+        tokenize("0 x"),
+        Ok(DefinedUnit {
+            name: "x".into(),
+            child: Box::new(NumberLiteral("0".into()))
+        })
+    );
+    assert_eq!(
+        tokenize("0x1f Meter"),
+        Ok(DefinedUnit {
+            name: "Meter".into(),
+            child: Box::new(NumberLiteral("0x1f".into()))
+        })
+    );
+    assert_eq!(
+        tokenize("10x"),
+        Ok(DefinedUnit {
+            name: "x".into(),
+            child: Box::new(NumberLiteral("10".into()))
+        })
+    );
+}
+
+#[test]
+fn digit_separators() {
+    assert_eq!(tokenize("1_000_000"), Ok(NumberLiteral("1_000_000".into())));
+    assert_eq!(tokenize("0xFF_FF"), Ok(NumberLiteral("0xFF_FF".into())));
+    // names can still contain _
+    assert_eq!(tokenize("_1"), Ok(VariableRef("_1".into())));
+    for invalid in ["1__0", "1_", "1_.5"] {
+        assert!(tokenize(invalid).is_err(), "{invalid}");
+    }
+    // a name right after the number ends it, so _m can be a unit
+    assert!(tokenize("1_m").is_ok());
+    assert_eq!(tokenize("1_m"), tokenize("1 _m"));
+    assert_eq!(tokenize("0xF_a"), Ok(NumberLiteral("0xF_a".into())));
+}
+
+#[test]
+fn expression_ref() {
+    assert_eq!(tokenize("!area"), Ok(ExpressionRef("area".into())));
+    assert_eq!(
+        tokenize("-!area"),
+        Ok(Negate(Box::new(ExpressionRef("area".into()))))
+    );
+    // other operands keep ! as a unary operator
+    assert_eq!(
+        tokenize("!3"),
+        Ok(UnaryOperator {
+            operator: "!".into(),
+            child: Box::new(NumberLiteral("3".into()))
+        })
+    );
+}
+
+fn assert_error(source: &str, message: &str) {
+    assert_eq!(format!("{:?}", tokenize(source).unwrap_err()), format!("tokenizer error: {message}"));
+}
+
+#[test]
+fn empty_contexts() {
+    // gen_tree starts at a closing token, so its loop never runs
+    assert_error(")", "Expected expression");
+    assert_error("()", "Expected expression");
+    assert_error("2 * ()", "Expected expression");
+    assert_error("max(1, )", "Expected argument after , in function 'max'");
+    // or at the end of the input
+    assert_error("a =", "Expected expression");
+    assert_error("(-", "Expected expression");
+}
+
+// inputs found by tokenize_never_panics
+#[test]
+fn malformed_regressions() {
+    for source in [")", "-)", "√", "(1,)", "f(,", "a = )"] {
+        assert!(tokenize(source).is_err(), "{source}");
+    }
+}
+
+proptest::proptest! {
+    #[test]
+    fn tokenize_never_panics(source in "[0-9a-z_.,()+\\-*/^=!√\" ]{0,24}") {
+        let _ = tokenize(&source);
+    }
+
+    #[test]
+    fn tokenize_never_panics_on_any_text(source in "\\PC{0,24}") {
+        let _ = tokenize(&source);
+    }
+}
+
+
+#[test]
+fn renamed_variables() {
+    let rename = |name: &str| ["g", "h"].contains(&name).then(|| format!("c.{name}"));
+    assert_eq!(
+        rename_variables("h = g*2 + max(g, gh) \"g\" Meter", rename),
+        Ok("c.h = c.g*2 + max(c.g, gh) \"g\" Meter".to_string())
+    );
+    // names are never function names
+    assert_eq!(rename_variables("g(2) + g", rename), Ok("g(2) + c.g".to_string()));
+    assert_eq!(tokenize("c.h = c.g").unwrap().to_string(), "c.h = c.g");
+}
+
+#[test]
+fn names_with_digits() {
+    for name in ["x1", "v_2", "alpha2", "a1b2"] {
+        assert_eq!(tokenize(name), Ok(VariableRef(name.into())));
+    }
+    assert_eq!(
+        tokenize("x1 = 2"),
+        Ok(VariableAssign {
+            name: "x1".into(),
+            child: Box::new(NumberLiteral("2".into()))
+        })
+    );
+    // names can not start with a digit, so a number followed by a name is still a unit
+    assert_eq!(
+        tokenize("2m2"),
+        Ok(DefinedUnit {
+            name: "m2".into(),
+            child: Box::new(NumberLiteral("2".into()))
+        })
+    );
+    assert_eq!(tokenize("0x1F"), Ok(NumberLiteral("0x1F".into())));
+}
+
+#[test]
+fn max_depth() {
+    let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+    assert!(tokenize(&nested(100)).is_ok());
+    assert_error(&nested(3000), "Expression is nested deeper than 128 levels");
+    assert_error(&format!("{}1", "- √ ".repeat(3000)), "Expression is nested deeper than 128 levels");
+    // operator sequences are flat, so they can be long
+    assert!(tokenize(&format!("1{}", " + 1".repeat(3000))).is_ok());
+
+    let options = ParseOptions {
+        max_depth: 1,
+        ..Default::default()
+    };
+    assert!(tokenize_with("(1) + 2", &options).is_ok());
+    assert!(tokenize_with("((1)) + 2", &options).is_err());
+}
@@ -1,62 +1,155 @@
 mod unit_lib;
+#[cfg(test)]
+mod unit_lib_tests;
+#[cfg(test)]
+mod lib_tests;
+mod logger;
+#[cfg(test)]
+mod logger_tests;
 
-mod language;
+pub mod language;
 mod markdown;
+#[cfg(test)]
+mod markdown_tests;
 
-use crate::language::expression::EvaluationContext;
-use crate::language::format::FormattableLibraryProvider;
+use crate::language::expression::{EvaluationContext, Expression};
+use crate::language::format::{parse_custom_operators, CustomOperator, FormattableLibraryProvider};
 use crate::language::latex_impl::LatexFormatter;
+use crate::language::parse;
+use crate::logger::Logger;
+use crate::markdown::BlockCache;
 use crate::unit_lib::{CLIUnitLib, UnitCollection};
-use std::path::Path;
-use std::process::Command;
-use std::time::Duration;
-use std::{fs, io, thread};
+pub use crate::markdown::{parse_markdown, CalculationWrapper, MarkdownOptions};
+pub use crate::unit_lib::{MissingUnits, NoopUnitLibrary};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{Duration, SystemTime};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::{env, fs, io, thread};
 
 const UNIT_PATH: &str = "units.txt";
+const OPERATOR_PATH: &str = "operators.txt";
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum CompileMode {
+    /// Prompts the user for every unknown unit, and saves the names.
     Resolving,
+    /// Resolves without prompting, unknown units are named after their structure (eg. m/s).
+    Defaults,
+    /// Never prompts and never saves, unknown units are displayed by their structure.
     NonResolving,
+    /// Like [NonResolving](Self::NonResolving), but recompiles whenever the input changes.
     Live,
 }
 
-pub fn run(compile_mode: CompileMode, input: &Path, output: &Path) -> io::Result<()> {
-    let unit_collection = load_units();
-    let md_output = output.with_extension("md");
-    let mut unit_lib = CLIUnitLib::new(unit_collection, compile_mode == CompileMode::Resolving);
-    let lib = FormattableLibraryProvider::new(LatexFormatter { precision: 5 });
+/// What to do when the unit file exists but can not be parsed.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum BadUnitFile {
+    /// Moves the file to a `.bak` file next to it and continues with no units, so it is not overwritten.
+    #[default]
+    Backup,
+    /// Fails with [InvalidData](io::ErrorKind::InvalidData).
+    Error,
+}
+
+/// Options for [run].
+#[derive(Debug, Default)]
+pub struct RunOptions {
+    /// Wrap every calculation for styling, in a div when every output is HTML and a span otherwise.
+    pub wrap_calculations: bool,
+    /// Write the time of each phase to stderr.
+    pub verbose: bool,
+    /// Write the markdown to the temp directory and remove it afterwards,
+    /// instead of next to the output where it could overwrite an existing file.
+    pub temp_intermediate: bool,
+    pub bad_units: BadUnitFile,
+    /// Variables are loaded from this file before compiling if it exists, and the variables of the document are saved to it after.
+    pub context: Option<PathBuf>,
+}
+
+/// The document is rendered once to markdown, which is then copied or converted with pandoc to each output.  
+/// Fails with [InvalidInput](io::ErrorKind::InvalidInput) when there are no outputs.
+pub fn run(compile_mode: CompileMode, input: &Path, outputs: &[PathBuf], options: &RunOptions) -> io::Result<()> {
+    if outputs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "No output files given"));
+    }
+    let mut logger = Logger::stderr(options.verbose);
+    let unit_collection = load_units(options.bad_units, &mut logger)?;
+    let (md_output, steps) = output_steps(outputs, options.temp_intermediate);
+    let loaded_ctx = match &options.context {
+        Some(path) if path.exists() => load_context(path)?,
+        _ => EvaluationContext::new(),
+    };
+    let mut unit_lib = unit_lib_for(&compile_mode, unit_collection);
+    let lib = library_provider(&mut logger);
+    let is_html = outputs
+        .iter()
+        .all(|o| o.extension().is_some_and(|e| e == "html" || e == "htm"));
+    let md_options = MarkdownOptions {
+        wrapper: match (options.wrap_calculations, is_html) {
+            (false, _) => None,
+            (true, true) => Some(CalculationWrapper::HtmlDiv),
+            (true, false) => Some(CalculationWrapper::MarkdownSpan),
+        },
+    };
+    // in live mode only blocks that changed are rendered again
+    let mut block_cache = BlockCache::default();
     let mut prev_modified = None;
+    // included files of the last compile, also watched in live mode
+    let mut included = Vec::new();
     loop {
         loop {
-            let new = fs::metadata(input)?.modified()?;
+            let new = last_modified(input, &included)?;
             if prev_modified.is_none() || new > prev_modified.unwrap() {
                 prev_modified = Some(new);
                 break;
             }
             thread::sleep(Duration::from_millis(500));
         }
-        let mut eval_ctx = EvaluationContext::new();
-        let input = fs::read_to_string(input)?;
-        let res = markdown::parse_markdown(&input, &mut eval_ctx, &mut unit_lib, &lib);
-        fs::write(&md_output, res)?;
-        match Command::new("pandoc")
-            .arg(&md_output)
-            .arg("-o")
-            .arg(output)
-            .args(["--katex", "-s"])
-            .status()
-        {
-            Ok(s) => {
-                if !s.success() {
-                    println!("pandoc failed with code {}", s.code().unwrap());
-                    return Ok(());
-                }
-            }
-            Err(e) => {
-                println!("pandoc executor exited with error: {}", e);
-                return Ok(());
-            }
+        let mut eval_ctx = loaded_ctx.clone();
+        for (from, to, factor) in unit_lib.conversions() {
+            eval_ctx.add_conversion(from, to, factor);
+        }
+        logger.log(&format!("compiling {}", input.display()));
+        let dir = input.parent().unwrap_or(Path::new(""));
+        let (input, files) = markdown::expand_includes_with_files(&fs::read_to_string(input)?, dir);
+        included = files;
+        if compile_mode == CompileMode::Live {
+            let (res, _) = markdown::parse_markdown_incremental(
+                &input,
+                &mut eval_ctx,
+                &mut unit_lib,
+                &lib,
+                &md_options,
+                &mut block_cache,
+                &mut logger,
+            );
+            logger.log(&format!("rendered {} changed blocks", block_cache.rendered_blocks().len()));
+            fs::write(&md_output, res)?;
+        } else {
+            // written as it is rendered, so large documents are not held in memory
+            let mut out = BufWriter::new(File::create(&md_output)?);
+            markdown::parse_markdown_streaming(
+                &input,
+                &mut eval_ctx,
+                &mut unit_lib,
+                &lib,
+                &md_options,
+                &mut out,
+                &mut logger,
+            )?;
+            out.flush()?;
+        }
+        if let Some(path) = &options.context {
+            fs::write(path, eval_ctx.to_string())?;
+        }
+        let made = make_outputs(&mut logger, &md_output, &steps);
+        if options.temp_intermediate {
+            fs::remove_file(&md_output)?;
+        }
+        if !made? {
+            return Ok(());
         }
         if compile_mode != CompileMode::Live {
             break;
@@ -64,33 +157,235 @@ pub fn run(compile_mode: CompileMode, input: &Path, output: &Path) -> io::Result
     }
 
     // only resolving should modify units
-    if compile_mode == CompileMode::Resolving {
+    if let CompileMode::Resolving | CompileMode::Defaults = compile_mode {
         save_units(&unit_lib.finish())?;
     }
     Ok(())
 }
 
-pub fn configure() -> io::Result<()> {
-    let units = load_units();
+/// The latest modification time of the input and the files it includes, included files that don't exist are skipped.
+fn last_modified(input: &Path, included: &[PathBuf]) -> io::Result<SystemTime> {
+    let mut latest = fs::metadata(input)?.modified()?;
+    for path in included {
+        if let Ok(modified) = fs::metadata(path).and_then(|m| m.modified()) {
+            latest = latest.max(modified);
+        }
+    }
+    Ok(latest)
+}
+
+fn load_context(path: &Path) -> io::Result<EvaluationContext> {
+    fs::read_to_string(path)?.parse().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Error parsing variables in {}: {}", path.display(), e),
+        )
+    })
+}
+
+/// How an output is made from the intermediate markdown.
+#[derive(Debug, PartialEq)]
+enum OutputStep {
+    Copy(PathBuf),
+    Pandoc(PathBuf),
+}
+
+/// The path of the intermediate markdown, and the steps making the outputs from it.  
+/// The first markdown output is used as the intermediate, so pandoc is only needed for other formats.  
+/// With temp_intermediate it is a file in the temp directory, and every output is made from it.
+/// `outputs` must not be empty.
+fn output_steps(outputs: &[PathBuf], temp_intermediate: bool) -> (PathBuf, Vec<OutputStep>) {
+    let is_markdown = |o: &&PathBuf| o.extension().is_some_and(|e| e == "md");
+    let md_output = if temp_intermediate {
+        temp_intermediate_path()
+    } else {
+        outputs
+            .iter()
+            .find(is_markdown)
+            .unwrap_or(&outputs[0])
+            .with_extension("md")
+    };
+    let steps = outputs
+        .iter()
+        .filter(|o| **o != md_output)
+        .map(|o| {
+            if is_markdown(&o) {
+                OutputStep::Copy(o.clone())
+            } else {
+                OutputStep::Pandoc(o.clone())
+            }
+        })
+        .collect();
+    (md_output, steps)
+}
+
+/// Unique per process, so simultaneous compiles do not share the file.
+fn temp_intermediate_path() -> PathBuf {
+    env::temp_dir().join(format!("markmath-{}.md", process::id()))
+}
+
+/// Makes the outputs from the intermediate markdown, false if pandoc failed.
+fn make_outputs(logger: &mut Logger<io::Stderr>, md_output: &Path, steps: &[OutputStep]) -> io::Result<bool> {
+    for step in steps {
+        match step {
+            OutputStep::Copy(output) => {
+                fs::copy(md_output, output)?;
+            }
+            OutputStep::Pandoc(output) => {
+                match logger.phase("pandoc", || pandoc_command(md_output, output).status()) {
+                    Ok(s) => {
+                        if !s.success() {
+                            logger.warn(&format!("pandoc failed with code {}", s.code().unwrap()));
+                            return Ok(false);
+                        }
+                    }
+                    Err(e) => {
+                        logger.warn(&format!("pandoc executor exited with error: {}", e));
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
+fn pandoc_command(md_output: &Path, output: &Path) -> Command {
+    let mut command = Command::new("pandoc");
+    command
+        .arg(md_output)
+        .arg("-o")
+        .arg(output)
+        .args(["--katex", "-s"]);
+    command
+}
+
+/// The library used for compiling, with the custom operators.
+fn library_provider(logger: &mut Logger<impl Write>) -> FormattableLibraryProvider<LatexFormatter> {
+    let mut lib = FormattableLibraryProvider::new(LatexFormatter { precision: 5, ..Default::default() });
+    for operator in load_operators(logger) {
+        if let Err(e) = lib.add_operator(Box::new(operator)) {
+            logger.warn(&format!("Error adding custom operator: {}", e));
+        }
+    }
+    lib
+}
+
+/// Lists the units in the input that the unit collection can not name, without prompting or saving.  
+/// Meant for CI, to fail when a document needs units resolved.
+pub fn check_units(input: &Path, bad_units: BadUnitFile) -> io::Result<MissingUnits> {
+    let dir = input.parent().unwrap_or(Path::new(""));
+    let source = markdown::expand_includes(&fs::read_to_string(input)?, dir);
+    let mut logger = Logger::stderr(false);
+    let units = load_units(bad_units, &mut logger)?;
+    Ok(missing_units(&source, units, &library_provider(&mut logger)))
+}
+
+fn missing_units(
+    source: &str,
+    collection: UnitCollection,
+    lib: &FormattableLibraryProvider<LatexFormatter>,
+) -> MissingUnits {
+    let mut unit_lib = CLIUnitLib::new_checking(collection);
+    let mut eval_ctx = EvaluationContext::new();
+    for (from, to, factor) in unit_lib.conversions() {
+        eval_ctx.add_conversion(from, to, factor);
+    }
+    markdown::parse_markdown_streaming(
+        source,
+        &mut eval_ctx,
+        &mut unit_lib,
+        lib,
+        &MarkdownOptions::default(),
+        &mut io::sink(),
+        &mut Logger::new(io::sink(), false),
+    )
+    .expect("writing to a sink can not fail");
+    unit_lib.missing_units().clone()
+}
+
+fn unit_lib_for(compile_mode: &CompileMode, collection: UnitCollection) -> CLIUnitLib {
+    match compile_mode {
+        CompileMode::Resolving => CLIUnitLib::new(collection, true),
+        CompileMode::Defaults => CLIUnitLib::new_with_defaults(collection),
+        CompileMode::NonResolving | CompileMode::Live => CLIUnitLib::new(collection, false),
+    }
+}
+
+/// The token tree and expression of source, for debugging how an expression is parsed.
+pub fn dump_ast(source: &str) -> Result<String, String> {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let tree = parse::tokenize(source).map_err(|e| format!("{e:?}"))?;
+    let tokens = tree.to_string();
+    let exp = Expression::new(tree, &lib).map_err(|e| format!("{e:?}"))?;
+    Ok(format!("tokens: {tokens}\nexpression: {exp}"))
+}
+
+pub fn configure(bad_units: BadUnitFile) -> io::Result<()> {
+    let units = load_units(bad_units, &mut Logger::stderr(false))?;
     let mut lib = CLIUnitLib::new(units, true);
     lib.configure();
     save_units(&lib.finish())?;
     Ok(())
 }
 
-fn load_units() -> UnitCollection {
-    match fs::read_to_string(UNIT_PATH) {
-        Ok(s) => s.parse().unwrap_or_else(|e| {
-            println!("Error parsing units: {}\n\nContinuing with new units", e);
-            UnitCollection::new()
-        }),
+fn load_units(bad_units: BadUnitFile, logger: &mut Logger<impl Write>) -> io::Result<UnitCollection> {
+    load_units_from(Path::new(UNIT_PATH), bad_units, logger)
+}
+
+fn load_units_from(path: &Path, bad_units: BadUnitFile, logger: &mut Logger<impl Write>) -> io::Result<UnitCollection> {
+    let source = match fs::read_to_string(path) {
+        Ok(s) => s,
         Err(_) => {
-            println!("could not read unit collection, creating empty");
-            UnitCollection::new()
+            logger.warn("could not read unit collection, creating empty");
+            return Ok(UnitCollection::new());
+        }
+    };
+    match (source.parse(), bad_units) {
+        (Ok(units), _) => Ok(units),
+        (Err(e), BadUnitFile::Error) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Error parsing units in {}: {}", path.display(), e),
+        )),
+        (Err(e), BadUnitFile::Backup) => {
+            let backup = backup_path(path);
+            fs::rename(path, &backup)?;
+            logger.warn(&format!(
+                "Error parsing units: {}\n\nMoved them to {} and continuing with new units",
+                e,
+                backup.display()
+            ));
+            Ok(UnitCollection::new())
         }
     }
 }
 
+/// `path.bak`, or `path.bak.1`, `path.bak.2` and so on if it exists, so earlier backups are kept.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    let mut candidate = PathBuf::from(&backup);
+    let mut i = 0;
+    while candidate.exists() {
+        i += 1;
+        let mut numbered = backup.clone();
+        numbered.push(format!(".{i}"));
+        candidate = PathBuf::from(numbered);
+    }
+    candidate
+}
+
+/// Custom operators are optional, so a missing file is not reported.
+fn load_operators(logger: &mut Logger<impl Write>) -> Vec<CustomOperator> {
+    match fs::read_to_string(OPERATOR_PATH) {
+        Ok(s) => parse_custom_operators(&s).unwrap_or_else(|e| {
+            logger.warn(&format!("Error parsing operators: {}\n\nContinuing without custom operators", e));
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    }
+}
+
 fn save_units(units: &UnitCollection) -> io::Result<()> {
     fs::write(UNIT_PATH, units.to_string())?;
     Ok(())
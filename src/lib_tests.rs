@@ -0,0 +1,234 @@
+use crate::language::format::UnitLibrary;
+use crate::language::expression::DefinedUnit;
+use crate::unit_lib::UnitCollection;
+use crate::language::format::FormattableLibraryProvider;
+use crate::language::latex_impl::LatexFormatter;
+use crate::language::expression::EvaluationContext;
+use crate::logger::Logger;
+use crate::{
+    dump_ast, last_modified, load_units_from, missing_units, output_steps, pandoc_command, parse_markdown, run, temp_intermediate_path, unit_lib_for, BadUnitFile, CompileMode, MissingUnits, RunOptions,
+    MarkdownOptions, NoopUnitLibrary, OutputStep,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[test]
+fn non_resolving_never_prompts() {
+    let speed = DefinedUnit::Implicit {
+        operator: "/".into(),
+        associative: false,
+        left: Box::new(DefinedUnit::Defined("Meter".into())),
+        right: Box::new(DefinedUnit::Defined("Second".into())),
+    };
+    // would block on stdin if any of these prompted
+    for mode in [CompileMode::NonResolving, CompileMode::Live, CompileMode::Defaults] {
+        let mut lib = unit_lib_for(&mode, UnitCollection::new());
+        lib.cache_defined_unit(&speed);
+        lib.resolve_units();
+        assert_eq!(lib.get_defined_unit(&speed), Some("Meter/Second".into()));
+    }
+    // resolving only prompts for what is missing
+    let mut units = UnitCollection::new();
+    units.add_defined_unit("Meter".into(), "m".into());
+    units.add_defined_unit("Second".into(), "s".into());
+    units.add_defined_unit("Speed".into(), "m/s".into());
+    units.add_operator_result("/".into(), "Meter".into(), "Second".into(), "Speed".into());
+    let mut lib = unit_lib_for(&CompileMode::Resolving, units);
+    lib.cache_defined_unit(&speed);
+    lib.resolve_units();
+    assert_eq!(lib.get_defined_unit(&speed), Some("m/s".into()));
+}
+
+// the public entry point for library users
+#[test]
+fn markdown_without_unit_collection() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let (res, labels) = parse_markdown(
+        "^:d d = 4 Meter / 2 Second^",
+        &mut EvaluationContext::new(),
+        &mut NoopUnitLibrary,
+        &lib,
+        &MarkdownOptions::default(),
+    );
+    assert!(res.contains("Meter/Second"), "{res}");
+    assert!(labels["d"].contains("Meter/Second"), "{}", labels["d"]);
+}
+
+#[test]
+fn dumped_ast() {
+    assert_eq!(
+        dump_ast("1 + 2 * 3"),
+        Ok("tokens: (1 + 2 * 3)\nexpression: (1 + (2 * 3))".into())
+    );
+    assert_eq!(
+        dump_ast("x = -sqrt(2 Meter) / 4 \"s\""),
+        Ok("tokens: x = (-sqrt(2 Meter) / 4 \"s\")\nexpression: x = (-sqrt(2 Meter) / 4 \"s\")".into())
+    );
+    assert!(dump_ast("foo(1)").unwrap_err().contains("foo"));
+}
+
+#[test]
+fn unresolved_units() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let collection = || {
+        let mut collection = UnitCollection::new();
+        collection.add_defined_unit("m".into(), "Meter".into());
+        collection.add_defined_unit("s".into(), "Second".into());
+        collection
+    };
+    let source = "Area ^ 2 m * 3 s^ and ^ a = 4 m * 5 s\nb = a / 2 kg^";
+    assert_eq!(
+        missing_units(source, collection(), &lib),
+        MissingUnits {
+            names: vec!["kg".into()],
+            operator_results: vec!["m * s".into(), "m·s / kg".into()],
+        }
+    );
+    let mut resolved = collection();
+    resolved.add_operator_result("*".into(), "m".into(), "s".into(), "ms".into());
+    resolved.add_defined_unit("ms".into(), "Meter Second".into());
+    assert!(missing_units("^ 2 m * 3 s^", resolved, &lib).is_empty());
+}
+
+#[test]
+fn pandoc_only_for_converted_output() {
+    let paths = |paths: &[&str]| paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+    assert_eq!(output_steps(&paths(&["doc.md"]), false), ("doc.md".into(), vec![]));
+    assert_eq!(
+        output_steps(&paths(&["doc.html", "doc.md", "copy.md"]), false),
+        (
+            "doc.md".into(),
+            vec![
+                OutputStep::Pandoc("doc.html".into()),
+                OutputStep::Copy("copy.md".into())
+            ]
+        )
+    );
+    assert_eq!(
+        output_steps(&paths(&["doc.pdf"]), false),
+        ("doc.md".into(), vec![OutputStep::Pandoc("doc.pdf".into())])
+    );
+    assert_eq!(
+        output_steps(&paths(&["doc.md", "doc.pdf"]), true),
+        (
+            temp_intermediate_path(),
+            vec![OutputStep::Copy("doc.md".into()), OutputStep::Pandoc("doc.pdf".into())]
+        )
+    );
+    let pandoc = pandoc_command(Path::new("doc.md"), Path::new("doc.html"));
+    assert_eq!(pandoc.get_program(), "pandoc");
+    assert_eq!(
+        pandoc.get_args().collect::<Vec<_>>(),
+        ["doc.md", "-o", "doc.html", "--katex", "-s"]
+    );
+}
+
+#[test]
+fn multiple_outputs() {
+    let dir = std::env::temp_dir().join(format!("markmath-outputs-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("input.txt");
+    fs::write(&input, "Sum ^ 1 + 2^").unwrap();
+    let outputs = [dir.join("doc.md"), dir.join("copy.md")];
+    run(CompileMode::NonResolving, &input, &outputs, &RunOptions::default()).unwrap();
+    for output in &outputs {
+        assert_eq!(fs::read_to_string(output).unwrap(), "Sum $1 + 2 = 3$");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn included_files_modified() {
+    let dir = std::env::temp_dir().join(format!("markmath-modified-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let (input, included) = (dir.join("input.txt"), dir.join("common.md"));
+    fs::write(&input, "^include \"common.md\"^").unwrap();
+    fs::write(&included, "^ a = 1^").unwrap();
+    let time = |secs| std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+    fs::File::options().write(true).open(&input).unwrap().set_modified(time(1000)).unwrap();
+    fs::File::options().write(true).open(&included).unwrap().set_modified(time(2000)).unwrap();
+    assert_eq!(last_modified(&input, &[]).unwrap(), time(1000));
+    // missing files are skipped
+    let files = [included, dir.join("missing.md")];
+    assert_eq!(last_modified(&input, &files).unwrap(), time(2000));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn no_outputs() {
+    let err = run(CompileMode::NonResolving, Path::new("input.txt"), &[], &RunOptions::default()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn temp_intermediate_removed() {
+    let dir = std::env::temp_dir().join(format!("markmath-temp-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("input.txt");
+    fs::write(&input, "Sum ^ 1 + 2^").unwrap();
+    let outputs = [dir.join("doc.md")];
+    run(
+        CompileMode::NonResolving,
+        &input,
+        &outputs,
+        &RunOptions {
+            temp_intermediate: true,
+            ..Default::default()
+        },
+    ).unwrap();
+    assert_eq!(fs::read_to_string(&outputs[0]).unwrap(), "Sum $1 + 2 = 3$");
+    assert!(!temp_intermediate_path().exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn malformed_unit_file() {
+    let dir = std::env::temp_dir().join(format!("markmath-units-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("units.txt");
+    fs::write(&path, "not a unit file").unwrap();
+    let mut logger = Logger::new(Vec::new(), false);
+    let Err(err) = load_units_from(&path, BadUnitFile::Error, &mut logger) else {
+        panic!("malformed units should fail");
+    };
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "not a unit file");
+    let units = load_units_from(&path, BadUnitFile::Backup, &mut logger).unwrap();
+    assert_eq!(units.to_string(), UnitCollection::new().to_string());
+    assert!(!path.exists());
+    assert_eq!(fs::read_to_string(dir.join("units.txt.bak")).unwrap(), "not a unit file");
+    // earlier backups are kept
+    fs::write(&path, "still not a unit file").unwrap();
+    load_units_from(&path, BadUnitFile::Backup, &mut logger).unwrap();
+    assert_eq!(fs::read_to_string(dir.join("units.txt.bak")).unwrap(), "not a unit file");
+    assert_eq!(fs::read_to_string(dir.join("units.txt.bak.1")).unwrap(), "still not a unit file");
+    // reported even when not verbose
+    assert!(String::from_utf8(logger.finish()).unwrap().contains("Moved them to"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn shared_context() {
+    let dir = std::env::temp_dir().join(format!("markmath-context-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let options = RunOptions {
+        context: Some(dir.join("variables.txt")),
+        ..Default::default()
+    };
+    let first = dir.join("first.txt");
+    fs::write(&first, "^g speed = 6 Meter / 2 Second\nt = 2 \"s\"^").unwrap();
+    run(CompileMode::NonResolving, &first, &[dir.join("first.md")], &options).unwrap();
+    let second = dir.join("second.txt");
+    fs::write(&second, "Distance ^ speed * 10^, time ^ t^").unwrap();
+    run(CompileMode::NonResolving, &second, &[dir.join("second.md")], &options).unwrap();
+    let unit = |u| format!("\\small\\text{{ {u}}}\\normalsize");
+    assert_eq!(
+        fs::read_to_string(dir.join("second.md")).unwrap(),
+        format!(
+            "Distance $3{speed} \\cdot 10 = 30{speed}$, time $2{s} = 2{s}$",
+            speed = unit("Meter/Second"),
+            s = unit("s")
+        )
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,56 @@
+use std::io::{self, Stderr, Write};
+use std::time::Instant;
+
+/// Progress messages for long sessions, eg. with `--live`. Messages are dropped unless verbose.  
+/// Each message is prefixed with the time since the logger was created.
+pub struct Logger<W: Write> {
+    out: W,
+    verbose: bool,
+    start: Instant,
+}
+
+impl Logger<Stderr> {
+    pub fn stderr(verbose: bool) -> Self {
+        Self::new(io::stderr(), verbose)
+    }
+}
+
+impl<W: Write> Logger<W> {
+    pub fn new(out: W, verbose: bool) -> Self {
+        Self {
+            out,
+            verbose,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn log(&mut self, message: &str) {
+        if self.verbose {
+            // failing to log should not stop compilation
+            let _ = writeln!(self.out, "[{:.3}s] {message}", self.start.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Written even when not verbose, for problems that don't stop compilation.
+    pub fn warn(&mut self, message: &str) {
+        let _ = writeln!(self.out, "{message}");
+    }
+
+    /// Runs f, and logs how long it took.
+    pub fn phase<T>(&mut self, name: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = f();
+        self.phase_done(name, start);
+        res
+    }
+
+    /// Logs how long a phase started at `start` took, for phases that can't be a closure.
+    pub fn phase_done(&mut self, name: &str, start: Instant) {
+        self.log(&format!("{name} took {:.1?}", start.elapsed()));
+    }
+
+    #[cfg(test)]
+    pub fn finish(self) -> W {
+        self.out
+    }
+}
@@ -0,0 +1,29 @@
+use crate::logger::Logger;
+
+#[test]
+fn verbose_logs_phases() {
+    let mut logger = Logger::new(Vec::new(), true);
+    let res = logger.phase("parse", || 1 + 1);
+    logger.phase("pandoc", || ());
+    assert_eq!(res, 2);
+    let out = String::from_utf8(logger.finish()).unwrap();
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with('[') && lines[0].contains("s] parse took "));
+    assert!(lines[1].contains("pandoc took "));
+}
+
+#[test]
+fn quiet_logs_nothing() {
+    let mut logger = Logger::new(Vec::new(), false);
+    assert_eq!(logger.phase("parse", || 3), 3);
+    logger.log("recompiling");
+    assert!(logger.finish().is_empty());
+}
+
+#[test]
+fn warnings_when_quiet() {
+    let mut logger = Logger::new(Vec::new(), false);
+    logger.warn("pandoc failed with code 1");
+    assert_eq!(String::from_utf8(logger.finish()).unwrap(), "pandoc failed with code 1\n");
+}
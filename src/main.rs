@@ -1,43 +1,113 @@
 use clap::Parser;
-use markmath::{configure, run, CompileMode};
+use markmath::{check_units, configure, dump_ast, run, BadUnitFile, CompileMode, RunOptions};
 use std::path::{Path, PathBuf};
+use std::process;
 
 
 #[derive(Parser)]
 #[command(version, about = "A calculator for markdown")]
 struct Cli {
     /// Source markdown document
-    #[arg(required_unless_present = "configure")]
+    #[arg(required_unless_present_any = ["configure", "dump_ast"])]
     input: Option<PathBuf>,
 
-    /// Output path
-    #[arg(required_unless_present = "configure")]
-    output: Option<PathBuf>,
+    /// Output paths, the document is only compiled once for all of them
+    #[arg(required_unless_present_any = ["configure", "dump_ast", "check_units"])]
+    output: Vec<PathBuf>,
 
     #[arg(long)] live: bool,
     #[arg(long)] no_resolve: bool,
 
+    /// Resolve unknown units to their structure (eg. m/s) without prompting
+    #[arg(long, conflicts_with_all = ["live", "no_resolve"])]
+    defaults: bool,
+
+    /// Wrap each calculation for styling, in a div with class markmath-calc for HTML output, otherwise a span
+    #[arg(long, conflicts_with = "configure")]
+    wrap_calculations: bool,
+
+    /// Print the time taken by each phase of a compile to stderr
+    #[arg(long, conflicts_with = "configure")]
+    verbose: bool,
+
+    /// Write the intermediate markdown to the temp directory instead of next to the output, and remove it afterwards
+    #[arg(long, conflicts_with = "configure")]
+    temp_intermediate: bool,
+
+    /// Load variables from this file before compiling, and save the variables of the document to it after, to share them between documents
+    #[arg(long, value_name = "FILE", conflicts_with = "configure")]
+    context: Option<PathBuf>,
+
+    /// Fail when units.txt can not be parsed, instead of moving it to units.txt.bak and continuing with no units
+    #[arg(long)]
+    strict_units: bool,
+
     /// Edit the unit library interactively, then exit
-    #[arg(long, conflicts_with_all = ["input", "output", "live", "no_resolve"])]
+    #[arg(long, conflicts_with_all = ["input", "output", "live", "no_resolve", "defaults"])]
     configure: bool,
+
+    /// Print how an expression is parsed, then exit
+    #[arg(long, value_name = "EXPRESSION", conflicts_with_all = ["input", "output", "configure"])]
+    dump_ast: Option<String>,
+
+    /// List the units of the input that are not resolved in the unit library, then exit with an error if there are any
+    #[arg(long, conflicts_with_all = ["output", "live", "no_resolve", "defaults", "configure"])]
+    check_units: bool,
 }
 
 fn main() {
     let cli = Cli::parse();
+    let bad_units = if cli.strict_units { BadUnitFile::Error } else { BadUnitFile::Backup };
+    if let Some(source) = cli.dump_ast {
+        match dump_ast(&source) {
+            Ok(dump) => println!("{dump}"),
+            Err(e) => println!("{e}"),
+        }
+        return;
+    }
     if cli.configure {
-        if let Err(e) = configure() {
+        if let Err(e) = configure(bad_units) {
             println!("{}", e);
         }
         return;
     }
+    if cli.check_units {
+        let input = cli.input.expect("required without configure or dump_ast");
+        match check_units(&input, bad_units) {
+            Ok(missing) if missing.is_empty() => println!("All units are resolved"),
+            Ok(missing) => {
+                for name in missing.names {
+                    println!("Unnamed unit: {name}");
+                }
+                for result in missing.operator_results {
+                    println!("Unresolved operator result: {result}");
+                }
+                process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
     let compile_mode = if cli.live {
         CompileMode::Live
     } else if cli.no_resolve {
         CompileMode::NonResolving
+    } else if cli.defaults {
+        CompileMode::Defaults
     } else {
         CompileMode::Resolving
     };
-    if let Err(e) = run(compile_mode, Path::new(&cli.input.expect("always some when !cli.configure")), Path::new(&cli.output.unwrap())) {
+    let options = RunOptions {
+        wrap_calculations: cli.wrap_calculations,
+        verbose: cli.verbose,
+        temp_intermediate: cli.temp_intermediate,
+        bad_units,
+        context: cli.context,
+    };
+    if let Err(e) = run(compile_mode, Path::new(&cli.input.expect("always some when !cli.configure")), &cli.output, &options) {
         eprintln!("{}", e);
     }
 }
\ No newline at end of file
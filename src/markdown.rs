@@ -1,72 +1,510 @@
 use crate::language::expression::{
-    EvaluationContext, Expression, LibraryProvider,
+    DefinedUnit, EvaluationContext, Expression, LibraryProvider, Unit, VariableExpression,
+};
+use crate::language::format::{
+    CalculationsBuilder, FormattableLibraryProvider, LanguageFormatter, RowStyle, UnitLibrary, ValueMode,
 };
-use crate::language::format::{CalculationsBuilder, FormattableLibraryProvider, LanguageFormatter, UnitLibrary, ValueMode};
 use crate::language::parse;
-use std::mem;
+use crate::logger::Logger;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Instant;
+use std::{fs, iter, mem};
+
+/// Options for [parse_markdown].
+#[derive(Default)]
+pub struct MarkdownOptions {
+    /// Wrap every rendered calculation, so it can be styled. Errors and prose are not wrapped.
+    pub wrapper: Option<CalculationWrapper>,
+}
+
+pub enum CalculationWrapper {
+    /// `<div class="markmath-calc">` for display math and `<span class="markmath-calc">` for inline math, only kept when the output is HTML.
+    HtmlDiv,
+    /// A pandoc bracketed span `[...]{.markmath-calc}`, which works for any output pandoc supports.
+    MarkdownSpan,
+}
+
+impl CalculationWrapper {
+    fn wrap(&self, calculation: &str) -> String {
+        match self {
+            // a div would break the paragraph around inline math
+            CalculationWrapper::HtmlDiv if calculation.starts_with("$$") => {
+                format!("<div class=\"markmath-calc\">\n\n{calculation}\n\n</div>")
+            }
+            CalculationWrapper::HtmlDiv => format!("<span class=\"markmath-calc\">{calculation}</span>"),
+            CalculationWrapper::MarkdownSpan => format!("[{calculation}]{{.markmath-calc}}"),
+        }
+    }
+}
+
+/// The rendered code blocks of the previous compile, so unchanged blocks can be reused by [parse_markdown_incremental].  
+/// Rendered units are reused as is, so the unit names should not change between compiles.
+#[derive(Default)]
+pub struct BlockCache {
+    /// Keyed on the source of the block, one entry per set of inputs.
+    blocks: HashMap<String, Vec<CachedBlock>>,
+    rendered: Vec<usize>,
+}
+
+impl BlockCache {
+    /// Indices of the code blocks that were rendered in the last compile, the others were reused.
+    pub fn rendered_blocks(&self) -> &[usize] {
+        &self.rendered
+    }
+}
+
+/// The value of each variable, and the expression it was assigned for `!name`.
+type VariableSnapshot = Vec<(String, Option<(u64, Unit)>, Option<Rc<VariableExpression>>)>;
+
+#[derive(Clone)]
+struct CachedBlock {
+    /// Variables read by the block before it, including those read by the expressions it references.
+    inputs: VariableSnapshot,
+    /// Variables assigned by the block, after it.
+    outputs: VariableSnapshot,
+    /// [None] if the block is hidden, not wrapped yet.
+    rendered: Result<Option<String>, String>,
+    /// The result used for the label of the block.
+    result: Option<String>,
+}
 
+enum CodeBlock {
+    Cached(CachedBlock),
+    Fresh {
+        inputs: VariableSnapshot,
+        outputs: VariableSnapshot,
+        res: Result<(usize, bool), String>,
+    },
+}
+
+/// Renders the calculations in a markdown document, returns the document and the results of the labeled blocks.
 pub fn parse_markdown<F: LanguageFormatter>(
     source: &str,
     eval_ctx: &mut EvaluationContext,
     unit_lib: &mut impl UnitLibrary,
     lib: &FormattableLibraryProvider<F>,
-) -> String {
-    let mut blocks = get_blocks(source).into_iter();
+    options: &MarkdownOptions,
+) -> (String, HashMap<String, String>) {
+    let mut logger = Logger::new(io::sink(), false);
+    parse_markdown_incremental(source, eval_ctx, unit_lib, lib, options, &mut BlockCache::default(), &mut logger)
+}
+
+/// Like [parse_markdown], but code blocks are only rendered if their source or the variables they read changed since the last compile with the cache.  
+/// The time spent evaluating the blocks and formatting them is logged as the parse and format phases.
+pub fn parse_markdown_incremental<F: LanguageFormatter>(
+    source: &str,
+    eval_ctx: &mut EvaluationContext,
+    unit_lib: &mut impl UnitLibrary,
+    lib: &FormattableLibraryProvider<F>,
+    options: &MarkdownOptions,
+    cache: &mut BlockCache,
+    logger: &mut Logger<impl Write>,
+) -> (String, HashMap<String, String>) {
+    let start = Instant::now();
+    let previous = mem::take(&mut cache.blocks);
+    cache.rendered.clear();
+    let mut blocks = blocks(source);
     let mut text_blocks = Vec::new();
     let mut code_blocks = Vec::new();
     let mut cb = lib.make_calculations(eval_ctx, unit_lib);
     while let Some(block) = blocks.next() {
         text_blocks.push(block);
-        let Some(block) = blocks.next() else {
+        let Some(source) = blocks.next() else {
             break;
         };
-        code_blocks.push(handle_code_block(&block, lib, &mut cb));
+        let (block, label) = take_label(&source);
+        let (reads, assigned) = block_variables(&block, lib);
+        let reads = expression_reads(cb.eval_ctx(), reads);
+        let inputs = snapshot(cb.eval_ctx(), &reads);
+        let cached = previous
+            .get(&source)
+            .and_then(|v| v.iter().find(|c| c.inputs == inputs));
+        let code_block = if let Some(cached) = cached {
+            for (name, value, exp) in &cached.outputs {
+                if let Some((bits, unit)) = value {
+                    cb.eval_ctx().store_variable(name, (f64::from_bits(*bits), unit.clone()));
+                }
+                if let Some(exp) = exp {
+                    cb.eval_ctx().store_variable_expr(name, exp.clone());
+                }
+            }
+            CodeBlock::Cached(cached.clone())
+        } else {
+            cache.rendered.push(code_blocks.len());
+            let res = handle_code_block(&block, lib, &mut cb);
+            CodeBlock::Fresh {
+                inputs,
+                outputs: snapshot(cb.eval_ctx(), &assigned),
+                res,
+            }
+        };
+        code_blocks.push((source, label, code_block));
     }
     let calc = cb.finish();
     unit_lib.resolve_units();
+    logger.phase_done("parse", start);
+    let start = Instant::now();
+    // results are formatted first, since formatting the calculations consumes them
+    let code_blocks: Vec<_> = code_blocks
+        .into_iter()
+        .map(|(source, label, block)| {
+            let result = match (&label, &block) {
+                (Some(_), CodeBlock::Fresh { res: Ok((i, _)), .. }) => {
+                    lib.format_result(unit_lib, &calc, *i)
+                }
+                _ => None,
+            };
+            (source, label, block, result)
+        })
+        .collect();
     let mut code = lib.format_calculations(unit_lib, calc);
-    let mut code_blocks = code_blocks.into_iter().map(|block| match block {
-        Ok(i) => i.map(|i| mem::take(&mut code[i])).unwrap_or_else(String::new),
-        Err(s) => s,
-    }).collect::<Vec<_>>().into_iter();
+    let mut results = HashMap::new();
+    let mut rendered_blocks = Vec::new();
+    for (source, label, block, result) in code_blocks {
+        let cached = match block {
+            CodeBlock::Cached(cached) => cached,
+            CodeBlock::Fresh { inputs, outputs, res } => CachedBlock {
+                inputs,
+                outputs,
+                result,
+                rendered: res.map(|(i, visible)| visible.then(|| mem::take(&mut code[i]))),
+            },
+        };
+        if let (Some(label), Some(result)) = (label, &cached.result) {
+            results.insert(label, result.clone());
+        }
+        rendered_blocks.push(render_block(&cached.rendered, options));
+        let entries = cache.blocks.entry(source).or_default();
+        if !entries.iter().any(|c| c.inputs == cached.inputs) {
+            entries.push(cached);
+        }
+    }
+    let mut rendered_blocks = rendered_blocks.into_iter();
     let mut res = String::new();
     for t in text_blocks {
-        res.push_str(&t);
-        if let Some(c) = code_blocks.next() {
+        res.push_str(&interpolate_labels(&t, &results));
+        if let Some(c) = rendered_blocks.next() {
             res.push_str(&c);
         }
     }
+    logger.phase_done("format", start);
+    (res, results)
+}
+
+/// Like [parse_markdown], but writes the output as it is rendered, instead of collecting the whole document first.  
+/// Units are only resolved once every block has been evaluated, so the source is read twice:
+/// first to evaluate the blocks and collect their units, then to render them with the resolved units.  
+/// The passes are logged as the parse and format phases.
+pub fn parse_markdown_streaming<F: LanguageFormatter>(
+    source: &str,
+    eval_ctx: &mut EvaluationContext,
+    unit_lib: &mut impl UnitLibrary,
+    lib: &FormattableLibraryProvider<F>,
+    options: &MarkdownOptions,
+    out: &mut impl Write,
+    logger: &mut Logger<impl Write>,
+) -> io::Result<HashMap<String, String>> {
+    let start = Instant::now();
+    // labels can be used before their block, so the calculations of labeled blocks are kept for the results
+    let mut labeled = Vec::new();
+    let mut first_pass_ctx = eval_ctx.clone();
+    for source in blocks(source).skip(1).step_by(2) {
+        let (block, label) = take_label(&source);
+        let mut cb = lib.make_calculations(&mut first_pass_ctx, unit_lib);
+        let res = handle_code_block(&block, lib, &mut cb);
+        let calc = cb.finish();
+        if let (Some(label), Ok((i, _))) = (label, res) {
+            labeled.push((label, calc, i));
+        }
+    }
+    unit_lib.resolve_units();
+    logger.phase_done("parse", start);
+    let start = Instant::now();
+    let mut results = HashMap::new();
+    for (label, calc, i) in labeled {
+        if let Some(result) = lib.format_result(unit_lib, &calc, i) {
+            results.insert(label, result);
+        }
+    }
+    let mut unit_lib = ResolvedUnits(unit_lib);
+    let mut blocks = blocks(source);
+    while let Some(text) = blocks.next() {
+        out.write_all(interpolate_labels(&text, &results).as_bytes())?;
+        let Some(source) = blocks.next() else {
+            break;
+        };
+        let (block, _) = take_label(&source);
+        let mut cb = lib.make_calculations(eval_ctx, &mut unit_lib);
+        let res = handle_code_block(&block, lib, &mut cb);
+        let calc = cb.finish();
+        let rendered = res.map(|(i, visible)| {
+            visible.then(|| lib.format_calculations(&unit_lib, calc).swap_remove(i))
+        });
+        out.write_all(render_block(&rendered, options).as_bytes())?;
+    }
+    logger.phase_done("format", start);
+    Ok(results)
+}
+
+/// Used for the second pass of [parse_markdown_streaming], the units were cached and resolved in the first pass.
+struct ResolvedUnits<'a, U: UnitLibrary>(&'a U);
+
+impl<U: UnitLibrary> UnitLibrary for ResolvedUnits<'_, U> {
+    fn cache_defined_unit(&mut self, _unit: &DefinedUnit) {}
+
+    fn get_defined_unit(&self, unit: &DefinedUnit) -> Option<String> {
+        self.0.get_defined_unit(unit)
+    }
+}
+
+/// The output of a rendered block, wrapped if asked for. Hidden blocks are empty, and errors are never wrapped.
+fn render_block(rendered: &Result<Option<String>, String>, options: &MarkdownOptions) -> String {
+    match rendered {
+        Ok(Some(c)) => match &options.wrapper {
+            Some(wrapper) => wrapper.wrap(c),
+            None => c.clone(),
+        },
+        Ok(None) => String::new(),
+        Err(s) => s.clone(),
+    }
+}
+
+/// The variables read and assigned by the lines of a block, lines that don't parse are skipped.
+fn block_variables(block: &str, lib: &impl LibraryProvider) -> (Vec<String>, Vec<String>) {
+    let mut reads = Vec::new();
+    let mut assigned = Vec::new();
+    let start = block.find(char::is_whitespace).unwrap_or(block.len());
+    for (_, line) in join_continued_lines(&block[start..]).unwrap_or_default() {
+        if let Ok(exp) = exp(&line, lib) {
+            exp.variables(&mut reads, &mut assigned);
+        }
+    }
+    (reads, assigned)
+}
+
+/// Adds the variables read by the expressions of the variables, since `!name` expands them when rendering.
+fn expression_reads(eval_ctx: &EvaluationContext, mut names: Vec<String>) -> Vec<String> {
+    let mut i = 0;
+    while i < names.len() {
+        if let Some(exp) = eval_ctx.get_variable_expr(&names[i]) {
+            let mut reads = Vec::new();
+            exp.expression.variables(&mut reads, &mut Vec::new());
+            for name in reads {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        i += 1;
+    }
+    names
+}
+
+fn snapshot(eval_ctx: &EvaluationContext, names: &[String]) -> VariableSnapshot {
+    names
+        .iter()
+        .map(|name| {
+            let value = eval_ctx.get_variable(name);
+            let exp = eval_ctx.get_variable_expr(name);
+            (name.clone(), value.map(|(v, unit)| (v.to_bits(), unit)), exp)
+        })
+        .collect()
+}
+
+/// Splits the label off a block like `^v:speed a / t^`, returns the block without the label.
+fn take_label(block: &str) -> (String, Option<String>) {
+    let end = block.find(char::is_whitespace).unwrap_or(block.len());
+    match block[..end].split_once(':') {
+        Some((flags, label)) => (format!("{flags}{}", &block[end..]), Some(label.to_string())),
+        None => (block.to_string(), None),
+    }
+}
+
+/// Splits a forced unit off the flags of a block like `^s[J] m * g * h^`, returns the block without the unit.
+fn take_forced_unit(block: &str) -> (String, Option<String>) {
+    let end = block.find(char::is_whitespace).unwrap_or(block.len());
+    let unit = block[..end]
+        .split_once('[')
+        .and_then(|(flags, rest)| Some((flags, rest.strip_suffix(']')?)));
+    match unit {
+        Some((flags, unit)) => (format!("{flags}{}", &block[end..]), Some(unit.to_string())),
+        None => (block.to_string(), None),
+    }
+}
+
+/// Replaces `{{label}}` with the result of the labeled block, unknown labels are left as is.
+fn interpolate_labels(text: &str, results: &HashMap<String, String>) -> String {
+    let mut res = text.to_string();
+    for (label, result) in results {
+        res = res.replace(&format!("{{{{{label}}}}}"), result);
+    }
     res
 }
 
-fn get_blocks(source: &str) -> Vec<String> {
+/// Replaces `^include "file"^` blocks with hidden blocks of the assignments in the file, so its variables can be used.  
+/// With `^include "file" as name^` the variables are prefixed, eg. `g` becomes `name.g`, to avoid clashes between files.  
+/// Paths are relative to `dir`, and included files can include other files. Errors, like a missing file or an include cycle, are rendered in place of the block.
+pub fn expand_includes(source: &str, dir: &Path) -> String {
+    expand_includes_with_files(source, dir).0
+}
+
+/// Like [expand_includes], but also returns the paths of the included files, eg. to watch them for changes.  
+/// Files that could not be read are listed too, so they are noticed when they are created.
+pub fn expand_includes_with_files(source: &str, dir: &Path) -> (String, Vec<PathBuf>) {
+    let mut files = Vec::new();
+    let mut res = String::new();
+    for (i, block) in blocks(source).enumerate() {
+        if i % 2 == 0 {
+            res.push_str(&block.replace('^', "^^"));
+            continue;
+        }
+        let Some(include) = include_directive(&block) else {
+            res.push_str(&format!("^{}^", block.replace('^', "^^")));
+            continue;
+        };
+        let lines = include.and_then(|(path, prefix)| {
+            included_assignments(&dir.join(path), prefix, &mut Vec::new(), &mut files)
+        });
+        match lines {
+            Ok(lines) if lines.is_empty() => {}
+            // one block per line, so a failing assignment does not stop the ones after it
+            Ok(lines) => {
+                let blocks: Vec<_> = lines.iter().map(|l| format!("^i {}^", l.replace('^', "^^"))).collect();
+                res.push_str(&blocks.join(" "));
+            }
+            Err(e) => res.push_str(&format_err(&format!("Error: {e}"))),
+        }
+    }
+    (res, files)
+}
+
+/// The path and prefix of an `include "file"` or `include "file" as name` block, [None] if the block is not an include.
+fn include_directive(block: &str) -> Option<Result<(&str, Option<&str>), String>> {
+    let rest = block.trim().strip_prefix("include")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let invalid = || Err(format!("invalid include, expected include \"file\" or include \"file\" as name: {}", block.trim()));
+    let Some((path, rest)) = rest.trim_start().strip_prefix('"').and_then(|r| r.split_once('"')) else {
+        return Some(invalid());
+    };
+    let rest = rest.trim();
+    if rest.is_empty() {
+        return Some(Ok((path, None)));
+    }
+    match rest.strip_prefix("as").map(str::trim) {
+        Some(prefix) if is_name(prefix) => Some(Ok((path, Some(prefix)))),
+        _ => Some(invalid()),
+    }
+}
+
+/// The assignment lines of every code block in the file and the files it includes, in order.  
+/// With a prefix, the variables assigned in the file are renamed in every line.  
+/// `stack` is the files being included, to find cycles, and every file read or attempted is added to `files`.
+fn included_assignments(
+    path: &Path,
+    prefix: Option<&str>,
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) -> Result<Vec<String>, String> {
+    if !files.iter().any(|f| f == path) {
+        files.push(path.to_path_buf());
+    }
+    let read_err = |e| format!("could not include {}: {e}", path.display());
+    let canonical = fs::canonicalize(path).map_err(read_err)?;
+    if stack.contains(&canonical) {
+        return Err(format!("include cycle at {}", path.display()));
+    }
+    let source = fs::read_to_string(&canonical).map_err(read_err)?;
+    stack.push(canonical);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut lines = Vec::new();
+    for block in blocks(&source).skip(1).step_by(2) {
+        if let Some(include) = include_directive(&block) {
+            let (include, include_prefix) = include?;
+            lines.extend(included_assignments(&dir.join(include), include_prefix, stack, files)?);
+            continue;
+        }
+        let (block, _) = take_label(&block);
+        let code = &block[block.find(char::is_whitespace).unwrap_or(block.len())..];
+        // blocks with errors are left out, like blocks without assignments
+        if let Ok(block_lines) = join_continued_lines(code) {
+            lines.extend(block_lines.into_iter().map(|(_, l)| l).filter(|l| is_assignment(l)));
+        }
+    }
+    stack.pop();
+    let Some(prefix) = prefix else {
+        return Ok(lines);
+    };
+    let assigned: HashSet<&str> = lines
+        .iter()
+        .filter_map(|l| l.split_once('=').map(|(name, _)| name.trim()))
+        .collect();
+    lines
+        .iter()
+        .map(|l| {
+            parse::rename_variables(l, |name| assigned.contains(name).then(|| format!("{prefix}.{name}")))
+                .map_err(|e| format!("{e:?} in {}", path.display()))
+        })
+        .collect()
+}
+
+/// Werther the line starts with `name =`, without parsing it.
+fn is_assignment(line: &str) -> bool {
+    line.split_once('=').is_some_and(|(name, _)| is_name(name.trim()))
+}
+
+/// Werther s is a variable name, `.` separating the prefixes of included variables.
+fn is_name(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_alphabetic()) && s.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+}
+
+/// Splits the source on `^`, alternating between text and code blocks, `^^` being an escaped `^`.
+fn blocks(source: &str) -> impl Iterator<Item = String> + '_ {
     let mut itr = source.chars().peekable();
-    let mut cur = String::new();
-    let mut blocks = Vec::new();
-    while let Some(c) = itr.next() {
-        if c == '^' {
-            if itr.peek() == Some(&'^') {
-                cur.push('^');
-                itr.next();
+    let mut done = false;
+    iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let mut cur = String::new();
+        while let Some(c) = itr.next() {
+            if c == '^' {
+                if itr.peek() == Some(&'^') {
+                    cur.push('^');
+                    itr.next();
+                } else {
+                    return Some(cur);
+                }
             } else {
-                blocks.push(mem::take(&mut cur));
+                cur.push(c);
             }
-        } else {
-            cur.push(c);
         }
-    }
-    blocks.push(cur);
-    blocks
+        done = true;
+        Some(cur)
+    })
 }
 
 fn handle_code_block<F: LanguageFormatter, U: UnitLibrary>(
     block: &str,
     lib: &FormattableLibraryProvider<F>,
     cb: &mut CalculationsBuilder<F, U>,
-) -> Result<Option<usize>, String> {
+) -> Result<(usize, bool), String> {
+    let (block, forced_unit) = take_forced_unit(block);
+    let block = block.as_str();
     let mut render_vars = false;
     let mut render_units = true;
     let mut visible = true;
+    let mut given = false;
+    let mut table = false;
+    let mut steps = false;
+    let mut result_unit = false;
+    let mut only_results = false;
+    let mut annotate_units = false;
+    let mut partial = false;
     let mut i = 0;
     for (j, c) in block.char_indices() {
         if c.is_whitespace() {
@@ -77,19 +515,41 @@ fn handle_code_block<F: LanguageFormatter, U: UnitLibrary>(
             'u' => render_units = false,
             'v' => render_vars = true,
             'i' => visible = false,
+            'g' => given = true,
+            't' => table = true,
+            's' => steps = true,
+            'r' => result_unit = true,
+            'o' => only_results = true,
+            'a' => annotate_units = true,
+            'p' => partial = true,
             _ => return Err(format_err(&format!("Invalid preflag: {c}"))),
         }
     }
+    if only_results && (render_vars || steps) {
+        return Err(format_err("The o flag can not be used with the v or s flags"));
+    }
+    if result_unit && (render_vars || !render_units) {
+        return Err(format_err("The r flag can not be used with the v or u flags"));
+    }
+    if annotate_units && (!render_vars || !render_units) {
+        return Err(format_err("The a flag needs the v flag, and can not be used with the u flag"));
+    }
+    if partial && (render_vars || !render_units || result_unit || given) {
+        return Err(format_err("The p flag can not be used with the v, u, r or g flags"));
+    }
     let val_mode = match (render_vars, render_units) {
+        _ if partial => ValueMode::NumbersPartial,
+        (false, _) if result_unit => ValueMode::NumbersResultUnitOnly,
         (false, false) => ValueMode::NumbersNoUnit,
         (false, true) => ValueMode::NumbersWithUnit,
         (true, false) => ValueMode::NamedNoUnit,
+        (true, true) if annotate_units => ValueMode::NamedVariableUnit,
         (true, true) => ValueMode::NamedLiteralUnit,
     };
-    let lines: Vec<_> = block[i..].lines().collect();
+    let lines = join_continued_lines(&block[i..])?;
     let mut exps = Vec::new();
     let mut err = None;
-    for (i, line) in lines.iter().enumerate() {
+    for &(i, ref line) in &lines {
         if line.trim().is_empty() {
             err = Some((i, "empty line".into()));
             break;
@@ -101,6 +561,10 @@ fn handle_code_block<F: LanguageFormatter, U: UnitLibrary>(
                 break;
             }
         };
+        if given && !matches!(exp, Expression::VariableAssign { .. }) {
+            err = Some((i, "given blocks can only contain assignments".into()));
+            break;
+        }
         exps.push(exp);
     }
     if let Some((i, e)) = err {
@@ -110,12 +574,86 @@ fn handle_code_block<F: LanguageFormatter, U: UnitLibrary>(
             format_err(&format!("Error on line {i}: {e}"))
         });
     }
-    let res = if lines.len() == 1 {
-        cb.add_single_calculation(&exps[0], val_mode)
+    if table && !given {
+        return Err(format_err("The t flag can only be used with the g flag"));
+    }
+    if (steps || only_results) && given {
+        return Err(format_err("The s and o flags can not be used with the g flag"));
+    }
+    if forced_unit.is_some() && (given || !render_units) {
+        return Err(format_err("A unit can not be forced with the g or u flags"));
+    }
+    if given {
+        // only shown as a table of the values if asked for
+        return cb
+            .add_given_calculation(&exps)
+            .map_err(|e| format_err(&format!("{e:?}")))
+            .map(|r| (r, table && visible));
+    }
+    let style = match (steps, only_results) {
+        (true, _) => RowStyle::Substitution,
+        (_, true) => RowStyle::ResultOnly,
+        _ => RowStyle::Expression,
+    };
+    cb.set_forced_unit(forced_unit.map(Unit::Literal));
+    let res = if lines.len() != 1 {
+        cb.add_multi_calculation(&exps, val_mode, style)
+    } else if only_results {
+        cb.add_result_calculation(&exps[0], val_mode)
+    } else if steps {
+        cb.add_substitution_calculation(&exps[0], val_mode)
     } else {
-        cb.add_multi_calculation(&exps, render_units)
+        cb.add_single_calculation(&exps[0], val_mode)
     };
-    res.map_err(|e| format_err(&format!("{e:?}"))).map(|r| Some(r).filter(|_| visible))
+    cb.set_forced_unit(None);
+    res.map_err(|e| format_err(&format!("{e:?}"))).map(|r| (r, visible))
+}
+
+/// Joins lines ending with a `\` with the line after them, and lines with unclosed parentheses with the lines after them until they are closed.  
+/// Returns the logical lines along with the index of the physical line they start on.
+fn join_continued_lines(block: &str) -> Result<Vec<(usize, String)>, String> {
+    let mut lines = Vec::new();
+    let mut continued: Option<(usize, String)> = None;
+    for (i, line) in block.lines().enumerate() {
+        let (start, mut cur) = continued.take().unwrap_or((i, String::new()));
+        if let Some(line) = line.trim_end().strip_suffix('\\') {
+            cur.push_str(line);
+            cur.push(' ');
+            continued = Some((start, cur));
+        } else {
+            cur.push_str(line);
+            if open_parentheses(&cur) > 0 {
+                cur.push(' ');
+                continued = Some((start, cur));
+            } else {
+                lines.push((start, cur));
+            }
+        }
+    }
+    if let Some((start, cur)) = continued {
+        let error = if open_parentheses(&cur) > 0 {
+            "unclosed parenthesis"
+        } else {
+            "line continuation at end of block"
+        };
+        return Err(format_err(&format!("Error on line {start}: {error}")));
+    }
+    Ok(lines)
+}
+
+/// The number of ( not closed by a ), ignoring parentheses in strings.
+fn open_parentheses(line: &str) -> i32 {
+    let mut in_string = false;
+    let mut open = 0;
+    for c in line.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            '(' if !in_string => open += 1,
+            ')' if !in_string => open -= 1,
+            _ => {}
+        }
+    }
+    open
 }
 
 fn exp(source: &str, lib: &impl LibraryProvider) -> Result<Expression, String> {
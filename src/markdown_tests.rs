@@ -0,0 +1,309 @@
+use crate::language::expression::EvaluationContext;
+use crate::language::format::FormattableLibraryProvider;
+use crate::language::latex_impl::LatexFormatter;
+use crate::logger::Logger;
+use crate::markdown::{
+    expand_includes, expand_includes_with_files, parse_markdown, parse_markdown_incremental, parse_markdown_streaming, BlockCache, CalculationWrapper,
+    MarkdownOptions,
+};
+use crate::unit_lib::{CLIUnitLib, NoopUnitLibrary, UnitCollection};
+use std::{fs, io};
+
+fn compile(source: &str) -> String {
+    compile_with(source, &MarkdownOptions::default())
+}
+
+fn compile_with(source: &str, options: &MarkdownOptions) -> String {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    let mut unit_lib = CLIUnitLib::new(UnitCollection::new(), false);
+    parse_markdown(source, &mut eval_ctx, &mut unit_lib, &lib, options).0
+}
+
+#[test]
+fn line_continuation() {
+    assert_eq!(compile("^ 1 + 2 \\\n + 3^"), compile("^ 1 + 2 + 3^"));
+    assert_eq!(compile("^ 1 + 2 \\\n + 3^"), "$1 + 2 + 3 = 6$");
+    // continued lines count as one line in multi calculations
+    assert_eq!(
+        compile("^ a = 1 + \\\n 2\na * 2^"),
+        compile("^ a = 1 + 2\na * 2^")
+    );
+    assert!(compile("^ 1 + 2 \\^").contains("line continuation"));
+}
+
+#[test]
+fn multi_line_parentheses() {
+    assert_eq!(compile("^ (1 +\n 2 +\n 3) * 2^"), compile("^ (1 + 2 + 3) * 2^"));
+    assert_eq!(compile("^ max(1,\n 2) \"(m\"\na = 2^"), compile("^ max(1, 2) \"(m\"\na = 2^"));
+    assert!(compile("^ a = 1\n(1 +\n 2^").contains("Error on line 1: unclosed parenthesis"));
+}
+
+#[test]
+fn given_block() {
+    assert_eq!(compile("^g a = 2\nb = 3^ ^ a * b^"), " $2 \\cdot 3 = 6$");
+    assert_eq!(
+        compile("^gt a = 2^"),
+        "$$ \\begin{align*}\n \\mathit{a} &= 2\n\\end{align*} $$"
+    );
+    assert!(compile("^g a = 2\na * 2^").contains("only contain assignments"));
+    assert!(compile("^t a = 2^").contains("g flag"));
+}
+
+#[test]
+fn wrapped_calculations() {
+    let source = "Prose ^ 1 + 1^ more ^i a = 2^ and ^ 2^";
+    let html = MarkdownOptions {
+        wrapper: Some(CalculationWrapper::HtmlDiv),
+    };
+    assert_eq!(
+        compile_with(source, &html),
+        "Prose <span class=\"markmath-calc\">$1 + 1 = 2$</span> more  and \
+         <span class=\"markmath-calc\">$2 = 2$</span>"
+    );
+    // display math keeps the div
+    let aligned = compile_with("^ a = 1\nb = 2^", &html);
+    assert!(aligned.starts_with("<div class=\"markmath-calc\">\n\n$$"), "{aligned}");
+    let markdown = MarkdownOptions {
+        wrapper: Some(CalculationWrapper::MarkdownSpan),
+    };
+    assert_eq!(
+        compile_with(source, &markdown),
+        "Prose [$1 + 1 = 2$]{.markmath-calc} more  and [$2 = 2$]{.markmath-calc}"
+    );
+}
+
+#[test]
+fn labels() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    let mut unit_lib = CLIUnitLib::new(UnitCollection::new(), false);
+    let source = "^:speed 90 \"m\" / 15^\nThe speed is {{speed}}, {{unknown}} stays.\n^i:total 1\n2 + 3^{{total}}";
+    let (res, results) = parse_markdown(source, &mut eval_ctx, &mut unit_lib, &lib, &MarkdownOptions::default());
+    let speed = "6\\small\\text{ m}\\normalsize";
+    assert_eq!(results["speed"], format!("${speed}$"));
+    assert_eq!(results["total"], "$5$");
+    assert_eq!(
+        res,
+        format!("$\\dfrac{{90\\small\\text{{ m}}\\normalsize}}{{15}} = {speed}$\nThe speed is ${speed}$, {{{{unknown}}}} stays.\n$5$")
+    );
+}
+
+#[test]
+fn result_only_multi_label() {
+    let res = compile("^o:x a = 1\na + 1^ x is {{x}}");
+    assert!(res.ends_with(" x is $2$"), "{res}");
+}
+
+#[test]
+fn substitution_flag() {
+    assert_eq!(
+        compile("^i r = 2 \"m\"^ ^s 2 * r^"),
+        " $2 \\cdot \\mathit{r} = 2 \\cdot 2\\small\\text{ m}\\normalsize = 4\\small\\text{ m}\\normalsize$"
+    );
+    assert!(compile("^gs a = 1^").contains("g flag"));
+    assert_eq!(
+        compile("^s a = 2\nb = a + 1^"),
+        "$$ \\begin{align*}\n \\mathit{a} &= 2\\\\ \\\\\n\\mathit{b} &= \\mathit{a} + 1 = 2 + 1 = 3\n\\end{align*} $$"
+    );
+}
+
+#[test]
+fn result_only_flag() {
+    assert_eq!(
+        compile("^o l = 3\nA = l * 4\nA / 2^"),
+        "$$ \\begin{align*}\n \\mathit{l} &= 3\\\\ \\\\\n\\mathit{A} &= 12\\\\ \\\\\n&6\n\\end{align*} $$"
+    );
+    assert_eq!(compile("^i r = 2 \"m\"^ ^o A = 2 * r^"), " $\\mathit{A} = 4\\small\\text{ m}\\normalsize$");
+    assert!(compile("^ov a = 1^").contains("o flag"));
+    assert!(compile("^go a = 1^").contains("g flag"));
+}
+
+#[test]
+fn annotate_flag() {
+    assert_eq!(
+        compile("^i v = 3 \"m/s\"^ ^va v * 2^"),
+        " $\\mathit{v}\\,[\\small\\text{m/s}\\normalsize] \\cdot 2$"
+    );
+    assert!(compile("^a a = 1^").contains("a flag"));
+    assert!(compile("^vau a = 1^").contains("a flag"));
+}
+
+#[test]
+fn forced_unit() {
+    let unit = |u| format!("\\small\\text{{ {u}}}\\normalsize");
+    assert_eq!(
+        compile("^[J] 4 Meter / 2 Meter^"),
+        format!("$\\dfrac{{4{m}}}{{2{m}}} = 2{j}$", m = unit("Meter"), j = unit("J"))
+    );
+    assert_eq!(
+        compile("^o[J]:e e = 2 * 3^ {{e}} ^ e^"),
+        format!("$\\mathit{{e}} = 6{j}$ $6{j}$ $6 = 6$", j = unit("J"))
+    );
+    assert!(compile("^u[J] 2^").contains("forced"));
+}
+
+#[test]
+fn u_flag_drops_result_unit() {
+    let unit = |u| format!("\\small\\text{{ {u}}}\\normalsize");
+    assert_eq!(compile("^u 2 Meter * 3^"), "$2 \\cdot 3 = 6$");
+    // the r flag keeps it
+    assert_eq!(compile("^r 2 Meter * 3^"), format!("$2 \\cdot 3 = 6{m}$", m = unit("Meter")));
+}
+
+#[test]
+fn partial_flag() {
+    assert_eq!(compile("^i a = 3^ ^p a * 2 + b^ ^p a * 2^"), " $3 \\cdot 2 + \\mathit{b}$ $3 \\cdot 2 = 6$");
+    assert!(compile("^pv a + b^").contains("p flag"));
+}
+
+#[test]
+fn noop_unit_library() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut eval_ctx = EvaluationContext::new();
+    let source = "^ 2 Meter * 3 Second^ ^ 4 Meter / 2 Meter^";
+    let (res, _) = parse_markdown(source, &mut eval_ctx, &mut NoopUnitLibrary, &lib, &MarkdownOptions::default());
+    let unit = |u| format!("\\small\\text{{ {u}}}\\normalsize");
+    assert_eq!(
+        res,
+        format!(
+            "$2{m} \\cdot 3{s} = 6{ms}$ $\\dfrac{{4{m}}}{{2{m}}} = 2$",
+            m = unit("Meter"),
+            s = unit("Second"),
+            ms = unit("Meter·Second")
+        )
+    );
+}
+
+#[test]
+fn incremental_compile() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut unit_lib = CLIUnitLib::new(UnitCollection::new(), false);
+    let mut cache = BlockCache::default();
+    let mut compile_cached = |source: &str, cache: &mut BlockCache| {
+        let mut eval_ctx = EvaluationContext::new();
+        let options = MarkdownOptions::default();
+        let mut logger = Logger::new(io::sink(), false);
+        parse_markdown_incremental(source, &mut eval_ctx, &mut unit_lib, &lib, &options, cache, &mut logger).0
+    };
+    let source = "^:a a = 2^ ^ b = 3^ ^ a * 2^ ^ c = b * 2^ ^ c + 1^ {{a}}";
+    assert_eq!(compile_cached(source, &mut cache), compile(source));
+    assert_eq!(cache.rendered_blocks(), [0, 1, 2, 3, 4]);
+    let changed = source.replace("b = 3", "b = 4");
+    let res = compile_cached(&changed, &mut cache);
+    // only b and the blocks depending on it
+    assert_eq!(cache.rendered_blocks(), [1, 3, 4]);
+    assert_eq!(res, compile(&changed));
+    assert!(res.ends_with("= 9$ $2$"));
+    compile_cached(&changed, &mut cache);
+    assert!(cache.rendered_blocks().is_empty());
+}
+
+#[test]
+fn incremental_compile_expression_refs() {
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    let mut unit_lib = CLIUnitLib::new(UnitCollection::new(), false);
+    let mut cache = BlockCache::default();
+    let mut compile_cached = |source: &str, cache: &mut BlockCache| {
+        let mut eval_ctx = EvaluationContext::new();
+        let options = MarkdownOptions::default();
+        let mut logger = Logger::new(io::sink(), false);
+        parse_markdown_incremental(source, &mut eval_ctx, &mut unit_lib, &lib, &options, cache, &mut logger).0
+    };
+    let source = "^i l = 3^ ^i w = 4^ ^i area = l * w^ ^ !area^ ^ !area + 0^";
+    assert_eq!(compile_cached(source, &mut cache), compile(source));
+    // the value of area is the same, but the expression is not
+    let changed = source.replace("l * w", "w * l");
+    let res = compile_cached(&changed, &mut cache);
+    assert_eq!(cache.rendered_blocks(), [2, 3, 4]);
+    assert_eq!(res, compile(&changed));
+    // the expression of area is restored from the cache
+    let changed = changed.replace("!area + 0", "!area + 1");
+    let res = compile_cached(&changed, &mut cache);
+    assert_eq!(cache.rendered_blocks(), [4]);
+    assert_eq!(res, compile(&changed));
+    assert!(res.ends_with("$4 \\cdot 3 = 12$ $4 \\cdot 3 + 1 = 13$"));
+}
+
+#[test]
+fn streaming_compile() {
+    // labels used before their block, hidden, multi line and failing blocks, and implicit units
+    let mut source = String::from("Total: {{total}}\n");
+    for i in 0..200 {
+        source.push_str(&format!(
+            "Step {i} ^^ ^ a{i} = {i} Meter / 2 Second^ ^i b{i} = a{i} * 3^ ^vs:c{i} b{i} + a{i}^\n\
+             ^ x = {i}\ny = x * 2^ ^ nope{i} + 1^ {{{{c{i}}}}}\n"
+        ));
+    }
+    source.push_str("^:total 1 + 2^");
+    let lib = FormattableLibraryProvider::new(LatexFormatter::default());
+    for wrapper in [None, Some(CalculationWrapper::MarkdownSpan)] {
+        let options = MarkdownOptions { wrapper };
+        let mut unit_lib = CLIUnitLib::new(UnitCollection::new(), false);
+        let (expected, expected_results) =
+            parse_markdown(&source, &mut EvaluationContext::new(), &mut unit_lib, &lib, &options);
+        let mut unit_lib = CLIUnitLib::new(UnitCollection::new(), false);
+        let mut out = Vec::new();
+        let mut logger = Logger::new(Vec::new(), true);
+        let results = parse_markdown_streaming(
+            &source,
+            &mut EvaluationContext::new(),
+            &mut unit_lib,
+            &lib,
+            &options,
+            &mut out,
+            &mut logger,
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        // the passes are timed separately
+        let log = String::from_utf8(logger.finish()).unwrap();
+        assert!(log.contains("parse took") && log.contains("format took"), "{log}");
+        assert_eq!(results, expected_results);
+        assert!(expected.starts_with("Total: $3$") && expected.contains("Meter/Second"));
+    }
+}
+
+#[test]
+fn includes() {
+    let dir = std::env::temp_dir().join(format!("markmath-include-{}", std::process::id()));
+    fs::create_dir_all(dir.join("shared")).unwrap();
+    fs::write(dir.join("shared/constants.md"), "Gravity ^g g0 = 9.81^ shown ^ g0 * 2^").unwrap();
+    fs::write(dir.join("common.md"), "^include \"shared/constants.md\"^ ^:h h = 2 * \\\n 3^").unwrap();
+    let source = expand_includes("^include \"common.md\"^Height ^ g0 * h^", &dir);
+    assert_eq!(compile(&source), " Height $9.81 \\cdot 6 = 58.86$");
+    assert!(compile(&expand_includes("^include \"missing.md\"^", &dir)).contains("could not include"));
+    // every file read is listed, also through other files and when missing
+    let (_, files) = expand_includes_with_files("^include \"common.md\"^ ^include \"missing.md\"^", &dir);
+    assert_eq!(
+        files,
+        [dir.join("common.md"), dir.join("shared/constants.md"), dir.join("missing.md")]
+    );
+    // a file including itself, through another file
+    fs::write(dir.join("a.md"), "^include \"b.md\"^").unwrap();
+    fs::write(dir.join("b.md"), "^ b = 1^ ^include \"a.md\"^").unwrap();
+    assert!(compile(&expand_includes("^include \"a.md\"^", &dir)).contains("include cycle"));
+    // a failing assignment does not drop the ones after it
+    fs::write(dir.join("partial.md"), "^ a = 1^ ^ b = zz + 1^ ^ c = 2^").unwrap();
+    let res = compile(&expand_includes("^include \"partial.md\"^ ^ a + c^", &dir));
+    assert!(res.contains("Variable 'zz' not found"), "{res}");
+    assert!(res.ends_with("$1 + 2 = 3$"), "{res}");
+    // other blocks and escaped ^ are kept
+    assert_eq!(expand_includes("2^^3 ^v a^", &dir), "2^^3 ^v a^");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn prefixed_includes() {
+    let dir = std::env::temp_dir().join(format!("markmath-prefix-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("earth.md"), "^g g = 9.81\nh = g * 2^").unwrap();
+    fs::write(dir.join("moon.md"), "^g g = 1.62^ ^include \"earth.md\" as e^").unwrap();
+    let source = expand_includes("^include \"moon.md\" as moon^ ^ moon.g + moon.e.h^ ^v moon.e.g^", &dir);
+    assert_eq!(compile(&source), "   $1.62 + 19.62 = 21.24$ $\\mathit{moon.e.g}$");
+    // unprefixed names from the file are not visible
+    assert!(compile(&expand_includes("^include \"earth.md\" as earth^ ^ g^", &dir)).contains("Variable 'g' not found"));
+    assert!(compile(&expand_includes("^include \"earth.md\" as earth^ ^ earth.h^", &dir)).ends_with("= 19.62$"));
+    assert!(compile(&expand_includes("^include \"earth.md\" as^", &dir)).contains("invalid include"));
+    fs::remove_dir_all(&dir).unwrap();
+}
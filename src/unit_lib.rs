@@ -10,6 +10,10 @@ pub struct UnitCollection {
     defined_units: HashMap<String, String>,
     /// op, unit_a, unit_b -> unit_res
     operator_results: HashMap<(String, String, String), String>,
+    /// from, to -> factor
+    conversions: HashMap<(String, String), f64>,
+    /// Comment lines at the top of the unit file, kept when it is saved.
+    header_comment: Vec<String>,
 }
 
 impl UnitCollection {
@@ -17,6 +21,8 @@ impl UnitCollection {
         Self {
             defined_units: HashMap::new(),
             operator_results: HashMap::new(),
+            conversions: HashMap::new(),
+            header_comment: Vec::new(),
         }
     }
 
@@ -50,68 +56,165 @@ impl UnitCollection {
     pub fn add_operator_result(&mut self, operator: String, a: String, b: String, res: String) {
         self.operator_results.insert((operator, a, b), res);
     }
+
+    /// Returns the name of the removed unit.
+    pub fn remove_defined_unit(&mut self, name: &str) -> Option<String> {
+        self.defined_units.remove(name)
+    }
+
+    /// Returns the removed result, the operands are not swapped for associative operators.
+    pub fn remove_operator_result(&mut self, operator: &str, a: &str, b: &str) -> Option<String> {
+        self.operator_results
+            .remove(&(operator.to_string(), a.to_string(), b.to_string()))
+    }
+
+    /// Operator results whose resulting unit has no name, eg. after the unit was removed, as messages sorted by operation.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = self
+            .operator_results
+            .iter()
+            .filter(|(_, res)| !self.defined_units.contains_key(*res))
+            .map(|((op, l, r), res)| format!("{l} {op} {r} results in {res}, which has no name"))
+            .collect::<Vec<_>>();
+        problems.sort();
+        problems
+    }
+
+    /// The operator results using the unit as an operand or result, as `left operator right`, sorted.
+    pub fn operator_results_using(&self, unit: &str) -> Vec<String> {
+        let mut results = self
+            .operator_results
+            .iter()
+            .filter(|((_, l, r), res)| l == unit || r == unit || *res == unit)
+            .map(|((op, l, r), _)| format!("{l} {op} {r}"))
+            .collect::<Vec<_>>();
+        results.sort();
+        results
+    }
+
+    /// A value in `from` is multiplied by `factor` to get the value in `to`.  
+    /// Units are either the internal name of a defined unit or a literal unit.
+    pub fn add_conversion(&mut self, from: String, to: String, factor: f64) {
+        self.conversions.insert((from, to), factor);
+    }
+
+    pub fn conversions(&self) -> impl Iterator<Item = (&str, &str, f64)> {
+        self.conversions
+            .iter()
+            .map(|((from, to), f)| (from.as_str(), to.as_str(), *f))
+    }
 }
 
+/// Headers of the sections of the unit file, in order.
+const SECTIONS: [&str; 3] = ["[defined]", "[operators]", "[conversions]"];
+
 impl Display for UnitCollection {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let str = self
-            .defined_units
-            .iter()
-            .map(|(a, b)| format!("{a};{b}"))
-            .collect::<Vec<_>>()
-            .join("\n")
-            + "\n\n"
-            + &self
-                .operator_results
-                .iter()
-                .map(|((op, a, b), r)| format!("{a};{op};{b};{r}"))
-                .collect::<Vec<_>>()
-                .join("\n");
-        write!(f, "{}", str)
+        for line in &self.header_comment {
+            writeln!(f, "{line}")?;
+        }
+        writeln!(f, "{}", SECTIONS[0])?;
+        for (a, b) in &self.defined_units {
+            writeln!(f, "{a};{b}")?;
+        }
+        writeln!(f, "\n{}", SECTIONS[1])?;
+        for ((op, a, b), r) in &self.operator_results {
+            writeln!(f, "{a};{op};{b};{r}")?;
+        }
+        write!(f, "\n{}", SECTIONS[2])?;
+        for ((from, to), factor) in &self.conversions {
+            write!(f, "\n{from};{to};{factor}")?;
+        }
+        Ok(())
     }
 }
 
 impl FromStr for UnitCollection {
     type Err = String;
 
+    /// Blank lines, lines starting with `#` and whitespace around lines and fields are ignored.  
+    /// Comments before the first section or entry are kept as the header comment.  
+    /// Files from before the section headers separate the sections with a blank line instead.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut defined_units = HashMap::new();
-        let mut operator_results = HashMap::new();
-        let mut lines = s.lines();
-        for line in lines.by_ref() {
+        let mut units = Self::new();
+        let has_headers = s.lines().any(|l| SECTIONS.contains(&l.trim()));
+        let mut section = 0;
+        let mut in_header = true;
+        for line in s.lines() {
             let line = line.trim();
-            if line.is_empty() {
-                break;
+            if line.starts_with('#') {
+                if in_header {
+                    units.header_comment.push(line.to_string());
+                }
+                continue;
             }
-            let [k, v] = line.split(';').collect::<Vec<&str>>()[..] else {
-                return Err(format!("Invalid defined line: {}", line));
-            };
-            defined_units.insert(k.to_string(), v.to_string());
-        }
-        for line in lines {
-            let line = line.trim();
             if line.is_empty() {
-                break;
+                // the conversion section is missing in older files, and is preceded by extra blank lines when there are no operator results
+                if !has_headers && !in_header {
+                    section = (section + 1).min(SECTIONS.len() - 1);
+                }
+                continue;
+            }
+            in_header = false;
+            if let Some(i) = SECTIONS.iter().position(|h| *h == line) {
+                section = i;
+                continue;
+            }
+            let fields = line.split(';').map(str::trim).collect::<Vec<&str>>();
+            match (section, &fields[..]) {
+                (0, [k, v]) => units.add_defined_unit(k.to_string(), v.to_string()),
+                (1, [a, op, b, r]) => {
+                    units.add_operator_result(op.to_string(), a.to_string(), b.to_string(), r.to_string())
+                }
+                (2, [from, to, factor]) => {
+                    let Ok(factor) = factor.parse() else {
+                        return Err(format!("Invalid conversion factor: {}", line));
+                    };
+                    units.add_conversion(from.to_string(), to.to_string(), factor);
+                }
+                (0, _) => return Err(format!("Invalid defined line: {}", line)),
+                (1, _) => return Err(format!("Invalid operator line: {}", line)),
+                _ => return Err(format!("Invalid conversion line: {}", line)),
             }
-            let [a, op, b, r] = line.split(';').collect::<Vec<&str>>()[..] else {
-                return Err(format!("Invalid operator line: {}", line));
-            };
-            operator_results.insert(
-                (op.to_string(), a.to_string(), b.to_string()),
-                r.to_string(),
-            );
         }
-        Ok(Self {
-            defined_units,
-            operator_results,
-        })
+        Ok(units)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ResolveMode {
+    /// Units are never resolved, and are displayed by their structure.
+    Off,
+    /// The user is prompted for missing unit names and operator results.
+    Prompt,
+    /// Missing operator results and unit names default to the structure of the unit.
+    Defaults,
+    /// Missing operator results and unit names are only recorded, see [CLIUnitLib::new_checking].
+    Check,
+}
+
+/// Units used by a document that the [UnitCollection] has no resolution for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MissingUnits {
+    /// Units without a name, sorted.
+    pub names: Vec<String>,
+    /// Operator results without a unit, as `left operator right`, sorted.
+    pub operator_results: Vec<String>,
+}
+
+impl MissingUnits {
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.operator_results.is_empty()
     }
 }
 
 pub struct CLIUnitLib {
     collection: UnitCollection,
     cache: Vec<DefinedUnit>,
-    interact: bool,
+    /// Names of the units resolved in the last [resolve_units](UnitLibrary::resolve_units), so formatting is a lookup.
+    interned: HashMap<DefinedUnit, Option<String>>,
+    mode: ResolveMode,
+    missing: MissingUnits,
 }
 
 impl CLIUnitLib {
@@ -119,20 +222,62 @@ impl CLIUnitLib {
         Self {
             collection,
             cache: Vec::new(),
-            interact,
+            interned: HashMap::new(),
+            mode: if interact {
+                ResolveMode::Prompt
+            } else {
+                ResolveMode::Off
+            },
+            missing: MissingUnits::default(),
+        }
+    }
+
+    /// Resolves like an interactive lib, but never prompts.  
+    /// Missing results are added to the collection, so they can be renamed later with [configure](Self::configure).
+    pub fn new_with_defaults(collection: UnitCollection) -> Self {
+        Self {
+            collection,
+            cache: Vec::new(),
+            interned: HashMap::new(),
+            mode: ResolveMode::Defaults,
+            missing: MissingUnits::default(),
         }
     }
 
+    /// Resolves without prompting or changing the collection, and records what is missing in [missing_units](Self::missing_units).  
+    /// Units are displayed like with [new_with_defaults](Self::new_with_defaults).
+    pub fn new_checking(collection: UnitCollection) -> Self {
+        Self {
+            collection,
+            cache: Vec::new(),
+            interned: HashMap::new(),
+            mode: ResolveMode::Check,
+            missing: MissingUnits::default(),
+        }
+    }
+
+    /// What was missing in the resolutions so far, only recorded by [new_checking](Self::new_checking) libs.
+    pub fn missing_units(&self) -> &MissingUnits {
+        &self.missing
+    }
+
     pub fn finish(self) -> UnitCollection {
         self.collection
     }
 
+    pub fn conversions(&self) -> impl Iterator<Item = (&str, &str, f64)> {
+        self.collection.conversions()
+    }
+
     pub fn configure(&mut self) {
         loop {
             println!("\nUNIT CONFIG MODE");
-            println!("[1]: Continue    [2]: List unit names    [3]: List operator results    [4]: Rename unit    [5]: Change operator result");
+            println!("[1]: Continue    [2]: List unit names    [3]: List operator results    [4]: Rename unit    [5]: Change operator result    [6]: List conversions    [7]: Set conversion    [8]: Remove unit    [9]: Remove operator result");
             match prompt("action: ", false).trim() {
-                "1" => return,
+                "1" => {
+                    self.fix_undefined_results();
+                    return;
+                }
                 "2" => {
                     println!("Unit names:");
                     for (name, unit) in &self.collection.defined_units {
@@ -171,15 +316,79 @@ impl CLIUnitLib {
                     }
                     self.collection.add_operator_result(op, l, r, res);
                 }
+                "6" => {
+                    println!("Conversions:");
+                    for (from, to, factor) in self.collection.conversions() {
+                        println!("1 {from} = {factor} {to}");
+                    }
+                }
+                "7" => {
+                    let from = prompt("Convert from unit: ", false);
+                    let to = prompt("Convert to unit: ", false);
+                    let Ok(factor) = prompt(&format!("Value of 1 {from} in {to}: "), false).parse() else {
+                        println!("Invalid number");
+                        continue;
+                    };
+                    self.collection.add_conversion(from, to, factor);
+                }
+                "8" => {
+                    let unit = prompt("Unit to remove: ", false);
+                    if self.collection.get_defined_unit(&unit).is_none() {
+                        println!("Unit does not exist: {unit}");
+                        continue;
+                    }
+                    let used_by = self.collection.operator_results_using(&unit);
+                    if !used_by.is_empty() {
+                        println!("Warning: {unit} is used by the operator results {}", used_by.join(", "));
+                        if prompt("Remove anyway? [y/N]: ", false) != "y" {
+                            continue;
+                        }
+                    }
+                    self.collection.remove_defined_unit(&unit);
+                }
+                "9" => {
+                    let op = prompt("Enter operator: ", false);
+                    let l = prompt("Enter left unit: ", false);
+                    let r = prompt("Enter right unit: ", false);
+                    if self.collection.remove_operator_result(&op, &l, &r).is_none() {
+                        println!("{l}{op}{r} does not exist");
+                    }
+                }
                 _ => {
-                    println!("Please enter a number 1..5");
+                    println!("Please enter a number 1..9");
                     continue
                 }
             }
         }
     }
 
+    /// Reports the problems found by [UnitCollection::validate], and prompts for the missing names.
+    fn fix_undefined_results(&mut self) {
+        let problems = self.collection.validate();
+        if problems.is_empty() {
+            return;
+        }
+        for problem in &problems {
+            println!("{problem}");
+        }
+        let mut missing = self
+            .collection
+            .operator_results
+            .values()
+            .filter(|res| self.collection.get_defined_unit(res).is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing.dedup();
+        for res in missing {
+            let unit_name = prompt(&format!("Name unit {res}: "), false);
+            self.collection.add_defined_unit(res, unit_name);
+        }
+    }
+
     fn resolve_unit(&mut self, unit: DefinedUnit, missing_names: &mut HashSet<String>) -> String {
+        // the default name keeps the grouping, eg. m/(s·s)
+        let structure = structural_unit(&unit);
         match unit {
             DefinedUnit::Defined(name) => {
                 if self.collection.get_defined_unit(&name).is_none() {
@@ -202,9 +411,19 @@ impl CLIUnitLib {
                     associative,
                 ) {
                     res.to_string()
+                } else if self.mode == ResolveMode::Check {
+                    let missing = format!("{l} {operator} {r}");
+                    if !self.missing.operator_results.contains(&missing) {
+                        self.missing.operator_results.push(missing);
+                    }
+                    structure
                 } else {
-                    let msg = format!("Enter result of {l} {operator} {r}: ");
-                    let res = prompt(&msg, true);
+                    let res = if self.mode == ResolveMode::Defaults {
+                        structure
+                    } else {
+                        let msg = format!("Enter result of {l} {operator} {r}: ");
+                        prompt(&msg, true)
+                    };
                     self.collection
                         .add_operator_result(operator, l, r, res.clone());
                     if self.collection.get_defined_unit(&res).is_none() {
@@ -216,20 +435,32 @@ impl CLIUnitLib {
         }
     }
 
-    fn get_internal_unit<'a>(&'a self, unit: &'a DefinedUnit) -> &'a str {
+    /// Builds the name of a unit from the collection, see [get_defined_unit](UnitLibrary::get_defined_unit).
+    fn lookup_defined_unit(&self, unit: &DefinedUnit) -> Option<String> {
+        let unit = simplify(unit)?;
+        let name = match self.mode {
+            ResolveMode::Off => None,
+            _ => self
+                .get_internal_unit(&unit)
+                .and_then(|internal| self.collection.get_defined_unit(internal)),
+        };
+        // units without a resolution, eg. never cached or with an unnamed result, are displayed by their structure
+        Some(name.map_or_else(|| structural_unit(&unit), str::to_string))
+    }
+
+    fn get_internal_unit<'a>(&'a self, unit: &'a DefinedUnit) -> Option<&'a str> {
         match unit {
-            DefinedUnit::Defined(name) => name,
+            DefinedUnit::Defined(name) => Some(name),
             DefinedUnit::Implicit {
                 operator,
                 associative,
                 left,
                 right,
             } => {
-                let l = self.get_internal_unit(left).to_string();
-                let r = self.get_internal_unit(right).to_string();
+                let l = self.get_internal_unit(left)?.to_string();
+                let r = self.get_internal_unit(right)?.to_string();
                 self.collection
                     .get_operator_result(operator.clone(), l, r, *associative)
-                    .unwrap()
             }
         }
     }
@@ -242,31 +473,142 @@ impl UnitLibrary for CLIUnitLib {
     }
 
     fn resolve_units(&mut self) {
-        if !self.interact {
-            return;
+        let cache = mem::take(&mut self.cache);
+        if self.mode != ResolveMode::Off {
+            let mut missing = HashSet::new();
+            for unit in &cache {
+                if let Some(unit) = simplify(unit) {
+                    self.resolve_unit(unit, &mut missing);
+                }
+            }
+            if self.mode == ResolveMode::Check {
+                self.missing.names.extend(missing.drain());
+                self.missing.names.sort();
+                self.missing.names.dedup();
+                self.missing.operator_results.sort();
+            }
+            for m in missing {
+                let name = if self.mode == ResolveMode::Defaults {
+                    m.clone()
+                } else {
+                    prompt(&format!("Name unit {m}: "), false)
+                };
+                self.collection.add_defined_unit(m, name);
+            }
         }
-        let mut missing = HashSet::new();
-        for unit in mem::take(&mut self.cache) {
-            self.resolve_unit(unit, &mut missing);
+        // names can change between resolutions, eg. when a structural name gets resolved
+        self.interned.clear();
+        for unit in cache {
+            if !self.interned.contains_key(&unit) {
+                let name = self.lookup_defined_unit(&unit);
+                self.interned.insert(unit, name);
+            }
         }
-        for m in missing {
-            let msg = format!("Name unit {m}: ");
-            self.collection.add_defined_unit(m, prompt(&msg, false));
+    }
+
+    fn get_defined_unit(&self, unit: &DefinedUnit) -> Option<String> {
+        match self.interned.get(unit) {
+            Some(name) => name.clone(),
+            None => self.lookup_defined_unit(unit),
         }
     }
+}
+
+/// A [UnitLibrary] that never resolves, units are named after their structure like in `CLIUnitLib` without resolution.  
+/// For library users that don't want to prompt or keep a `UnitCollection`.
+pub struct NoopUnitLibrary;
+
+impl UnitLibrary for NoopUnitLibrary {
+    fn cache_defined_unit(&mut self, _unit: &DefinedUnit) {}
+
+    fn get_defined_unit(&self, unit: &DefinedUnit) -> Option<String> {
+        simplify(unit).map(|u| structural_unit(&u))
+    }
+}
 
-    fn get_defined_unit(&self, unit: &DefinedUnit) -> String {
-        if !self.interact {
-            match unit {
-                DefinedUnit::Defined(d) => d.clone(),
-                DefinedUnit::Implicit { operator, left, right, .. } => format!("{} {} {}", self.get_defined_unit(left), operator, self.get_defined_unit(right)),
+/// Cancels out units that are divided by themselves, [None] meaning the unit is dimensionless.  
+/// Only structurally equal units are cancelled, so units with different names are never cancelled.
+fn simplify(unit: &DefinedUnit) -> Option<DefinedUnit> {
+    let DefinedUnit::Implicit {
+        operator,
+        associative,
+        left,
+        right,
+    } = unit
+    else {
+        return Some(unit.clone());
+    };
+    let is_div = matches!(operator.as_str(), "/" | "//" | "÷");
+    let (left, right) = match (simplify(left), simplify(right)) {
+        (Some(l), Some(r)) => (l, r),
+        (None, Some(r)) if operator == "*" => return Some(r),
+        (Some(l), None) if operator == "*" || is_div => return Some(l),
+        (None, None) if operator == "*" || is_div => return None,
+        // eg. 1/s can not be expressed
+        _ => return Some(unit.clone()),
+    };
+    if is_div {
+        if left == right {
+            return None;
+        }
+        if let DefinedUnit::Implicit {
+            operator: l_op,
+            left: l_l,
+            right: l_r,
+            ..
+        } = &left
+            && l_op == "*"
+        {
+            if **l_r == right {
+                return Some(*l_l.clone());
+            }
+            if **l_l == right {
+                return Some(*l_r.clone());
             }
-        } else {
-            self.collection
-                .get_defined_unit(self.get_internal_unit(unit))
-                .unwrap().to_string()
         }
     }
+    Some(DefinedUnit::Implicit {
+        operator: operator.clone(),
+        associative: *associative,
+        left: Box::new(left),
+        right: Box::new(right),
+    })
+}
+
+/// Renders a unit that has not been resolved from its structure, eg. `m/s`.
+fn structural_unit(unit: &DefinedUnit) -> String {
+    match unit {
+        DefinedUnit::Defined(d) => d.clone(),
+        DefinedUnit::Implicit {
+            operator,
+            left,
+            right,
+            ..
+        } => {
+            // only the left side can go without parentheses, and only when the operator is repeated, eg. m/s/s
+            let child = |child: &DefinedUnit, is_left: bool| match child {
+                DefinedUnit::Implicit { operator: c_op, .. } if !is_left || c_op != operator => {
+                    format!("({})", structural_unit(child))
+                }
+                _ => structural_unit(child),
+            };
+            format!(
+                "{}{}{}",
+                child(left, true),
+                unit_operator_symbol(operator),
+                child(right, false)
+            )
+        }
+    }
+}
+
+fn unit_operator_symbol(operator: &str) -> String {
+    match operator {
+        "*" => "·".to_string(),
+        "/" | "//" | "÷" => "/".to_string(),
+        "**" => "^".to_string(),
+        op => format!(" {op} "),
+    }
 }
 
 fn prompt(message: &str, is_name: bool) -> String {
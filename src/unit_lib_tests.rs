@@ -0,0 +1,252 @@
+use crate::language::expression::DefinedUnit;
+use crate::language::format::UnitLibrary;
+use crate::language::expression::EvaluationContext;
+use crate::language::format::FormattableLibraryProvider;
+use crate::language::latex_impl::LatexFormatter;
+use crate::markdown::{parse_markdown, MarkdownOptions};
+use crate::unit_lib::{CLIUnitLib, UnitCollection};
+
+fn defined(name: &str) -> DefinedUnit {
+    DefinedUnit::Defined(name.into())
+}
+
+fn implicit(operator: &str, left: DefinedUnit, right: DefinedUnit) -> DefinedUnit {
+    DefinedUnit::Implicit {
+        operator: operator.into(),
+        associative: matches!(operator, "+" | "*"),
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+#[test]
+fn structural_units() {
+    let lib = CLIUnitLib::new(UnitCollection::new(), false);
+    assert_eq!(lib.get_defined_unit(&defined("m")), Some("m".into()));
+    assert_eq!(
+        lib.get_defined_unit(&implicit("*", defined("m"), defined("s"))),
+        Some("m·s".into())
+    );
+    assert_eq!(
+        lib.get_defined_unit(&implicit("/", defined("m"), defined("s"))),
+        Some("m/s".into())
+    );
+    let acceleration = implicit("/", implicit("/", defined("m"), defined("s")), defined("s"));
+    assert_eq!(lib.get_defined_unit(&acceleration), Some("m/s/s".into()));
+    let per_second_squared = implicit("/", defined("m"), implicit("*", defined("s"), defined("s")));
+    assert_eq!(lib.get_defined_unit(&per_second_squared), Some("m/(s·s)".into()));
+}
+
+#[test]
+fn unresolved_units_are_structural() {
+    let mut units = UnitCollection::new();
+    units.add_defined_unit("m".into(), "Meter".into());
+    units.add_defined_unit("s".into(), "Second".into());
+    units.add_operator_result("*".into(), "m".into(), "m".into(), "m*m".into());
+    // never cached, so never resolved, and a result without a name
+    let lib = CLIUnitLib::new(units, true);
+    assert_eq!(lib.get_defined_unit(&implicit("/", defined("m"), defined("s"))), Some("m/s".into()));
+    assert_eq!(lib.get_defined_unit(&implicit("*", defined("m"), defined("m"))), Some("m·m".into()));
+    assert_eq!(lib.get_defined_unit(&defined("m")), Some("Meter".into()));
+    let mut units = UnitCollection::new();
+    units.add_defined_unit("Meter".into(), "m".into());
+    let mut lib = CLIUnitLib::new_checking(units);
+    let (res, _) = parse_markdown(
+        "^ 2 Meter / 4 Second^",
+        &mut EvaluationContext::new(),
+        &mut lib,
+        &FormattableLibraryProvider::new(LatexFormatter::default()),
+        &MarkdownOptions::default(),
+    );
+    assert!(res.contains("= 0.5\\small\\text{ Meter/Second}"), "{res}");
+}
+
+#[test]
+fn default_resolution() {
+    // a prompt would block on stdin, so finishing means none happened
+    let mut lib = CLIUnitLib::new_with_defaults(UnitCollection::new());
+    let speed = implicit("/", defined("m"), defined("s"));
+    lib.cache_defined_unit(&speed);
+    lib.resolve_units();
+    assert_eq!(lib.get_defined_unit(&speed), Some("m/s".into()));
+    assert_eq!(lib.get_defined_unit(&defined("m")), Some("m".into()));
+    let units = lib.finish();
+    assert_eq!(units.get_defined_unit("m"), Some("m"));
+    assert_eq!(units.get_defined_unit("s"), Some("s"));
+    assert_eq!(units.get_defined_unit("m/s"), Some("m/s"));
+    assert_eq!(
+        units.get_operator_result("/".into(), "m".into(), "s".into(), false),
+        Some("m/s")
+    );
+}
+
+#[test]
+fn resolved_names() {
+    let mut units = UnitCollection::new();
+    units.add_defined_unit("Meter".into(), "m".into());
+    units.add_defined_unit("Second".into(), "s".into());
+    units.add_defined_unit("Speed".into(), "m/s".into());
+    units.add_operator_result("/".into(), "Meter".into(), "Second".into(), "Speed".into());
+    let lib = CLIUnitLib::new(units, true);
+
+    // resolved units get their name, others are displayed by their structure
+    let speed = implicit("/", defined("Meter"), defined("Second"));
+    assert_eq!(lib.get_defined_unit(&speed), Some("m/s".into()));
+    assert_eq!(lib.get_defined_unit(&defined("Meter")), Some("m".into()));
+    // both leaves are known, but the operator result is not
+    let area = implicit("*", defined("Meter"), defined("Meter"));
+    assert_eq!(lib.get_defined_unit(&area), Some("Meter·Meter".into()));
+    assert_eq!(
+        lib.get_defined_unit(&implicit("*", speed, defined("Second"))),
+        Some("(Meter/Second)·Second".into())
+    );
+    // unknown leaf
+    assert_eq!(lib.get_defined_unit(&defined("Kelvin")), Some("Kelvin".into()));
+    assert_eq!(
+        lib.get_defined_unit(&implicit("/", defined("Meter"), defined("Kelvin"))),
+        Some("Meter/Kelvin".into())
+    );
+}
+
+#[test]
+fn cancelling_units() {
+    let lib = CLIUnitLib::new(UnitCollection::new(), false);
+    assert_eq!(lib.get_defined_unit(&implicit("/", defined("m"), defined("m"))), None);
+    let ms = implicit("*", defined("m"), defined("s"));
+    assert_eq!(lib.get_defined_unit(&implicit("/", ms.clone(), defined("s"))), Some("m".into()));
+    assert_eq!(lib.get_defined_unit(&implicit("/", ms.clone(), defined("m"))), Some("s".into()));
+    assert_eq!(lib.get_defined_unit(&implicit("/", ms.clone(), ms.clone())), None);
+    // differently named units are never cancelled
+    assert_eq!(
+        lib.get_defined_unit(&implicit("/", ms, defined("Second"))),
+        Some("(m·s)/Second".into())
+    );
+    assert_eq!(
+        lib.get_defined_unit(&implicit("/", defined("m"), defined("km"))),
+        Some("m/km".into())
+    );
+
+    // known units should not need prompting after cancelling
+    let mut units = UnitCollection::new();
+    units.add_defined_unit("m".into(), "m".into());
+    let mut lib = CLIUnitLib::new(units, true);
+    let unit = implicit("/", implicit("*", defined("m"), defined("m")), defined("m"));
+    lib.cache_defined_unit(&unit);
+    lib.resolve_units();
+    assert_eq!(lib.get_defined_unit(&unit), Some("m".into()));
+}
+
+#[test]
+fn conversions_round_trip() {
+    let mut units = UnitCollection::new();
+    units.add_defined_unit("Hour".into(), "h".into());
+    units.add_conversion("Hour".into(), "s".into(), 3600.);
+    let units: UnitCollection = units.to_string().parse().unwrap();
+    assert_eq!(units.get_defined_unit("Hour"), Some("h"));
+    assert_eq!(units.conversions().collect::<Vec<_>>(), vec![("Hour", "s", 3600.)]);
+    // files from before conversions were added
+    let units: UnitCollection = "Hour;h\n\nHour;*;Hour;HourSq".parse().unwrap();
+    assert_eq!(units.conversions().count(), 0);
+    assert_eq!(units.get_operator_result("*".into(), "Hour".into(), "Hour".into(), false), Some("HourSq"));
+}
+
+#[test]
+fn blank_lines_in_unit_file() {
+    let source = "[defined]\nHour;h  \n\n\n  Day ; d\n\n[operators]\n\nHour;*;Hour;HourSq\t\n\n[conversions]\nDay;Hour;24\n\n";
+    let units: UnitCollection = source.parse().unwrap();
+    assert_eq!(units.get_defined_unit("Day"), Some("d"));
+    assert_eq!(units.get_operator_result("*".into(), "Hour".into(), "Hour".into(), false), Some("HourSq"));
+    assert_eq!(units.conversions().collect::<Vec<_>>(), vec![("Day", "Hour", 24.)]);
+    let units: UnitCollection = units.to_string().parse().unwrap();
+    assert_eq!(units.get_defined_unit("Hour"), Some("h"));
+    assert_eq!(units.get_operator_result("*".into(), "Hour".into(), "Hour".into(), false), Some("HourSq"));
+    assert_eq!(units.conversions().count(), 1);
+    assert_eq!(UnitCollection::new().to_string().parse::<UnitCollection>().unwrap().conversions().count(), 0);
+    assert!("[operators]\nHour;*;Hour".parse::<UnitCollection>().is_err());
+}
+
+#[test]
+fn unit_file_comments() {
+    let source = "# units for the physics notes\n# edit by hand\n\n[defined]\n# time\nHour;h\n  # not a unit;x\n[operators]\n# squared\nHour;*;Hour;HourSq";
+    let units: UnitCollection = source.parse().unwrap();
+    assert_eq!(units.get_defined_unit("Hour"), Some("h"));
+    assert_eq!(units.get_defined_unit("# not a unit"), None);
+    assert_eq!(units.get_operator_result("*".into(), "Hour".into(), "Hour".into(), false), Some("HourSq"));
+    let saved = units.to_string();
+    assert!(saved.starts_with("# units for the physics notes\n# edit by hand\n[defined]\n"));
+    assert!(!saved.contains("# time"));
+    assert_eq!(saved.parse::<UnitCollection>().unwrap().to_string(), saved);
+    // files from before the section headers
+    let units: UnitCollection = "# old\n\nHour;h\n\nHour;*;Hour;HourSq".parse().unwrap();
+    assert_eq!(units.get_operator_result("*".into(), "Hour".into(), "Hour".into(), false), Some("HourSq"));
+}
+
+#[test]
+fn remove_units() {
+    let mut units = UnitCollection::new();
+    units.add_defined_unit("Hour".into(), "h".into());
+    units.add_defined_unit("HourSq".into(), "h²".into());
+    units.add_defined_unit("Day".into(), "d".into());
+    units.add_operator_result("*".into(), "Hour".into(), "Hour".into(), "HourSq".into());
+    units.add_operator_result("/".into(), "HourSq".into(), "Day".into(), "Hour".into());
+    // removing these would leave operator results naming a missing unit
+    assert_eq!(units.operator_results_using("Hour"), ["Hour * Hour", "HourSq / Day"]);
+    assert_eq!(units.operator_results_using("Day"), ["HourSq / Day"]);
+    assert_eq!(units.remove_operator_result("/", "Day", "HourSq"), None);
+    assert_eq!(units.remove_operator_result("/", "HourSq", "Day"), Some("Hour".into()));
+    assert!(units.operator_results_using("Day").is_empty());
+    assert_eq!(units.remove_defined_unit("Day"), Some("d".into()));
+    assert_eq!(units.remove_defined_unit("Day"), None);
+    assert_eq!(units.get_defined_unit("Day"), None);
+    assert_eq!(units.get_defined_unit("Hour"), Some("h"));
+}
+
+#[test]
+fn default_resolution_keeps_grouping() {
+    let mut lib = CLIUnitLib::new_with_defaults(UnitCollection::new());
+    let acceleration = implicit("/", defined("m"), implicit("*", defined("s"), defined("s")));
+    lib.cache_defined_unit(&acceleration);
+    lib.resolve_units();
+    assert_eq!(lib.get_defined_unit(&acceleration), Some("m/(s·s)".into()));
+    let units = lib.finish();
+    assert_eq!(
+        units.get_operator_result("/".into(), "m".into(), "s·s".into(), false),
+        Some("m/(s·s)")
+    );
+}
+
+#[test]
+fn dangling_operator_results() {
+    let mut units = UnitCollection::new();
+    units.add_defined_unit("Hour".into(), "h".into());
+    units.add_defined_unit("HourSq".into(), "h²".into());
+    units.add_operator_result("*".into(), "Hour".into(), "Hour".into(), "HourSq".into());
+    units.add_operator_result("/".into(), "HourSq".into(), "Hour".into(), "Hour".into());
+    assert!(units.validate().is_empty());
+    units.remove_defined_unit("HourSq");
+    assert_eq!(units.validate(), ["Hour * Hour results in HourSq, which has no name"]);
+    units.add_defined_unit("HourSq".into(), "h²".into());
+    assert!(units.validate().is_empty());
+}
+
+#[test]
+fn interned_units() {
+    let mut units = UnitCollection::new();
+    units.add_defined_unit("m".into(), "Meter".into());
+    units.add_defined_unit("s".into(), "Second".into());
+    units.add_defined_unit("m/s".into(), "Speed".into());
+    units.add_operator_result("/".into(), "m".into(), "s".into(), "m/s".into());
+    let mut lib = CLIUnitLib::new_with_defaults(units);
+    let speed = implicit("/", defined("m"), defined("s"));
+    // not cached, so looked up directly
+    assert_eq!(lib.get_defined_unit(&speed), Some("Speed".into()));
+    for _ in 0..1000 {
+        lib.cache_defined_unit(&speed);
+    }
+    lib.cache_defined_unit(&implicit("/", defined("s"), defined("s")));
+    lib.resolve_units();
+    for _ in 0..100_000 {
+        assert_eq!(lib.get_defined_unit(&speed), Some("Speed".into()));
+    }
+    assert_eq!(lib.get_defined_unit(&implicit("/", defined("s"), defined("s"))), None);
+}